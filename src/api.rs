@@ -0,0 +1,261 @@
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, RequestParts},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBasic;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::commandqueue::{CommandQueue, QueuedAction};
+use crate::commands::{CommandResult, CommandState};
+use crate::credentials;
+use crate::sessions::SharedSessions;
+use crate::shellyactor::ShellyLink;
+use crate::utils::ValveCommandManager;
+
+/// State of the command task the REST API can ask for.
+#[derive(Debug, Clone, Copy)]
+pub enum ApiQuery {
+    ShellyConnections,
+    PendingValveCommands,
+    BestActuators,
+    Discoveries,
+}
+
+#[derive(Debug)]
+pub enum ApiRequest {
+    Query {
+        query: ApiQuery,
+        responder: oneshot::Sender<serde_json::Value>,
+    },
+    /// Command with the payload of the DHT `command` messages, answered
+    /// once delivered, rejected or timed out.
+    Command {
+        command: serde_json::Value,
+        responder: oneshot::Sender<CommandResult>,
+    },
+}
+
+/// Credential required by the REST API.
+#[derive(Debug, Clone)]
+pub struct ApiAdmin {
+    pub user: String,
+    /// PHC string, as printed by `hash-password`.
+    pub password_hash: String,
+}
+
+/// Last mDNS announce of a Shelly.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredDevice {
+    pub topic_name: String,
+    pub mac_address: String,
+    pub ip_address: String,
+    pub mdns_name: String,
+    pub last_seen: DateTime<Local>,
+}
+
+/// Last announce of every Shelly, by mac address.
+pub type SharedDiscoveries = Arc<Mutex<HashMap<String, DiscoveredDevice>>>;
+
+/// Rejects requests without the Basic credentials of the admin.
+struct Admin;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Admin {
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(admin) = Extension::<ApiAdmin>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        match AuthBasic::from_request(req).await {
+            Ok(AuthBasic((user, Some(password))))
+                if credentials::plaintext_matches(&user, &admin.user)
+                    && credentials::verify_password(&password, &admin.password_hash) =>
+            {
+                Ok(Admin)
+            }
+            _ => Err((
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Basic realm=\"domo-wot-bridge\"")],
+            )
+                .into_response()),
+        }
+    }
+}
+
+async fn ask(tx_api: &mpsc::Sender<ApiRequest>, query: ApiQuery) -> Response {
+    let (responder, rx) = oneshot::channel();
+
+    if tx_api
+        .send(ApiRequest::Query { query, responder })
+        .await
+        .is_err()
+    {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    match rx.await {
+        Ok(value) => Json(value).into_response(),
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+async fn list_sessions(_: Admin, Extension(sessions): Extension<SharedSessions>) -> Response {
+    let sessions = sessions.lock().unwrap().sessions();
+
+    Json(sessions).into_response()
+}
+
+async fn list_shelly(_: Admin, Extension(tx_api): Extension<mpsc::Sender<ApiRequest>>) -> Response {
+    ask(&tx_api, ApiQuery::ShellyConnections).await
+}
+
+async fn list_pending_valve_commands(
+    _: Admin,
+    Extension(tx_api): Extension<mpsc::Sender<ApiRequest>>,
+) -> Response {
+    ask(&tx_api, ApiQuery::PendingValveCommands).await
+}
+
+async fn list_best_actuators(
+    _: Admin,
+    Extension(tx_api): Extension<mpsc::Sender<ApiRequest>>,
+) -> Response {
+    ask(&tx_api, ApiQuery::BestActuators).await
+}
+
+async fn list_discoveries(
+    _: Admin,
+    Extension(tx_api): Extension<mpsc::Sender<ApiRequest>>,
+) -> Response {
+    ask(&tx_api, ApiQuery::Discoveries).await
+}
+
+async fn post_command(
+    _: Admin,
+    Extension(tx_api): Extension<mpsc::Sender<ApiRequest>>,
+    Json(command): Json<serde_json::Value>,
+) -> Response {
+    let (responder, rx) = oneshot::channel();
+
+    if tx_api
+        .send(ApiRequest::Command { command, responder })
+        .await
+        .is_err()
+    {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    let result = match rx.await {
+        Ok(result) => result,
+        Err(_) => return StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    };
+
+    (status_code(result.state), Json(result)).into_response()
+}
+
+fn status_code(state: CommandState) -> StatusCode {
+    match state {
+        CommandState::Delivered => StatusCode::OK,
+        CommandState::Queued => StatusCode::ACCEPTED,
+        CommandState::Superseded => StatusCode::CONFLICT,
+        CommandState::Rejected => StatusCode::BAD_REQUEST,
+        CommandState::UnknownTarget => StatusCode::NOT_FOUND,
+        CommandState::DeviceOffline => StatusCode::SERVICE_UNAVAILABLE,
+        CommandState::Timeout => StatusCode::GATEWAY_TIMEOUT,
+    }
+}
+
+/// Introspection endpoints and direct commands, served next to the ESP32
+/// WebSocket.
+pub fn router(
+    admin: ApiAdmin,
+    sessions: SharedSessions,
+    tx_api: mpsc::Sender<ApiRequest>,
+) -> Router {
+    Router::new()
+        .route("/api/sessions", get(list_sessions))
+        .route("/api/shelly", get(list_shelly))
+        .route("/api/valves/pending", get(list_pending_valve_commands))
+        .route("/api/valves/best_actuators", get(list_best_actuators))
+        .route("/api/discovery", get(list_discoveries))
+        .route("/api/commands", post(post_command))
+        .layer(Extension(admin))
+        .layer(Extension(sessions))
+        .layer(Extension(tx_api))
+}
+
+pub fn shelly_connections(links: &[ShellyLink]) -> serde_json::Value {
+    serde_json::to_value(links).unwrap_or_default()
+}
+
+pub fn pending_valve_commands(command_queue: &CommandQueue) -> serde_json::Value {
+    command_queue
+        .commands
+        .values()
+        .filter_map(|pending| match &pending.action {
+            QueuedAction::Valve(action) => Some(serde_json::json!({
+                "mac_address": action.mac_address,
+                "desired_state": action.desired_state,
+                "attempts": pending.attempts,
+                "context": pending.context,
+            })),
+            QueuedAction::Actuator(_) => None,
+        })
+        .collect()
+}
+
+pub fn best_actuators(valve_command_manager: &ValveCommandManager) -> serde_json::Value {
+    valve_command_manager
+        .best_actuator
+        .iter()
+        .map(|(valve_mac_address, data)| {
+            serde_json::json!({
+                "valve_mac_address": valve_mac_address,
+                "actuator_mac_address": data.actuator_mac_address,
+                "rssi": data.rssi,
+                "updated_at": DateTime::<Local>::from(data.timestamp),
+            })
+        })
+        .collect()
+}
+
+pub fn discoveries(discovered: &HashMap<String, DiscoveredDevice>) -> serde_json::Value {
+    serde_json::to_value(discovered.values().collect::<Vec<_>>()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn best_actuators_listing() {
+        let mut valve_command_manager = ValveCommandManager::new();
+        valve_command_manager.update_best_actuator("valve", "esp-1", -70);
+        valve_command_manager.update_best_actuator("valve", "esp-2", -50);
+
+        let listing = best_actuators(&valve_command_manager);
+
+        assert_eq!(listing[0]["valve_mac_address"], "valve");
+        assert_eq!(listing[0]["actuator_mac_address"], "esp-2");
+        assert_eq!(listing[0]["rssi"], -50);
+    }
+
+    #[test]
+    fn superseded_commands_conflict() {
+        assert_eq!(status_code(CommandState::Superseded), StatusCode::CONFLICT);
+        assert_eq!(
+            serde_json::to_value(CommandState::Superseded).unwrap(),
+            "superseded"
+        );
+    }
+}
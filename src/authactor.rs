@@ -0,0 +1,172 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::authguard::{AuthLogEntry, AuthOutcome, SharedAuthGuard, SharedAuthLog};
+use crate::dhtmanager::DHTManager;
+use crate::messages::{AuthCredMessage, Credentials};
+use crate::metrics::metrics;
+use crate::supervisor::TaskResult;
+use crate::BridgeHandles;
+
+/// Period of the writes of the attempts refused before the upgrade.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// State shared by the successive runs of the auth task.
+#[derive(Clone)]
+pub struct AuthTask {
+    bridge: BridgeHandles,
+    requests: Arc<tokio::sync::Mutex<mpsc::Receiver<AuthCredMessage>>>,
+    auth_guard: SharedAuthGuard,
+    auth_log: SharedAuthLog,
+    allow_plaintext: bool,
+}
+
+impl AuthTask {
+    pub fn new(
+        bridge: BridgeHandles,
+        rx_auth_cred: mpsc::Receiver<AuthCredMessage>,
+        auth_guard: SharedAuthGuard,
+        auth_log: SharedAuthLog,
+        allow_plaintext: bool,
+    ) -> Self {
+        AuthTask {
+            bridge,
+            requests: Arc::new(tokio::sync::Mutex::new(rx_auth_cred)),
+            auth_guard,
+            auth_log,
+            allow_plaintext,
+        }
+    }
+
+    /// Checks the credentials of the connecting ESP32 and writes the
+    /// authentication audit log.
+    pub async fn run(self) -> TaskResult {
+        let mut requests = self.requests.lock().await;
+        let mut shutdown = self.bridge.shutdown.clone();
+        let dht_manager = &self.bridge.dht_manager;
+
+        let mut flush_timer = tokio::time::interval(FLUSH_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    write_auth_log(dht_manager, &self.auth_log).await;
+                    return Ok(());
+                }
+                request = requests.recv() => {
+                    let auth_cred_message = match request {
+                        Some(auth_cred_message) => auth_cred_message,
+                        None => return Ok(()),
+                    };
+
+                    let _timer = metrics().event_loop_latency.with_label_values(&["auth"]).start_timer();
+
+                    // the socket task opens the session once authenticated
+                    let _ret = handle_cred_message(
+                        auth_cred_message,
+                        dht_manager,
+                        &self.auth_guard,
+                        &self.auth_log,
+                        self.allow_plaintext,
+                    )
+                    .await;
+                }
+                _ = flush_timer.tick() => {
+                    // counts of the attempts refused before the upgrade
+                    write_auth_log(dht_manager, &self.auth_log).await;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_cred_message(
+    auth_cred_message: AuthCredMessage,
+    dht_manager: &DHTManager,
+    auth_guard: &SharedAuthGuard,
+    auth_log: &SharedAuthLog,
+    allow_plaintext: bool,
+) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+    let source_ip = auth_cred_message.source_ip;
+    let credentials = &auth_cred_message.credentials;
+    let user = credentials.user().to_owned();
+
+    let mut entry = AuthLogEntry::new(source_ip, credentials, AuthOutcome::Failure);
+
+    // the lockout may have started since the WebSocket upgrade
+    let locked_out = auth_guard
+        .lock()
+        .unwrap()
+        .locked_out(source_ip, &user, Instant::now());
+
+    let ret = match locked_out {
+        Some(remaining) => {
+            entry.result = AuthOutcome::LockedOut;
+            Err(format!("locked out for {} s", remaining.as_secs().max(1)).into())
+        }
+        None => match credentials {
+            Credentials::Basic { user, pass } => {
+                dht_manager.get_auth_cred(user, pass, allow_plaintext).await
+            }
+            Credentials::Certificate {
+                mac_address,
+                serial,
+            } => dht_manager.get_auth_cert(mac_address, serial).await,
+        },
+    };
+
+    match &ret {
+        Ok(m) => {
+            auth_guard.lock().unwrap().record_success(&user);
+            entry.result = AuthOutcome::Success;
+            if let Some(mac_address) = m["mac_address"].as_str() {
+                entry.mac_address = Some(mac_address.to_owned());
+            }
+            auth_log.lock().unwrap().attempt(entry);
+        }
+        Err(e) => {
+            log::warn!("ESP32 login of {} from {} refused: {}", user, source_ip, e);
+            entry.reason = Some(e.to_string());
+
+            match locked_out {
+                Some(remaining) => {
+                    auth_log
+                        .lock()
+                        .unwrap()
+                        .locked_out(entry, remaining, Instant::now());
+                }
+                None => {
+                    auth_guard
+                        .lock()
+                        .unwrap()
+                        .record_failure(source_ip, &user, Instant::now());
+                    auth_log.lock().unwrap().attempt(entry);
+                }
+            }
+        }
+    }
+
+    write_auth_log(dht_manager, auth_log).await;
+
+    match ret {
+        Ok(m) => {
+            let _r = auth_cred_message.responder.send(Ok(m.clone()));
+            Ok(m)
+        }
+        Err(e) => {
+            let _r = auth_cred_message.responder.send(Err(e.to_string()));
+            Err(e)
+        }
+    }
+}
+
+/// Writes the pending entries of the authentication audit log.
+async fn write_auth_log(dht_manager: &DHTManager, auth_log: &SharedAuthLog) {
+    let entries = auth_log.lock().unwrap().drain(Instant::now());
+
+    for (slot, entry) in entries {
+        dht_manager.write_auth_log(slot, &entry).await;
+    }
+}
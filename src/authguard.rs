@@ -0,0 +1,376 @@
+use chrono::Local;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::messages::Credentials;
+
+/// DHT topic receiving the ESP32 authentication attempts.
+pub const AUTH_LOG_TOPIC_NAME: &str = "domo_bridge_auth_log";
+
+pub type SharedAuthGuard = Arc<Mutex<AuthGuard>>;
+
+pub type SharedAuthLog = Arc<Mutex<AuthLog>>;
+
+/// Consecutive failures tolerated before a source is locked out, the
+/// lockout then doubles at every further failure up to `max_lockout`.
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    /// Zero disables the lockout.
+    pub max_failures: u32,
+    pub initial_lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+#[derive(Debug)]
+struct FailureRecord {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failure counters of the WebSocket authentication, kept per source ip and
+/// per user name (the mac address for certificate logins).
+///
+/// A success clears the counter of the user name only, so that a valid
+/// device cannot be used to reset the counter of an address guessing the
+/// credentials of other devices. Counters idle for longer than the maximum
+/// lockout are forgotten.
+#[derive(Debug)]
+pub struct AuthGuard {
+    policy: LockoutPolicy,
+    by_ip: HashMap<IpAddr, FailureRecord>,
+    by_user: HashMap<String, FailureRecord>,
+}
+
+fn lockout(policy: &LockoutPolicy, failures: u32) -> Option<Duration> {
+    if policy.max_failures == 0 || failures < policy.max_failures {
+        return None;
+    }
+
+    let factor = 1u32
+        .checked_shl(failures - policy.max_failures)
+        .unwrap_or(u32::MAX);
+
+    Some(
+        policy
+            .initial_lockout
+            .saturating_mul(factor)
+            .min(policy.max_lockout),
+    )
+}
+
+impl FailureRecord {
+    fn new(now: Instant) -> Self {
+        FailureRecord {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        }
+    }
+
+    fn fail(&mut self, policy: &LockoutPolicy, now: Instant) {
+        self.failures += 1;
+        self.last_failure = now;
+
+        if let Some(lockout) = lockout(policy, self.failures) {
+            self.locked_until = Some(now + lockout);
+        }
+    }
+
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
+
+impl AuthGuard {
+    pub fn new(policy: LockoutPolicy) -> Self {
+        AuthGuard {
+            policy,
+            by_ip: HashMap::new(),
+            by_user: HashMap::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let max_idle = self.policy.max_lockout;
+
+        self.by_ip
+            .retain(|_, r| now.duration_since(r.last_failure) < max_idle);
+        self.by_user
+            .retain(|_, r| now.duration_since(r.last_failure) < max_idle);
+    }
+
+    /// Returns the remaining lockout of `ip` or `user`, if any.
+    pub fn locked_out(&mut self, ip: IpAddr, user: &str, now: Instant) -> Option<Duration> {
+        self.expire(now);
+
+        let by_ip = self.by_ip.get(&ip).and_then(|r| r.remaining(now));
+        let by_user = self.by_user.get(user).and_then(|r| r.remaining(now));
+
+        by_ip.max(by_user)
+    }
+
+    pub fn record_failure(&mut self, ip: IpAddr, user: &str, now: Instant) {
+        self.by_ip
+            .entry(ip)
+            .or_insert_with(|| FailureRecord::new(now))
+            .fail(&self.policy, now);
+        self.by_user
+            .entry(user.to_owned())
+            .or_insert_with(|| FailureRecord::new(now))
+            .fail(&self.policy, now);
+    }
+
+    pub fn record_success(&mut self, user: &str) {
+        self.by_user.remove(user);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthOutcome {
+    Success,
+    Failure,
+    LockedOut,
+}
+
+/// Entry of the `domo_bridge_auth_log` topic.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthLogEntry {
+    /// RFC 3339 local time of the attempt.
+    pub timestamp: String,
+    pub ip: IpAddr,
+    /// `basic` or `certificate`.
+    pub method: &'static str,
+    pub user: String,
+    /// Mac address of the device, when known.
+    pub mac_address: Option<String>,
+    pub result: AuthOutcome,
+    pub reason: Option<String>,
+    /// Attempts counted by the entry, the attempts refused during a lockout
+    /// share a single entry.
+    pub attempts: u32,
+}
+
+impl AuthLogEntry {
+    /// Entry of an attempt made now with `credentials`.
+    pub fn new(ip: IpAddr, credentials: &Credentials, result: AuthOutcome) -> Self {
+        let mac_address = match credentials {
+            Credentials::Certificate { mac_address, .. } => Some(mac_address.to_owned()),
+            Credentials::Basic { .. } => None,
+        };
+
+        AuthLogEntry {
+            timestamp: Local::now().to_rfc3339(),
+            ip,
+            method: credentials.method(),
+            user: credentials.user().to_owned(),
+            mac_address,
+            result,
+            reason: None,
+            attempts: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Lockout {
+    slot: usize,
+    until: Instant,
+    entry: AuthLogEntry,
+    written: bool,
+}
+
+/// Entries waiting to be written to the `domo_bridge_auth_log` topic.
+///
+/// The topic keeps the last `capacity` entries, an entry being stored under
+/// its slot in a ring. The attempts refused while a source ip is locked out
+/// are counted in the entry of its lockout, so that a client retrying in a
+/// loop does not flood the DHT.
+#[derive(Debug)]
+pub struct AuthLog {
+    capacity: usize,
+    next_slot: usize,
+    pending: Vec<(usize, AuthLogEntry)>,
+    lockouts: HashMap<IpAddr, Lockout>,
+}
+
+impl AuthLog {
+    /// `next_slot` is the slot following the newest entry already stored.
+    pub fn new(capacity: usize, next_slot: usize) -> Self {
+        let capacity = capacity.max(1);
+
+        AuthLog {
+            capacity,
+            next_slot: next_slot % capacity,
+            pending: Vec::new(),
+            lockouts: HashMap::new(),
+        }
+    }
+
+    fn take_slot(&mut self) -> usize {
+        let slot = self.next_slot;
+        self.next_slot = (slot + 1) % self.capacity;
+        slot
+    }
+
+    /// Queues a successful or failed attempt.
+    pub fn attempt(&mut self, entry: AuthLogEntry) {
+        let slot = self.take_slot();
+        self.pending.push((slot, entry));
+    }
+
+    /// Counts an attempt refused because its source is locked out for
+    /// `remaining`.
+    pub fn locked_out(&mut self, entry: AuthLogEntry, remaining: Duration, now: Instant) {
+        let until = now + remaining;
+
+        match self.lockouts.get_mut(&entry.ip) {
+            Some(lockout) if lockout.until > now => {
+                lockout.entry.attempts += entry.attempts;
+                lockout.until = lockout.until.max(until);
+                lockout.written = false;
+            }
+            _ => {
+                let slot = self.take_slot();
+                self.lockouts.insert(
+                    entry.ip,
+                    Lockout {
+                        slot,
+                        until,
+                        entry,
+                        written: false,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Returns the entries to write by slot, the entry of a lockout being
+    /// written again while its count grows.
+    pub fn drain(&mut self, now: Instant) -> Vec<(usize, AuthLogEntry)> {
+        let mut entries = std::mem::take(&mut self.pending);
+
+        for lockout in self.lockouts.values_mut() {
+            if !lockout.written {
+                lockout.written = true;
+                entries.push((lockout.slot, lockout.entry.clone()));
+            }
+        }
+
+        self.lockouts.retain(|_, lockout| lockout.until > now);
+
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> AuthGuard {
+        AuthGuard::new(LockoutPolicy {
+            max_failures: 3,
+            initial_lockout: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(60),
+        })
+    }
+
+    #[test]
+    fn lockout_grows_exponentially() {
+        let mut guard = guard();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        guard.record_failure(ip, "esp", now);
+        guard.record_failure(ip, "esp", now);
+        assert_eq!(guard.locked_out(ip, "esp", now), None);
+
+        guard.record_failure(ip, "esp", now);
+        assert_eq!(
+            guard.locked_out(ip, "esp", now),
+            Some(Duration::from_secs(10))
+        );
+
+        guard.record_failure(ip, "esp", now);
+        assert_eq!(
+            guard.locked_out(ip, "other", now),
+            Some(Duration::from_secs(20))
+        );
+
+        for _ in 0..40 {
+            guard.record_failure(ip, "esp", now);
+        }
+        assert_eq!(
+            guard.locked_out(ip, "esp", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            guard.locked_out(ip, "esp", now + Duration::from_secs(61)),
+            None
+        );
+    }
+
+    #[test]
+    fn success_clears_the_user_only() {
+        let mut guard = guard();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let other_ip: IpAddr = "10.0.0.3".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            guard.record_failure(ip, "esp", now);
+        }
+        guard.record_success("esp");
+
+        assert_eq!(guard.locked_out(other_ip, "esp", now), None);
+        assert!(guard.locked_out(ip, "esp", now).is_some());
+    }
+
+    fn entry(ip: IpAddr, result: AuthOutcome) -> AuthLogEntry {
+        AuthLogEntry {
+            timestamp: "2022-06-01T10:00:00+02:00".to_owned(),
+            ip,
+            method: "basic",
+            user: "esp".to_owned(),
+            mac_address: None,
+            result,
+            reason: None,
+            attempts: 1,
+        }
+    }
+
+    #[test]
+    fn lockouts_share_one_entry() {
+        let mut log = AuthLog::new(3, 2);
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+        let lockout = Duration::from_secs(10);
+
+        log.attempt(entry(ip, AuthOutcome::Failure));
+        for _ in 0..100 {
+            log.locked_out(entry(ip, AuthOutcome::LockedOut), lockout, now);
+        }
+
+        let entries = log.drain(now);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, 2);
+        assert_eq!((entries[1].0, entries[1].1.attempts), (0, 100));
+        assert!(log.drain(now).is_empty());
+
+        log.locked_out(entry(ip, AuthOutcome::LockedOut), lockout, now);
+        let entries = log.drain(now + lockout);
+        assert_eq!((entries[0].0, entries[0].1.attempts), (0, 101));
+
+        // the lockout ended, the ring wraps around
+        log.locked_out(entry(ip, AuthOutcome::LockedOut), lockout, now + lockout);
+        log.attempt(entry(ip, AuthOutcome::Success));
+        let slots: Vec<usize> = log.drain(now).iter().map(|(slot, _)| *slot).collect();
+        assert_eq!(slots, [2, 1]);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::metrics::metrics;
 use aead::{generic_array::GenericArray, Aead, KeyInit, Payload};
 use ccm::{
     consts::{U11, U12, U4},
@@ -46,7 +47,13 @@ pub fn decrypt_atc(
 
     let res = match res_r {
         Ok(r) => r,
-        Err(_e) => return Err("error".into()),
+        Err(_e) => {
+            metrics()
+                .ble_decrypt_failures
+                .with_label_values(&["atc"])
+                .inc();
+            return Err("error".into());
+        }
     };
 
     if res.len() == 3 {
@@ -127,7 +134,7 @@ pub fn decrypt_contact(
         },
     );
 
-    return match res {
+    match res {
         Ok(r) => {
             //println!("{:?}", r);
             let re = hex::encode(r);
@@ -137,15 +144,15 @@ pub fn decrypt_contact(
             // 57 is used by open/close updates
             if chars[1] == 57 as char {
                 if chars[chars.len() - 1] == 48 as char {
-                    return Ok(ContactResult {
+                    Ok(ContactResult {
                         state: ContactStatus::Open,
-                    });
+                    })
                 } else if chars[chars.len() - 1] == 49 as char {
-                    return Ok(ContactResult {
+                    Ok(ContactResult {
                         state: ContactStatus::Close,
-                    });
+                    })
                 } else {
-                    return Err("Bad request".into());
+                    Err("Bad request".into())
                 }
             } else {
                 // 56 is used in case of light intensity change
@@ -154,9 +161,13 @@ pub fn decrypt_contact(
         }
         Err(_e) => {
             //println!("{:?}", _e);
+            metrics()
+                .ble_decrypt_failures
+                .with_label_values(&["contact"])
+                .inc();
             Err("Bad request".into())
         }
-    };
+    }
 }
 
 fn decrypt_aes_ccm(
@@ -1,367 +1,126 @@
+use crate::commands::{Command, CommandContext, CommandError, ValveAction, ValveCommand};
 use crate::dhtmanager::{DHTCommand, DHTManager};
-use std::error::Error;
-
-pub async fn handle_turn_command(
-    dht_manager: &DHTManager,
-    command: &serde_json::Value,
-) -> Result<DHTCommand, Box<dyn Error>> {
-    //println!("DOMO: handle_turn_command");
-    if let Some(value) = command.get("value") {
-        let mut topic_uuid = "";
-        let mut desired_state = false;
-
-        if let Some(t_uuid) = value.get("topic_uuid") {
-            if let Some(t) = t_uuid.as_str() {
-                topic_uuid = t;
-            } else {
-                return Err("err_topic_uuid".into());
-            }
-        }
-
-        if let Some(d_state) = value.get("desired_state") {
-            if let Some(d) = d_state.as_bool() {
-                desired_state = d;
-            } else {
-                return Err("err_desired_state".into());
-            }
-        }
-
-        let dht_connection_topic = dht_manager
-            .cache
-            .get_topic_uuid("domo_actuator_connection", topic_uuid)?;
-
-        //println!("Connection {}", dht_connection_topic);
-
-        if dht_connection_topic.get("value").is_none() {
-            return Err("no connection".into());
-        }
-
-        let dht_connection_topic = dht_connection_topic.get("value").unwrap();
-
-        if let Some(t_topic_name) = dht_connection_topic.get("target_topic_name") {
-            if let Some(t_topic_uuid) = dht_connection_topic.get("target_topic_uuid") {
-                if let Some(t_channel_number) = dht_connection_topic.get("target_channel_number") {
-                    let target_topic_name: &str;
-                    let target_topic_uuid: &str;
-                    let target_channel_number: u64;
-
-                    if let Some(t) = t_topic_name.as_str() {
-                        target_topic_name = t;
-                    } else {
-                        return Err("err_target_topic_name".into());
-                    }
-
-                    if let Some(t) = t_topic_uuid.as_str() {
-                        target_topic_uuid = t;
-                    } else {
-                        return Err("err_target_topic_uuid".into());
-                    }
-
-                    if let Some(t) = t_channel_number.as_u64() {
-                        target_channel_number = t;
-                    } else {
-                        return Err("err_target_channel_number".into());
-                    }
-
-                    //println!(
-                    //    "DOMO: handle_turn_command got actuator connection fields {} {}",
-                    //    target_topic_name, target_topic_uuid
-                    //);
-
-                    let actuator_topic = dht_manager
-                        .cache
-                        .get_topic_uuid(target_topic_name, target_topic_uuid)?;
-
-                    if let Some(value) = actuator_topic.get("value") {
-                        if let Some(mac_address) = value.get("mac_address") {
-                            let action_payload = serde_json::json!({
-                                "output_number": target_channel_number,
-                                "value": desired_state
-                            });
-
-                            let value = serde_json::json!({
-                                "mac_address": mac_address,
-                                "shelly_action": {
-                                  "input": {
-                                    "action": {
-                                      "action_name": "set_output",
-                                      "action_payload": action_payload.to_string(),
-                                    },
-                                  },
-                                }
-                            });
-
-                            //println!("DOMO: RETURN ACTUATOR COMMAND");
-                            return Ok(DHTCommand::ActuatorCommand(value));
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    //println!("DOMO: SKIPPING COMMAND");
-    Err("not_able_to_parse_command".into())
+use crate::drivers;
+
+/// Actuator channel a logical topic is wired to, as described by its
+/// `domo_actuator_connection` topic.
+struct TargetActuator {
+    topic_name: String,
+    channel_number: u64,
+    mac_address: String,
 }
 
-pub async fn handle_shutter_command(
+async fn get_target_actuator(
     dht_manager: &DHTManager,
-    command: &serde_json::Value,
-) -> Result<DHTCommand, Box<dyn Error>> {
-    if let Some(value) = command.get("value") {
-        let topic_uuid = value.get("topic_uuid").unwrap().as_str().unwrap();
-        let shutter_command = value.get("shutter_command").unwrap().as_str().unwrap();
-
-        let dht_connection_topic = dht_manager
-            .cache
-            .get_topic_uuid("domo_actuator_connection", topic_uuid)?;
-
-        let dht_connection_topic = dht_connection_topic.get("value").unwrap();
-
-        if let Some(target_topic_name) = dht_connection_topic.get("target_topic_name") {
-            if let Some(target_topic_uuid) = dht_connection_topic.get("target_topic_uuid") {
-                if let Some(_target_channel_number) =
-                    dht_connection_topic.get("target_channel_number")
-                {
-                    let target_topic_name = target_topic_name.as_str().unwrap();
-                    let target_topic_uuid = target_topic_uuid.as_str().unwrap();
-
-                    let actuator_topic = dht_manager
-                        .cache
-                        .get_topic_uuid(target_topic_name, target_topic_uuid)?;
-
-                    if let Some(value) = actuator_topic.get("value") {
-                        if let Some(mac_address) = value.get("mac_address") {
-                            let mut shut_cmd = 0;
-                            if shutter_command == "up" {
-                                shut_cmd = 0;
-                            }
-
-                            if shutter_command == "down" {
-                                shut_cmd = 1;
-                            }
-
-                            if shutter_command == "stop" {
-                                shut_cmd = 2;
-                            }
-
-                            let action_payload = serde_json::json!({
-                                "shutter_command": shut_cmd,
-                            });
-
-                            let value = serde_json::json!({
-                                "mac_address": mac_address,
-                                "shelly_action": {
-                                  "input": {
-                                    "action": {
-                                      "action_name": "set_shutter",
-                                      "action_payload": action_payload.to_string(),
-                                    },
-                                  },
-                                }
-                            });
-
-                            return Ok(DHTCommand::ActuatorCommand(value));
-                        }
-                    }
-                }
-            }
+    context: &CommandContext,
+    topic_uuid: &str,
+) -> Result<TargetActuator, CommandError> {
+    let dht_connection_topic = dht_manager
+        .get_topic_uuid("domo_actuator_connection", topic_uuid)
+        .await
+        .map_err(|_| CommandError::ConnectionNotFound {
+            context: context.to_owned(),
+        })?;
+
+    let dht_connection_topic = match dht_connection_topic.get("value") {
+        Some(value) => value,
+        None => {
+            return Err(CommandError::ConnectionNotFound {
+                context: context.to_owned(),
+            })
         }
-    }
-
-    Err("not_able_to_parse_command".into())
+    };
+
+    let connection_field = |field: &str| CommandError::InvalidConnection {
+        context: context.to_owned(),
+        field: field.to_owned(),
+    };
+
+    let target_topic_name = dht_connection_topic
+        .get("target_topic_name")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| connection_field("target_topic_name"))?;
+
+    let target_topic_uuid = dht_connection_topic
+        .get("target_topic_uuid")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| connection_field("target_topic_uuid"))?;
+
+    let target_channel_number = dht_connection_topic
+        .get("target_channel_number")
+        .and_then(|t| t.as_u64())
+        .ok_or_else(|| connection_field("target_channel_number"))?;
+
+    let actuator_not_found = || CommandError::ActuatorNotFound {
+        context: context.to_owned(),
+        target_topic_name: target_topic_name.to_owned(),
+    };
+
+    let actuator_topic = dht_manager
+        .get_topic_uuid(target_topic_name, target_topic_uuid)
+        .await
+        .map_err(|_| actuator_not_found())?;
+
+    let mac_address = actuator_topic
+        .get("value")
+        .and_then(|v| v.get("mac_address"))
+        .and_then(|m| m.as_str())
+        .ok_or_else(actuator_not_found)?;
+
+    Ok(TargetActuator {
+        topic_name: target_topic_name.to_owned(),
+        channel_number: target_channel_number,
+        mac_address: mac_address.to_owned(),
+    })
 }
 
-pub async fn handle_dim_command(
+/// Encodes a turn, dim, rgbw or shutter command with the driver of the
+/// actuator the topic is connected to.
+pub async fn handle_actuator_command(
     dht_manager: &DHTManager,
-    command: &serde_json::Value,
-) -> Result<DHTCommand, Box<dyn Error>> {
-    if let Some(value) = command.get("value") {
-        let topic_uuid = value.get("topic_uuid").unwrap().as_str().unwrap();
-        let desired_state = value.get("desired_state").unwrap().as_u64().unwrap();
-
-        let dht_connection_topic = dht_manager
-            .cache
-            .get_topic_uuid("domo_actuator_connection", topic_uuid)?;
-        let dht_connection_topic = dht_connection_topic.get("value").unwrap();
-
-        if let Some(target_topic_name) = dht_connection_topic.get("target_topic_name") {
-            if let Some(target_topic_uuid) = dht_connection_topic.get("target_topic_uuid") {
-                if let Some(target_channel_number) =
-                    dht_connection_topic.get("target_channel_number")
-                {
-                    let target_topic_name = target_topic_name.as_str().unwrap();
-                    let target_topic_uuid = target_topic_uuid.as_str().unwrap();
-                    let target_channel_number = target_channel_number.as_u64().unwrap();
-
-                    let actuator_topic = dht_manager
-                        .cache
-                        .get_topic_uuid(target_topic_name, target_topic_uuid)?;
-
-                    if let Some(value) = actuator_topic.get("value") {
-                        if let Some(mac_address) = value.get("mac_address") {
-                            if target_topic_name == "shelly_dimmer" {
-                                let action_payload =
-                                    serde_json::json!({ "dim_value": desired_state });
-
-                                let value = serde_json::json!({
-                                    "mac_address": mac_address,
-                                    "shelly_action": {
-                                      "input": {
-                                        "action": {
-                                          "action_name": "set_dimmer",
-                                          "action_payload": action_payload.to_string(),
-                                        },
-                                      },
-                                    }
-                                });
-                                return Ok(DHTCommand::ActuatorCommand(value));
-                            }
-
-                            if target_topic_name == "shelly_rgbw" {
-                                let mut channel = "r";
-                                if target_channel_number == 1 {
-                                    channel = "r";
-                                }
-
-                                if target_channel_number == 2 {
-                                    channel = "g";
-                                }
-
-                                if target_channel_number == 3 {
-                                    channel = "b";
-                                }
-
-                                if target_channel_number == 4 {
-                                    channel = "w";
-                                }
-
-                                let action_payload = serde_json::json!({
-                                    "led_dimmer_status": {
-                                            "channel": channel,
-                                            "value": desired_state
-                                    }
-
-                                });
-
-                                let value = serde_json::json!({
-                                    "mac_address": mac_address,
-                                    "shelly_action": {
-                                      "input": {
-                                        "action": {
-                                          "action_name": "set_led_dimmer",
-                                          "action_payload": action_payload.to_string(),
-                                        },
-                                      },
-                                    }
-                                });
-                                return Ok(DHTCommand::ActuatorCommand(value));
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    context: &CommandContext,
+    topic_uuid: &str,
+    command: &Command,
+) -> Result<DHTCommand, CommandError> {
+    let target = get_target_actuator(dht_manager, context, topic_uuid).await?;
+
+    let action = drivers::get(&target.topic_name).and_then(|driver| {
+        driver.encode_command(command, &target.mac_address, target.channel_number)
+    });
+
+    match action {
+        Some(action) => Ok(DHTCommand::ActuatorCommand(action, context.to_owned())),
+        None => Err(CommandError::UnsupportedTarget {
+            context: context.to_owned(),
+            target_topic_name: target.topic_name,
+        }),
     }
-
-    Err("not_able_to_parse_command".into())
-}
-
-pub async fn handle_rgbw_command(
-    dht_manager: &DHTManager,
-    command: &serde_json::Value,
-) -> Result<DHTCommand, Box<dyn Error>> {
-    if let Some(value) = command.get("value") {
-        let topic_uuid = value.get("topic_uuid").unwrap().as_str().unwrap();
-        let desired_state: serde_json::Value = value.get("desired_state").unwrap().to_owned();
-
-        let dht_connection_topic = dht_manager
-            .cache
-            .get_topic_uuid("domo_actuator_connection", topic_uuid)?;
-        let dht_connection_topic = dht_connection_topic.get("value").unwrap();
-
-        if let Some(target_topic_name) = dht_connection_topic.get("target_topic_name") {
-            if let Some(target_topic_uuid) = dht_connection_topic.get("target_topic_uuid") {
-                if let Some(_target_channel_number) =
-                    dht_connection_topic.get("target_channel_number")
-                {
-                    let target_topic_name = target_topic_name.as_str().unwrap();
-                    let target_topic_uuid = target_topic_uuid.as_str().unwrap();
-
-                    let actuator_topic = dht_manager
-                        .cache
-                        .get_topic_uuid(target_topic_name, target_topic_uuid)?;
-
-                    if let Some(value) = actuator_topic.get("value") {
-                        if let Some(mac_address) = value.get("mac_address") {
-                            let action_payload = serde_json::json!({
-                                "rgbw_status": {
-                                    "r": desired_state.get("r").unwrap().as_u64().unwrap(),
-                                    "g": desired_state.get("g").unwrap().as_u64().unwrap(),
-                                    "b": desired_state.get("b").unwrap().as_u64().unwrap(),
-                                    "w": desired_state.get("w").unwrap().as_u64().unwrap()
-                                }
-                            });
-
-                            let value = serde_json::json!({
-                                "mac_address": mac_address,
-                                "shelly_action": {
-                                  "input": {
-                                    "action": {
-                                      "action_name": "set_rgbw",
-                                      "action_payload": action_payload.to_string(),
-                                    },
-                                  },
-                                }
-                            });
-
-                            return Ok(DHTCommand::ActuatorCommand(value));
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    Err("not_able_to_parse_command".into())
 }
 
 pub async fn handle_valve_command(
     dht_manager: &DHTManager,
-    command: &serde_json::Value,
-) -> Result<DHTCommand, Box<dyn Error>> {
-    if let Some(value) = command.get("value") {
-        let topic_uuid = value.get("topic_uuid").unwrap().as_str().unwrap();
-        let desired_state = value.get("desired_state").unwrap().as_bool().unwrap();
-
-        let valve_topic = dht_manager
-            .cache
-            .get_topic_uuid("domo_ble_valve", topic_uuid)?;
-
-        if let Some(value) = valve_topic.get("value") {
-            if let Some(mac_address) = value.get("mac_address") {
-                let action_payload = serde_json::json!({
-                    "mac_address": mac_address,
-                    "value": desired_state
-                });
-
-                let value = serde_json::json!({
-                    "mac_address": mac_address,
-                    "desired_state": desired_state,
-                    "shelly_action": {
-                      "input": {
-                        "action": {
-                          "action_name": "control_radiator_valve",
-                          "action_payload": action_payload.to_string(),
-                        },
-                      },
-                    }
-                });
-
-                return Ok(DHTCommand::ValveCommand(value));
-            }
-        }
+    context: &CommandContext,
+    command: &ValveCommand,
+) -> Result<DHTCommand, CommandError> {
+    let valve_topic = dht_manager
+        .get_topic_uuid("domo_ble_valve", &command.topic_uuid)
+        .await
+        .map_err(|_| CommandError::ConnectionNotFound {
+            context: context.to_owned(),
+        })?;
+
+    match valve_topic
+        .get("value")
+        .and_then(|v| v.get("mac_address"))
+        .and_then(|m| m.as_str())
+    {
+        Some(mac_address) => Ok(DHTCommand::ValveCommand(
+            ValveAction::new(mac_address, command.desired_state),
+            context.to_owned(),
+        )),
+        None => Err(CommandError::ActuatorNotFound {
+            context: context.to_owned(),
+            target_topic_name: "domo_ble_valve".to_owned(),
+        }),
     }
-
-    Err("not_able_to_parse_command".into())
 }
@@ -0,0 +1,512 @@
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::api::{self, ApiQuery, ApiRequest, SharedDiscoveries};
+use crate::commandqueue::{CommandFailure, CommandQueue, PendingCommand, QueuedAction};
+use crate::commands::{
+    CommandContext, CommandError, CommandResult, CommandState, ExpectedStatus, ShellyAction,
+    ValveAction,
+};
+use crate::commandtracker::CommandTracker;
+use crate::dhtmanager::{DHTCommand, DHTManager};
+use crate::heartbeat::SharedHealth;
+use crate::messages::{ESP32CommandMessage, ESP32CommandType};
+use crate::metrics::metrics;
+use crate::sessions::DeliveryError;
+use crate::shellyactor::ShellyHandle;
+use crate::snapshot::{Snapshot, SnapshotFile};
+use crate::supervisor::TaskResult;
+use crate::utils::ValveCommandManager;
+use crate::wssmanager::WssManager;
+use crate::BridgeHandles;
+
+/// Events queued for the command task before the senders wait.
+const EVENT_QUEUE_SIZE: usize = 64;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reports of the devices the commands in flight depend on.
+#[derive(Debug)]
+pub enum CommandEvent {
+    /// Status of an actuator or of a valve, confirming the commands it
+    /// satisfies.
+    Status {
+        mac_address: String,
+        status: serde_json::Value,
+    },
+    /// Beacon of a valve relayed by an ESP32 actuator.
+    ValveBeacon {
+        valve_mac_address: String,
+        actuator_mac_address: String,
+        rssi: i64,
+    },
+}
+
+/// Commands in flight and valve routing, saved in the snapshot.
+pub struct Commands {
+    pub queue: CommandQueue,
+    pub tracker: CommandTracker,
+    pub valves: ValveCommandManager,
+    pub snapshot_file: Option<SnapshotFile>,
+}
+
+/// State shared by the successive runs of the command task.
+#[derive(Clone)]
+pub struct CommandTask {
+    bridge: BridgeHandles,
+    commands: Arc<tokio::sync::Mutex<Commands>>,
+    snapshot_interval: Duration,
+    discovered: SharedDiscoveries,
+    volatile: Arc<tokio::sync::Mutex<mpsc::Receiver<serde_json::Value>>>,
+    api: Arc<tokio::sync::Mutex<mpsc::Receiver<ApiRequest>>>,
+    events: Arc<tokio::sync::Mutex<mpsc::Receiver<CommandEvent>>>,
+}
+
+/// Creates the command task and the sender of the device reports.
+pub fn channel(
+    bridge: BridgeHandles,
+    commands: Commands,
+    snapshot_interval: Duration,
+    discovered: SharedDiscoveries,
+    rx_volatile: mpsc::Receiver<serde_json::Value>,
+    rx_api: mpsc::Receiver<ApiRequest>,
+) -> (mpsc::Sender<CommandEvent>, CommandTask) {
+    let (tx_events, rx_events) = mpsc::channel(EVENT_QUEUE_SIZE);
+
+    let task = CommandTask {
+        bridge,
+        commands: Arc::new(tokio::sync::Mutex::new(commands)),
+        snapshot_interval,
+        discovered,
+        volatile: Arc::new(tokio::sync::Mutex::new(rx_volatile)),
+        api: Arc::new(tokio::sync::Mutex::new(rx_api)),
+        events: Arc::new(tokio::sync::Mutex::new(rx_events)),
+    };
+
+    (tx_events, task)
+}
+
+impl CommandTask {
+    /// Dispatches the commands of the DHT and of the REST API, sends them
+    /// again until confirmed and saves them in the snapshot, a last time
+    /// once the bridge shuts down.
+    pub async fn run(self) -> TaskResult {
+        let mut commands = self.commands.lock().await;
+        let commands = &mut *commands;
+        let mut volatile = self.volatile.lock().await;
+        let mut api = self.api.lock().await;
+        let mut events = self.events.lock().await;
+        let mut shutdown = self.bridge.shutdown.clone();
+
+        let BridgeHandles {
+            dht_manager,
+            wss_mgr,
+            shelly_manager,
+            health,
+            ..
+        } = &self.bridge;
+
+        let mut check_pending_commands = tokio::time::interval(CHECK_INTERVAL);
+
+        let mut check_command_results = tokio::time::interval(CHECK_INTERVAL);
+
+        let mut snapshot_timer = tokio::time::interval(self.snapshot_interval);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    let pending_commands = commands.queue.commands.len();
+
+                    match &mut commands.snapshot_file {
+                        Some(file) => {
+                            let snapshot = Snapshot::capture(&commands.queue, &commands.valves);
+                            match file.save(&snapshot) {
+                                Ok(_) => log::info!(
+                                    "Saved {} pending commands to {}",
+                                    pending_commands,
+                                    file.path
+                                ),
+                                Err(e) => log::error!("Failed to save snapshot: {}", e),
+                            }
+                        }
+                        None if pending_commands > 0 => log::warn!(
+                            "Dropping {} pending commands, no snapshot_path configured",
+                            pending_commands
+                        ),
+                        None => {}
+                    }
+
+                    return Ok(());
+                }
+                Some(message) = volatile.recv() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["dht_command"]).start_timer();
+
+                    match dht_manager.handle_volatile_command(message).await {
+                        Ok(cmd) => {
+                            //println!("Received command from dht");
+                            let dispatch = dispatch_command(cmd, wss_mgr, shelly_manager, &commands.valves, &mut commands.queue).await;
+
+                            publish_queue_outcome(dht_manager, dispatch.superseded, CommandState::Superseded).await;
+
+                            if dispatch.queued {
+                                // the queue publishes the outcome once confirmed or abandoned
+                                if !dispatch.sent {
+                                    publish_command_state(dht_manager, &dispatch.context, CommandState::Queued).await;
+                                }
+                            } else if dispatch.sent {
+                                commands.tracker.track(&dispatch.context, &dispatch.mac_address, dispatch.expected_status);
+                            } else {
+                                metrics().commands_dropped.with_label_values(&["device_offline"]).inc();
+                                publish_command_state(dht_manager, &dispatch.context, CommandState::DeviceOffline).await;
+                            }
+                        }
+                        Err(e) => handle_command_error(&e, dht_manager, health).await,
+                    }
+                }
+                Some(api_request) = api.recv() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["api"]).start_timer();
+                    match api_request {
+                        ApiRequest::Query { query, responder } => {
+                            let response = match query {
+                                ApiQuery::ShellyConnections => api::shelly_connections(&shelly_manager.links()),
+                                ApiQuery::PendingValveCommands => api::pending_valve_commands(&commands.queue),
+                                ApiQuery::BestActuators => api::best_actuators(&commands.valves),
+                                ApiQuery::Discoveries => api::discoveries(&self.discovered.lock().unwrap()),
+                            };
+                            let _ret = responder.send(response);
+                        }
+                        ApiRequest::Command { command, responder } => {
+                            let message = json!({ "command": command });
+
+                            match dht_manager.handle_volatile_command(message).await {
+                                Ok(cmd) => {
+                                    let dispatch = dispatch_command(cmd, wss_mgr, shelly_manager, &commands.valves, &mut commands.queue).await;
+
+                                    publish_queue_outcome(dht_manager, dispatch.superseded, CommandState::Superseded).await;
+
+                                    if dispatch.sent {
+                                        commands.tracker.track_with_responder(&dispatch.context, &dispatch.mac_address, dispatch.expected_status, dispatch.queued, responder);
+                                    } else if dispatch.queued {
+                                        let _ret = responder.send(CommandResult::new(&dispatch.context, CommandState::Queued));
+                                    } else {
+                                        metrics().commands_dropped.with_label_values(&["device_offline"]).inc();
+                                        let _ret = responder.send(CommandResult::new(&dispatch.context, CommandState::DeviceOffline));
+                                    }
+                                }
+                                Err(e) => {
+                                    log::warn!("Rejected REST command: {}", e);
+                                    metrics().commands_dropped.with_label_values(&["rejected"]).inc();
+                                    health.lock().unwrap().error(format!("rejected REST command: {}", e));
+                                    let result = CommandResult::rejected(&e).unwrap_or_else(|| {
+                                        CommandResult::new(&CommandContext::default(), CommandState::Rejected)
+                                    });
+                                    let _ret = responder.send(result);
+                                }
+                            }
+                        }
+                    }
+                }
+                Some(event) = events.recv() => {
+                    match event {
+                        CommandEvent::Status { mac_address, status } => {
+                            let confirmed = commands.queue.confirm(&mac_address, &status);
+                            publish_queue_outcome(dht_manager, confirmed, CommandState::Delivered).await;
+
+                            for result in commands.tracker.confirm(&mac_address, &status) {
+                                dht_manager.publish_command_result(&result).await;
+                            }
+                        }
+                        CommandEvent::ValveBeacon { valve_mac_address, actuator_mac_address, rssi } => {
+                            // update best actuator to use for valve depending on rssi
+                            commands.valves.update_best_actuator(&valve_mac_address, &actuator_mac_address, rssi);
+                        }
+                    }
+                }
+                _ = check_pending_commands.tick() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["command_queue"]).start_timer();
+                    //println!("PENDING COMMANDS QUEUE CHECK");
+                    check_command_queue(
+                        &mut commands.queue,
+                        dht_manager,
+                        shelly_manager,
+                        wss_mgr,
+                        &commands.valves,
+                        health,
+                    )
+                    .await;
+                }
+                _ = check_command_results.tick() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["command_results"]).start_timer();
+                    for result in commands.tracker.expired() {
+                        dht_manager.publish_command_result(&result).await;
+                    }
+                }
+                _ = snapshot_timer.tick() => {
+                    if let Some(file) = &mut commands.snapshot_file {
+                        let _timer = metrics().event_loop_latency.with_label_values(&["snapshot"]).start_timer();
+
+                        let snapshot = Snapshot::capture(&commands.queue, &commands.valves);
+                        if let Err(e) = file.save(&snapshot) {
+                            log::warn!("Failed to save snapshot: {}", e);
+                            health.lock().unwrap().error(format!("failed to save snapshot: {}", e));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn handle_command_error(err: &CommandError, dht_manager: &DHTManager, health: &SharedHealth) {
+    if let CommandError::NotACommand = err {
+        return;
+    }
+
+    log::error!("Rejected command: {}", err);
+
+    metrics()
+        .commands_dropped
+        .with_label_values(&["rejected"])
+        .inc();
+    health
+        .lock()
+        .unwrap()
+        .error(format!("rejected command: {}", err));
+
+    if let Some(result) = CommandResult::from_error(err) {
+        dht_manager.publish_command_result(&result).await;
+    }
+}
+
+async fn publish_command_state(
+    dht_manager: &DHTManager,
+    context: &CommandContext,
+    state: CommandState,
+) {
+    if context.request_id.is_some() {
+        dht_manager
+            .publish_command_result(&CommandResult::new(context, state))
+            .await;
+    }
+}
+
+/// Publishes the outcome of the commands leaving the queue.
+async fn publish_queue_outcome(
+    dht_manager: &DHTManager,
+    commands: impl IntoIterator<Item = PendingCommand>,
+    state: CommandState,
+) {
+    for pending in commands {
+        publish_command_state(dht_manager, &pending.context, state).await;
+    }
+}
+
+/// Command handed to the actuators, `sent` is false when no connection to
+/// the target is open.
+struct Dispatch {
+    context: CommandContext,
+    mac_address: String,
+    expected_status: Option<ExpectedStatus>,
+    sent: bool,
+    /// The command is sent again until confirmed.
+    queued: bool,
+    /// Command pending for the same channel, replaced by this one.
+    superseded: Option<PendingCommand>,
+}
+
+/// Sends a command parsed from the DHT or the REST API and queues it until
+/// the actuator reports the expected status.
+async fn dispatch_command(
+    cmd: DHTCommand,
+    wss_mgr: &WssManager,
+    shelly_manager: &ShellyHandle,
+    valve_command_manager: &ValveCommandManager,
+    command_queue: &mut CommandQueue,
+) -> Dispatch {
+    match cmd {
+        DHTCommand::ActuatorCommand(action, context) => {
+            //println!("Received actuator command");
+
+            let sent = send_actuator_action(&action, wss_mgr, shelly_manager).await;
+
+            let mut dispatch = Dispatch {
+                context: context.to_owned(),
+                mac_address: action.mac_address.to_owned(),
+                expected_status: action.expected_status.clone(),
+                sent,
+                queued: false,
+                superseded: None,
+            };
+
+            if let Some(expected_status) = action.expected_status.clone() {
+                dispatch.superseded = command_queue.insert(
+                    QueuedAction::Actuator(action),
+                    expected_status,
+                    &context,
+                    sent,
+                );
+                dispatch.queued = true;
+            }
+
+            dispatch
+        }
+        DHTCommand::ValveCommand(action, context) => {
+            //println!("Valve command {:?}", action);
+
+            let sent = send_valve_action(&action, valve_command_manager, wss_mgr).await;
+
+            let expected_status = action.expected_status();
+
+            Dispatch {
+                context: context.to_owned(),
+                mac_address: action.mac_address.to_owned(),
+                expected_status: Some(expected_status.clone()),
+                sent,
+                queued: true,
+                superseded: command_queue.insert(
+                    QueuedAction::Valve(action),
+                    expected_status,
+                    &context,
+                    sent,
+                ),
+            }
+        }
+    }
+}
+
+async fn send_actuator_action(
+    action: &ShellyAction,
+    wss_mgr: &WssManager,
+    shelly_manager: &ShellyHandle,
+) -> bool {
+    let cmd = ESP32CommandMessage {
+        command_type: ESP32CommandType::Actuator,
+        mac_address: action.mac_address.clone(),
+        payload: action.to_message(),
+        actuator_mac_address: String::from(""),
+    };
+
+    // the ESP8266 Shelly are not connected through the WebSocket server
+    let mut sent = match wss_mgr.send_command(cmd).await {
+        Ok(()) => true,
+        Err(DeliveryError::NotConnected(_)) => false,
+        Err(e) => {
+            log::warn!("Cannot send the action to {}: {}", action.mac_address, e);
+            false
+        }
+    };
+
+    //println!("DOMO: SENDING ACTION");
+
+    if shelly_manager.send_action(&action.mac_address, &action.to_message()) {
+        sent = true;
+    }
+
+    sent
+}
+
+async fn send_valve_action(
+    action: &ValveAction,
+    valve_command_manager: &ValveCommandManager,
+    wss_mgr: &WssManager,
+) -> bool {
+    match valve_command_manager.get_best_actuator_for_valve(&action.mac_address) {
+        Some(best_act) => {
+            //println!("SENDING VALVE COMMAND TO {} ", best_act);
+            let cmd = ESP32CommandMessage {
+                command_type: ESP32CommandType::Valve,
+                mac_address: action.mac_address.clone(),
+                payload: action.to_message(),
+                actuator_mac_address: best_act,
+            };
+
+            match wss_mgr.send_command(cmd).await {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!(
+                        "Cannot send the valve action for {}: {}",
+                        action.mac_address,
+                        e
+                    );
+                    false
+                }
+            }
+        }
+        None => {
+            //println!("NO ACTUATOR for {} ", action.mac_address);
+            false
+        }
+    }
+}
+
+async fn check_command_queue(
+    command_queue: &mut CommandQueue,
+    dht_manager: &DHTManager,
+    shelly_manager: &ShellyHandle,
+    wss_mgr: &WssManager,
+    valve_command_manager: &ValveCommandManager,
+    health: &SharedHealth,
+) {
+    if command_queue.commands.is_empty() {
+        return;
+    }
+
+    // the status last written on the DHT may already be the desired one
+    for mac_address in command_queue.mac_addresses() {
+        if let Ok(topic) = dht_manager
+            .get_actuator_from_mac_address(&mac_address)
+            .await
+        {
+            let confirmed = command_queue.confirm(&mac_address, &topic["value"]);
+            publish_queue_outcome(dht_manager, confirmed, CommandState::Delivered).await;
+        }
+    }
+
+    let (retry, failed) = command_queue.due();
+
+    for pending in retry {
+        match &pending.action {
+            QueuedAction::Actuator(action) => {
+                //println!("RE-SEND COMMAND TO {}", action.mac_address);
+                send_actuator_action(action, wss_mgr, shelly_manager).await;
+            }
+            QueuedAction::Valve(action) => {
+                //println!("RE-SEND VALVE COMMAND TO {}", action.mac_address);
+                metrics().valve_retries.inc();
+                send_valve_action(action, valve_command_manager, wss_mgr).await;
+            }
+        }
+    }
+
+    for pending in failed.iter() {
+        let failure = CommandFailure::from(pending);
+
+        metrics()
+            .commands_dropped
+            .with_label_values(&["gave_up"])
+            .inc();
+
+        if let QueuedAction::Valve(_) = pending.action {
+            metrics().valve_give_ups.inc();
+        }
+
+        log::warn!(
+            "Giving up {} for {} channel {} after {} attempts",
+            failure.context.command_type,
+            failure.mac_address,
+            failure.channel,
+            failure.attempts
+        );
+
+        health.lock().unwrap().error(format!(
+            "gave up {} for {} after {} attempts",
+            failure.context.command_type, failure.mac_address, failure.attempts
+        ));
+
+        dht_manager.publish_command_failure(&failure).await;
+    }
+
+    publish_queue_outcome(dht_manager, failed, CommandState::DeviceOffline).await;
+}
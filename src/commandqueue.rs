@@ -0,0 +1,291 @@
+use crate::commands::{normalize_mac, CommandContext, ExpectedStatus, ShellyAction, ValveAction};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum QueuedAction {
+    Actuator(ShellyAction),
+    Valve(ValveAction),
+}
+
+impl QueuedAction {
+    pub fn mac_address(&self) -> &str {
+        match self {
+            QueuedAction::Actuator(action) => &action.mac_address,
+            QueuedAction::Valve(action) => &action.mac_address,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingCommand {
+    pub action: QueuedAction,
+    pub expected_status: ExpectedStatus,
+    pub context: CommandContext,
+    pub attempts: usize,
+    pub next_attempt: Instant,
+    pub queued_at: SystemTime,
+}
+
+/// Volatile event published when a command is abandoned.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandFailure {
+    #[serde(flatten)]
+    pub context: CommandContext,
+    pub mac_address: String,
+    pub channel: u64,
+    pub attempts: usize,
+}
+
+impl From<&PendingCommand> for CommandFailure {
+    fn from(pending: &PendingCommand) -> Self {
+        CommandFailure {
+            context: pending.context.to_owned(),
+            mac_address: pending.action.mac_address().to_owned(),
+            channel: pending.expected_status.channel,
+            attempts: pending.attempts,
+        }
+    }
+}
+
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: usize,
+}
+
+impl RetryPolicy {
+    fn delay(&self, attempts: usize) -> Duration {
+        let exp = attempts.saturating_sub(1).min(16) as u32;
+        self.initial_delay
+            .saturating_mul(2_u32.pow(exp))
+            .min(self.max_delay)
+    }
+}
+
+/// Commands waiting for the actuator to report the desired state, keyed by
+/// normalized actuator mac address and channel. A newer command for the
+/// same channel replaces the pending one.
+pub struct CommandQueue {
+    pub commands: HashMap<(String, u64), PendingCommand>,
+    policy: RetryPolicy,
+}
+
+impl CommandQueue {
+    pub fn new(policy: RetryPolicy) -> Self {
+        CommandQueue {
+            commands: HashMap::new(),
+            policy,
+        }
+    }
+
+    /// Queues an action. `sent` tells whether the first attempt has
+    /// already been delivered. Returns the command pending for the same
+    /// channel, replaced by this one.
+    pub fn insert(
+        &mut self,
+        action: QueuedAction,
+        expected_status: ExpectedStatus,
+        context: &CommandContext,
+        sent: bool,
+    ) -> Option<PendingCommand> {
+        let attempts = usize::from(sent);
+        let next_attempt = if sent {
+            Instant::now() + self.policy.delay(attempts)
+        } else {
+            Instant::now()
+        };
+
+        let key = (normalize_mac(action.mac_address()), expected_status.channel);
+
+        self.commands.insert(
+            key,
+            PendingCommand {
+                action,
+                expected_status,
+                context: context.to_owned(),
+                attempts,
+                next_attempt,
+                queued_at: SystemTime::now(),
+            },
+        )
+    }
+
+    /// Queues a command restored from a snapshot, unless a newer one for
+    /// the same channel is already pending.
+    pub fn restore(&mut self, pending: PendingCommand) {
+        let key = (
+            normalize_mac(pending.action.mac_address()),
+            pending.expected_status.channel,
+        );
+
+        self.commands.entry(key).or_insert(pending);
+    }
+
+    /// Removes and returns the commands of `mac_address` whose expected
+    /// status is satisfied by `status`.
+    pub fn confirm(
+        &mut self,
+        mac_address: &str,
+        status: &serde_json::Value,
+    ) -> Vec<PendingCommand> {
+        let mac_address = normalize_mac(mac_address);
+
+        let keys: Vec<(String, u64)> = self
+            .commands
+            .iter()
+            .filter(|((mac, _), pending)| {
+                *mac == mac_address && pending.expected_status.matches(status)
+            })
+            .map(|(key, _)| key.to_owned())
+            .collect();
+
+        keys.iter()
+            .filter_map(|key| self.commands.remove(key))
+            .collect()
+    }
+
+    /// Mac addresses of the queued commands, as written in their actions.
+    pub fn mac_addresses(&self) -> Vec<String> {
+        let mut macs: Vec<String> = self
+            .commands
+            .values()
+            .map(|pending| pending.action.mac_address().to_owned())
+            .collect();
+        macs.sort();
+        macs.dedup();
+        macs
+    }
+
+    /// Returns the commands to send again, bumping their attempt counter,
+    /// and removes the ones that ran out of attempts.
+    pub fn due(&mut self) -> (Vec<PendingCommand>, Vec<PendingCommand>) {
+        let now = Instant::now();
+        let mut retry = Vec::new();
+        let mut failed = Vec::new();
+
+        self.commands.retain(|_, pending| {
+            if pending.next_attempt > now {
+                return true;
+            }
+
+            if pending.attempts >= self.policy.max_attempts {
+                failed.push(pending.to_owned());
+                return false;
+            }
+
+            pending.attempts += 1;
+            pending.next_attempt = now + self.policy.delay(pending.attempts);
+            retry.push(pending.to_owned());
+            true
+        });
+
+        (retry, failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(max_attempts: usize) -> CommandQueue {
+        CommandQueue::new(RetryPolicy {
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            max_attempts,
+        })
+    }
+
+    fn turn_on(channel: u64) -> (QueuedAction, ExpectedStatus) {
+        let field = format!("output{}", channel);
+        let expected = ExpectedStatus::new(channel).with(&field, serde_json::json!(true));
+        let action = ShellyAction::new(
+            "aa:bb:cc:dd:ee:ff",
+            "set_output",
+            &serde_json::json!({ "output_number": channel, "value": true }),
+        );
+        (QueuedAction::Actuator(action), expected)
+    }
+
+    #[test]
+    fn retries_until_confirmed() {
+        let mut queue = queue(5);
+        let (action, expected) = turn_on(1);
+
+        queue.insert(action, expected, &CommandContext::default(), true);
+
+        let (retry, failed) = queue.due();
+        assert_eq!(retry.len(), 1);
+        assert_eq!(retry[0].attempts, 2);
+        assert!(failed.is_empty());
+
+        let status = serde_json::json!({ "output1": false, "output2": true });
+        assert!(queue.confirm("AA:BB:CC:DD:EE:FF", &status).is_empty());
+
+        let status = serde_json::json!({ "output1": true });
+        assert_eq!(queue.confirm("AA:BB:CC:DD:EE:FF", &status).len(), 1);
+        assert!(queue.commands.is_empty());
+    }
+
+    #[test]
+    fn status_without_colons_confirms() {
+        let mut queue = queue(5);
+        let (action, expected) = turn_on(1);
+
+        queue.insert(action, expected, &CommandContext::default(), true);
+        assert_eq!(queue.mac_addresses(), vec!["aa:bb:cc:dd:ee:ff".to_owned()]);
+
+        // the Shelly report their mac address without colons
+        let status = serde_json::json!({ "output1": true });
+        assert_eq!(queue.confirm("AABBCCDDEEFF", &status).len(), 1);
+    }
+
+    #[test]
+    fn newer_command_replaces_the_pending_one() {
+        let mut queue = queue(5);
+        let (action, expected) = turn_on(1);
+
+        assert!(queue
+            .insert(
+                action.clone(),
+                expected.clone(),
+                &CommandContext::default(),
+                false
+            )
+            .is_none());
+
+        let replaced = queue
+            .insert(action, expected, &CommandContext::default(), true)
+            .unwrap();
+        assert_eq!(replaced.attempts, 0);
+        assert_eq!(queue.commands[&("aabbccddeeff".to_owned(), 1)].attempts, 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut queue = queue(1);
+        let (action, expected) = turn_on(2);
+
+        queue.insert(action, expected, &CommandContext::default(), true);
+
+        let (retry, failed) = queue.due();
+        assert!(retry.is_empty());
+        assert_eq!(failed.len(), 1);
+        assert_eq!(CommandFailure::from(&failed[0]).channel, 2);
+        assert!(queue.commands.is_empty());
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 100,
+        };
+
+        assert_eq!(policy.delay(1), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(8));
+        assert_eq!(policy.delay(50), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,593 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+/// Volatile message published on the DHT to drive an actuator.
+#[derive(Debug, Clone)]
+pub struct CommandMessage {
+    pub command: Command,
+    pub context: CommandContext,
+}
+
+/// Identifies the command an outcome or an error refers to.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CommandContext {
+    pub command_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic_uuid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// The volatile message does not carry a command.
+    NotACommand,
+    UnknownCommandType {
+        context: CommandContext,
+    },
+    MissingField {
+        context: CommandContext,
+        field: String,
+    },
+    InvalidField {
+        context: CommandContext,
+        field: String,
+        reason: String,
+    },
+    /// No `domo_actuator_connection` (or valve topic) for the topic_uuid.
+    ConnectionNotFound {
+        context: CommandContext,
+    },
+    /// The connection topic has a missing or ill-typed field.
+    InvalidConnection {
+        context: CommandContext,
+        field: String,
+    },
+    ActuatorNotFound {
+        context: CommandContext,
+        target_topic_name: String,
+    },
+    UnsupportedTarget {
+        context: CommandContext,
+        target_topic_name: String,
+    },
+}
+
+impl CommandError {
+    pub fn context(&self) -> Option<&CommandContext> {
+        match self {
+            CommandError::NotACommand => None,
+            CommandError::UnknownCommandType { context }
+            | CommandError::MissingField { context, .. }
+            | CommandError::InvalidField { context, .. }
+            | CommandError::ConnectionNotFound { context }
+            | CommandError::InvalidConnection { context, .. }
+            | CommandError::ActuatorNotFound { context, .. }
+            | CommandError::UnsupportedTarget { context, .. } => Some(context),
+        }
+    }
+
+    fn from_deserialize(
+        context: CommandContext,
+        err: serde_path_to_error::Error<serde_json::Error>,
+    ) -> Self {
+        let path = err.path().to_string();
+        let reason = err.inner().to_string();
+
+        if path == "command_type" {
+            return CommandError::UnknownCommandType { context };
+        }
+
+        let path = path.trim_start_matches("value").trim_start_matches('.');
+
+        if let Some(missing) = reason
+            .strip_prefix("missing field `")
+            .and_then(|r| r.split('`').next())
+        {
+            let field = if path.is_empty() || path == "?" {
+                missing.to_owned()
+            } else {
+                format!("{}.{}", path, missing)
+            };
+            return CommandError::MissingField { context, field };
+        }
+
+        CommandError::InvalidField {
+            context,
+            field: path.to_owned(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(context) = self.context() {
+            write!(f, "{}", context.command_type)?;
+            if let Some(topic_uuid) = &context.topic_uuid {
+                write!(f, " for {}", topic_uuid)?;
+            }
+            write!(f, ": ")?;
+        }
+
+        match self {
+            CommandError::NotACommand => write!(f, "not a command"),
+            CommandError::UnknownCommandType { .. } => write!(f, "unknown command_type"),
+            CommandError::MissingField { field, .. } => write!(f, "missing field {}", field),
+            CommandError::InvalidField { field, reason, .. } => {
+                write!(f, "invalid field {}: {}", field, reason)
+            }
+            CommandError::ConnectionNotFound { .. } => write!(f, "connection not found"),
+            CommandError::InvalidConnection { field, .. } => {
+                write!(f, "invalid connection field {}", field)
+            }
+            CommandError::ActuatorNotFound {
+                target_topic_name, ..
+            } => write!(f, "{} actuator not found", target_topic_name),
+            CommandError::UnsupportedTarget {
+                target_topic_name, ..
+            } => write!(f, "command not supported by {}", target_topic_name),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+impl CommandMessage {
+    pub fn parse(message: &serde_json::Value) -> Result<Self, CommandError> {
+        let command = match message.get("command") {
+            Some(command) => command,
+            None => return Err(CommandError::NotACommand),
+        };
+
+        let context = CommandContext {
+            command_type: command
+                .get("command_type")
+                .and_then(|c| c.as_str())
+                .unwrap_or_default()
+                .to_owned(),
+            topic_uuid: command
+                .get("value")
+                .and_then(|v| v.get("topic_uuid"))
+                .and_then(|t| t.as_str())
+                .map(str::to_owned),
+            request_id: command
+                .get("request_id")
+                .and_then(|r| r.as_str())
+                .map(str::to_owned),
+        };
+
+        if context.command_type.is_empty() {
+            return Err(CommandError::MissingField {
+                context,
+                field: "command_type".to_owned(),
+            });
+        }
+
+        match serde_path_to_error::deserialize(command) {
+            Ok(command) => Ok(CommandMessage { command, context }),
+            Err(e) => Err(CommandError::from_deserialize(context, e)),
+        }
+    }
+}
+
+/// Outcome of a command, published back on the DHT as volatile
+/// `command_result` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandState {
+    Rejected,
+    Delivered,
+    /// Not confirmed yet, the command is sent again until the device
+    /// reports the desired state or the queue gives up. The final state
+    /// follows in another `command_result`.
+    Queued,
+    /// Replaced by a newer command for the same channel while queued, it
+    /// is not sent again.
+    Superseded,
+    DeviceOffline,
+    UnknownTarget,
+    Timeout,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandResult {
+    #[serde(flatten)]
+    pub context: CommandContext,
+    pub state: CommandState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl CommandResult {
+    pub fn new(context: &CommandContext, state: CommandState) -> Self {
+        CommandResult {
+            context: context.to_owned(),
+            state,
+            reason: None,
+        }
+    }
+
+    /// Result reported for a command that could not be dispatched, only
+    /// when the command carries a request_id.
+    pub fn from_error(err: &CommandError) -> Option<Self> {
+        err.context()?.request_id.as_ref()?;
+
+        Self::rejected(err)
+    }
+
+    /// Result of a command that could not be dispatched, `None` when the
+    /// message was not a command.
+    pub fn rejected(err: &CommandError) -> Option<Self> {
+        let context = err.context()?;
+
+        let state = match err {
+            CommandError::ConnectionNotFound { .. }
+            | CommandError::InvalidConnection { .. }
+            | CommandError::ActuatorNotFound { .. } => CommandState::UnknownTarget,
+            _ => CommandState::Rejected,
+        };
+
+        Some(CommandResult {
+            context: context.to_owned(),
+            state,
+            reason: Some(err.to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "command_type", content = "value")]
+pub enum Command {
+    #[serde(rename = "shelly_actuator_command")]
+    ShellyAction(ShellyAction),
+    #[serde(rename = "radiator_valve_command")]
+    RadiatorValve(ValveAction),
+    #[serde(rename = "turn_command")]
+    Turn(TurnCommand),
+    #[serde(rename = "dim_command")]
+    Dim(DimCommand),
+    #[serde(rename = "rgbw_command")]
+    Rgbw(RgbwCommand),
+    #[serde(rename = "shutter_command")]
+    Shutter(ShutterCommand),
+    #[serde(rename = "valve_command")]
+    Valve(ValveCommand),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TurnCommand {
+    pub topic_uuid: String,
+    pub desired_state: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DimCommand {
+    pub topic_uuid: String,
+    pub desired_state: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RgbwValue {
+    pub r: u64,
+    pub g: u64,
+    pub b: u64,
+    pub w: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RgbwCommand {
+    pub topic_uuid: String,
+    pub desired_state: RgbwValue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutterDirection {
+    Up,
+    Down,
+    Stop,
+}
+
+impl ShutterDirection {
+    /// Value of `shutter_command` understood by the actuator firmware.
+    pub fn code(&self) -> u64 {
+        match self {
+            ShutterDirection::Up => 0,
+            ShutterDirection::Down => 1,
+            ShutterDirection::Stop => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShutterCommand {
+    pub topic_uuid: String,
+    pub shutter_command: ShutterDirection,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValveCommand {
+    pub topic_uuid: String,
+    pub desired_state: bool,
+}
+
+/// Status an actuator reports once a command has been applied, used to
+/// confirm the command. Field names may address nested objects with dots,
+/// e.g. `rgbw_status.r`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExpectedStatus {
+    pub channel: u64,
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ExpectedStatus {
+    pub fn new(channel: u64) -> Self {
+        ExpectedStatus {
+            channel,
+            fields: serde_json::Map::new(),
+        }
+    }
+
+    pub fn with(mut self, field: &str, value: serde_json::Value) -> Self {
+        self.fields.insert(field.to_owned(), value);
+        self
+    }
+
+    pub fn matches(&self, status: &serde_json::Value) -> bool {
+        self.fields.iter().all(|(field, expected)| {
+            let current = field
+                .split('.')
+                .try_fold(status, |value, key| value.get(key));
+
+            match current {
+                Some(current) if current.is_number() && expected.is_number() => {
+                    current.as_f64() == expected.as_f64()
+                }
+                Some(current) => current == expected,
+                None => false,
+            }
+        })
+    }
+}
+
+/// Action ready to be delivered to a Shelly or to an ESP32 actuator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShellyAction {
+    pub mac_address: String,
+    pub shelly_action: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_status: Option<ExpectedStatus>,
+}
+
+/// Radiator valve action, relayed through the ESP32 actuator that
+/// currently receives the valve with the best RSSI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValveAction {
+    pub mac_address: String,
+    pub desired_state: bool,
+    pub shelly_action: serde_json::Value,
+}
+
+/// Builds the `shelly_action` object expected by the actuator firmware.
+pub fn shelly_action(action_name: &str, action_payload: &serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "input": {
+            "action": {
+                "action_name": action_name,
+                "action_payload": action_payload.to_string(),
+            },
+        },
+    })
+}
+
+/// Wraps a `shelly_action` object into a `requestAction` websocket message.
+pub fn request_action_message(shelly_action: &serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "messageType": "requestAction",
+        "data": {
+            "shelly_action": shelly_action
+        }
+    })
+}
+
+/// Mac address compared between the commands and the status updates, the
+/// Shelly report theirs without colons.
+pub fn normalize_mac(mac_address: &str) -> String {
+    mac_address.replace(':', "").to_lowercase()
+}
+
+impl ShellyAction {
+    pub fn new(mac_address: &str, action_name: &str, action_payload: &serde_json::Value) -> Self {
+        ShellyAction {
+            mac_address: mac_address.to_owned(),
+            shelly_action: shelly_action(action_name, action_payload),
+            expected_status: None,
+        }
+    }
+
+    pub fn expecting(mut self, expected_status: ExpectedStatus) -> Self {
+        self.expected_status = Some(expected_status);
+        self
+    }
+
+    pub fn to_message(&self) -> serde_json::Value {
+        request_action_message(&self.shelly_action)
+    }
+}
+
+impl ValveAction {
+    pub fn new(mac_address: &str, desired_state: bool) -> Self {
+        let action_payload = serde_json::json!({
+            "mac_address": mac_address,
+            "value": desired_state
+        });
+
+        ValveAction {
+            mac_address: mac_address.to_owned(),
+            desired_state,
+            shelly_action: shelly_action("control_radiator_valve", &action_payload),
+        }
+    }
+
+    pub fn to_message(&self) -> serde_json::Value {
+        request_action_message(&self.shelly_action)
+    }
+
+    /// Valves report their state in the `status` field of their topic.
+    pub fn expected_status(&self) -> ExpectedStatus {
+        ExpectedStatus::new(0).with("status", serde_json::Value::Bool(self.desired_state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_turn_command() {
+        let message = serde_json::json!({
+            "command": {
+                "command_type": "turn_command",
+                "value": {
+                    "topic_uuid": "light-1",
+                    "desired_state": true
+                }
+            }
+        });
+
+        let message = CommandMessage::parse(&message).unwrap();
+
+        match message.command {
+            Command::Turn(turn) => {
+                assert_eq!(turn.topic_uuid, "light-1");
+                assert!(turn.desired_state);
+            }
+            _ => panic!("expected turn command"),
+        }
+    }
+
+    #[test]
+    fn parse_shutter_command() {
+        let message = serde_json::json!({
+            "command": {
+                "command_type": "shutter_command",
+                "value": {
+                    "topic_uuid": "shutter-1",
+                    "shutter_command": "down"
+                }
+            }
+        });
+
+        let message = CommandMessage::parse(&message).unwrap();
+
+        match message.command {
+            Command::Shutter(shutter) => {
+                assert_eq!(shutter.shutter_command.code(), 1);
+            }
+            _ => panic!("expected shutter command"),
+        }
+    }
+
+    #[test]
+    fn malformed_rgbw_command_is_an_error() {
+        let message = serde_json::json!({
+            "command": {
+                "command_type": "rgbw_command",
+                "value": {
+                    "topic_uuid": "rgbw-1",
+                    "desired_state": { "r": 10, "g": 10, "b": "x", "w": 0 }
+                }
+            }
+        });
+
+        match CommandMessage::parse(&message) {
+            Err(CommandError::InvalidField { context, field, .. }) => {
+                assert_eq!(context.command_type, "rgbw_command");
+                assert_eq!(context.topic_uuid.as_deref(), Some("rgbw-1"));
+                assert_eq!(field, "desired_state.b");
+            }
+            _ => panic!("expected invalid field"),
+        }
+    }
+
+    #[test]
+    fn missing_field_is_named() {
+        let message = serde_json::json!({
+            "command": {
+                "command_type": "dim_command",
+                "request_id": "req-1",
+                "value": {
+                    "topic_uuid": "dimmer-1"
+                }
+            }
+        });
+
+        let err = CommandMessage::parse(&message).unwrap_err();
+
+        match &err {
+            CommandError::MissingField { field, .. } => assert_eq!(field, "desired_state"),
+            _ => panic!("expected missing field"),
+        }
+
+        let result = CommandResult::from_error(&err).unwrap();
+        assert_eq!(result.context.request_id.as_deref(), Some("req-1"));
+        assert_eq!(result.state, CommandState::Rejected);
+    }
+
+    #[test]
+    fn unknown_command_type() {
+        let message = serde_json::json!({
+            "command": {
+                "command_type": "fly_command",
+                "value": {}
+            }
+        });
+
+        assert!(matches!(
+            CommandMessage::parse(&message),
+            Err(CommandError::UnknownCommandType { .. })
+        ));
+
+        let message = serde_json::json!({ "command_result": {} });
+
+        assert!(matches!(
+            CommandMessage::parse(&message),
+            Err(CommandError::NotACommand)
+        ));
+    }
+
+    #[test]
+    fn expected_status_matches_nested_fields() {
+        let expected = ExpectedStatus::new(0)
+            .with("rgbw_status.r", serde_json::json!(10))
+            .with("output1", serde_json::json!(true));
+
+        assert!(expected.matches(&serde_json::json!({
+            "output1": true,
+            "rgbw_status": { "r": 10.0, "g": 0 }
+        })));
+        assert!(!expected.matches(&serde_json::json!({
+            "output1": false,
+            "rgbw_status": { "r": 10 }
+        })));
+        assert!(!expected.matches(&serde_json::json!({ "output1": true })));
+    }
+
+    #[test]
+    fn valve_action_wire_format() {
+        let action = ValveAction::new("aa:bb:cc:dd:ee:ff", true);
+
+        let message = action.to_message();
+        let action = &message["data"]["shelly_action"]["input"]["action"];
+
+        assert_eq!(message["messageType"], "requestAction");
+        assert_eq!(action["action_name"], "control_radiator_valve");
+
+        let payload: serde_json::Value =
+            serde_json::from_str(action["action_payload"].as_str().unwrap()).unwrap();
+        assert_eq!(payload["mac_address"], "aa:bb:cc:dd:ee:ff");
+        assert_eq!(payload["value"], true);
+    }
+}
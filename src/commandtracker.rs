@@ -0,0 +1,226 @@
+use crate::commands::{normalize_mac, CommandContext, CommandResult, CommandState, ExpectedStatus};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+struct PendingResult {
+    context: CommandContext,
+    mac_address: String,
+    /// Status reported once the command is applied, any status update of
+    /// the actuator confirms the commands without one.
+    expected_status: Option<ExpectedStatus>,
+    deadline: Instant,
+    /// Caller waiting for the outcome, such as a REST request.
+    responder: Option<oneshot::Sender<CommandResult>>,
+    /// The command is also in the retry queue, which publishes its outcome
+    /// on the DHT. Past the deadline the caller is told it is queued.
+    queued: bool,
+}
+
+impl PendingResult {
+    /// Hands the outcome to the waiting caller, and returns it when it has
+    /// to be published on the DHT.
+    fn resolve(&mut self, state: CommandState) -> Option<CommandResult> {
+        let result = CommandResult::new(&self.context, state);
+
+        if let Some(responder) = self.responder.take() {
+            let _ret = responder.send(result.clone());
+        }
+
+        if self.queued {
+            return None;
+        }
+
+        self.context.request_id.as_ref().map(|_| result)
+    }
+}
+
+/// Keeps the commands carrying a request_id, or awaited by a caller, until
+/// the target actuator reports the expected status or the deadline expires.
+pub struct CommandTracker {
+    timeout: Duration,
+    pending: Vec<PendingResult>,
+}
+
+impl CommandTracker {
+    pub fn new(timeout: Duration) -> Self {
+        CommandTracker {
+            timeout,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn track(
+        &mut self,
+        context: &CommandContext,
+        mac_address: &str,
+        expected_status: Option<ExpectedStatus>,
+    ) {
+        if context.request_id.is_none() {
+            return;
+        }
+
+        self.pending.push(PendingResult {
+            context: context.to_owned(),
+            mac_address: normalize_mac(mac_address),
+            expected_status,
+            deadline: Instant::now() + self.timeout,
+            responder: None,
+            queued: false,
+        });
+    }
+
+    /// Tracks the command even without request_id, its outcome is sent to
+    /// `responder`. The outcome of a `queued` command is left to the queue.
+    pub fn track_with_responder(
+        &mut self,
+        context: &CommandContext,
+        mac_address: &str,
+        expected_status: Option<ExpectedStatus>,
+        queued: bool,
+        responder: oneshot::Sender<CommandResult>,
+    ) {
+        self.pending.push(PendingResult {
+            context: context.to_owned(),
+            mac_address: normalize_mac(mac_address),
+            expected_status,
+            deadline: Instant::now() + self.timeout,
+            responder: Some(responder),
+            queued,
+        });
+    }
+
+    /// Called for every status update received from an actuator, resolves
+    /// the commands whose expected status is satisfied by `status`.
+    pub fn confirm(&mut self, mac_address: &str, status: &serde_json::Value) -> Vec<CommandResult> {
+        let mac_address = normalize_mac(mac_address);
+        let mut results = Vec::new();
+
+        self.pending.retain_mut(|p| {
+            let applied = match &p.expected_status {
+                Some(expected) => expected.matches(status),
+                None => true,
+            };
+
+            if p.mac_address == mac_address && applied {
+                results.extend(p.resolve(CommandState::Delivered));
+                false
+            } else {
+                true
+            }
+        });
+
+        results
+    }
+
+    pub fn expired(&mut self) -> Vec<CommandResult> {
+        let now = Instant::now();
+        let mut results = Vec::new();
+
+        self.pending.retain_mut(|p| {
+            if p.deadline <= now {
+                let state = if p.queued {
+                    CommandState::Queued
+                } else {
+                    CommandState::Timeout
+                };
+                results.extend(p.resolve(state));
+                false
+            } else {
+                true
+            }
+        });
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(request_id: Option<&str>) -> CommandContext {
+        CommandContext {
+            command_type: "turn_command".to_owned(),
+            topic_uuid: Some("light-1".to_owned()),
+            request_id: request_id.map(str::to_owned),
+        }
+    }
+
+    fn turn_on() -> Option<ExpectedStatus> {
+        Some(ExpectedStatus::new(0).with("output0", serde_json::json!(true)))
+    }
+
+    #[test]
+    fn status_update_confirms_command() {
+        let mut tracker = CommandTracker::new(Duration::from_secs(10));
+        let status = serde_json::json!({ "output0": true });
+
+        tracker.track(&context(Some("req-1")), "AA:BB:CC:DD:EE:FF", turn_on());
+        tracker.track(&context(None), "AA:BB:CC:DD:EE:FF", turn_on());
+
+        assert!(tracker.confirm("112233445566", &status).is_empty());
+
+        let results = tracker.confirm("aabbccddeeff", &status);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].state, CommandState::Delivered);
+        assert!(tracker.expired().is_empty());
+    }
+
+    #[test]
+    fn unrelated_status_does_not_confirm() {
+        let mut tracker = CommandTracker::new(Duration::from_secs(10));
+
+        tracker.track(&context(Some("req-1")), "aa:bb:cc:dd:ee:ff", turn_on());
+
+        // a power report, or the output still off
+        let power = serde_json::json!({ "power0": 12.5 });
+        assert!(tracker.confirm("aabbccddeeff", &power).is_empty());
+        let off = serde_json::json!({ "output0": false });
+        assert!(tracker.confirm("aabbccddeeff", &off).is_empty());
+
+        let on = serde_json::json!({ "output0": true, "power0": 12.5 });
+        assert_eq!(tracker.confirm("aabbccddeeff", &on).len(), 1);
+    }
+
+    #[test]
+    fn missing_status_update_times_out() {
+        let mut tracker = CommandTracker::new(Duration::ZERO);
+
+        tracker.track(&context(Some("req-1")), "aa:bb:cc:dd:ee:ff", turn_on());
+
+        let results = tracker.expired();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].state, CommandState::Timeout);
+    }
+
+    #[test]
+    fn responder_receives_the_outcome() {
+        let mut tracker = CommandTracker::new(Duration::from_secs(10));
+        let (tx, mut rx) = oneshot::channel();
+
+        tracker.track_with_responder(&context(None), "aa:bb:cc:dd:ee:ff", turn_on(), false, tx);
+
+        // without request_id nothing is published on the DHT
+        let status = serde_json::json!({ "output0": true });
+        assert!(tracker.confirm("aabbccddeeff", &status).is_empty());
+        assert_eq!(rx.try_recv().unwrap().state, CommandState::Delivered);
+    }
+
+    #[test]
+    fn queued_command_outcome_is_left_to_the_queue() {
+        let mut tracker = CommandTracker::new(Duration::ZERO);
+        let (tx, mut rx) = oneshot::channel();
+
+        tracker.track_with_responder(
+            &context(Some("req-1")),
+            "aa:bb:cc:dd:ee:ff",
+            turn_on(),
+            true,
+            tx,
+        );
+
+        // the queue publishes the final state once it confirms or gives up
+        assert!(tracker.expired().is_empty());
+        assert_eq!(rx.try_recv().unwrap().state, CommandState::Queued);
+    }
+}
@@ -0,0 +1,73 @@
+use argon2::Argon2;
+use password_hash::{PasswordHash, PasswordHasher, SaltString};
+use pbkdf2::Pbkdf2;
+use subtle::ConstantTimeEq;
+
+/// Hashes a device password with Argon2id and a random salt, the result is
+/// the PHC string to store in the `user_password_hash` field.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Verifies a password against an Argon2 or PBKDF2 PHC string.
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    let password_hash = match PasswordHash::new(password_hash) {
+        Ok(password_hash) => password_hash,
+        Err(e) => {
+            log::warn!("Invalid user_password_hash: {}", e);
+            return false;
+        }
+    };
+
+    password_hash
+        .verify_password(&[&Argon2::default(), &Pbkdf2], password)
+        .is_ok()
+}
+
+/// Compares a password with a plaintext one still stored in the DHT,
+/// without leaking where they differ.
+pub fn plaintext_matches(password: &str, stored: &str) -> bool {
+    password.as_bytes().ct_eq(stored.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn argon2_hash_round_trip() {
+        let hash = hash_password("secret").unwrap();
+
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("Secret", &hash));
+        assert!(!verify_password("secret", "secret"));
+    }
+
+    #[test]
+    fn pbkdf2_hash_is_accepted() {
+        let salt = SaltString::generate(&mut rand::rngs::OsRng);
+        let params = pbkdf2::Params {
+            rounds: 1000,
+            output_length: 32,
+        };
+        let hash = Pbkdf2
+            .hash_password_customized("secret".as_bytes(), None, None, params, &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("other", &hash));
+    }
+
+    #[test]
+    fn plaintext_comparison() {
+        assert!(plaintext_matches("secret", "secret"));
+        assert!(!plaintext_matches("secret", "secret2"));
+    }
+}
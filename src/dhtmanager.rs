@@ -1,48 +1,280 @@
-use sifis_dht::domocache::DomoEvent;
+use chrono::{DateTime, Local};
+use sifis_dht::domocache::{DomoCache, DomoEvent};
 use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
 
+use crate::authguard::{self, AuthLogEntry};
 use crate::command_parser;
+use crate::commandqueue::CommandFailure;
+use crate::commands::{
+    Command, CommandContext, CommandError, CommandMessage, CommandResult, DimCommand, RgbwCommand,
+    ShellyAction, ShutterCommand, TurnCommand, ValveAction,
+};
+use crate::credentials;
+use crate::drivers::{self, Transport};
+use crate::energy::{self, EnergyHistory};
+use crate::heartbeat::{self, BridgeStatus};
+use crate::metrics::metrics;
+use crate::presence;
+use crate::supervisor::TaskResult;
+
+const REVOKED_CERTIFICATE_TOPIC_NAME: &str = "domo_revoked_certificate";
+
+/// Requests queued for the DHT task before the callers wait.
+const REQUEST_QUEUE_SIZE: usize = 64;
+
+/// Volatile messages queued for the command task before new ones are
+/// dropped.
+const VOLATILE_QUEUE_SIZE: usize = 64;
 
 pub enum DHTCommand {
-    ActuatorCommand(serde_json::Value),
-    ValveCommand(serde_json::Value),
+    ActuatorCommand(ShellyAction, CommandContext),
+    ValveCommand(ValveAction, CommandContext),
+}
+
+type ReadResponder = oneshot::Sender<Result<serde_json::Value, String>>;
+
+enum DHTRequest {
+    GetTopicName {
+        topic_name: String,
+        responder: ReadResponder,
+    },
+    GetTopicUuid {
+        topic_name: String,
+        topic_uuid: String,
+        responder: ReadResponder,
+    },
+    Write {
+        topic_name: String,
+        topic_uuid: String,
+        value: serde_json::Value,
+    },
+    Publish {
+        message: serde_json::Value,
+    },
+    /// Answered once the requests queued before it are served.
+    Flush {
+        responder: oneshot::Sender<()>,
+    },
 }
 
+/// Handle of the task owning the DHT cache.
+///
+/// Writes are queued without waiting for the DHT, so that a slow write
+/// holds up the DHT task only.
+#[derive(Clone)]
 pub struct DHTManager {
-    pub cache: sifis_dht::domocache::DomoCache,
+    tx: mpsc::Sender<DHTRequest>,
+}
+
+/// State shared by the successive runs of the DHT task.
+#[derive(Clone)]
+pub struct DHTTask {
+    cache: Arc<tokio::sync::Mutex<DomoCache>>,
+    requests: Arc<tokio::sync::Mutex<mpsc::Receiver<DHTRequest>>>,
+    tx_volatile: mpsc::Sender<serde_json::Value>,
+}
+
+/// Opens the DHT cache, returns the handle of its task, the task and the
+/// receiver of the volatile messages.
+pub async fn channel(
+    cache_config: sifis_config::Cache,
+) -> Result<(DHTManager, DHTTask, mpsc::Receiver<serde_json::Value>), Box<dyn Error>> {
+    let cache = DomoCache::new(cache_config).await?;
+
+    let (tx, rx) = mpsc::channel(REQUEST_QUEUE_SIZE);
+    let (tx_volatile, rx_volatile) = mpsc::channel(VOLATILE_QUEUE_SIZE);
+
+    let task = DHTTask {
+        cache: Arc::new(tokio::sync::Mutex::new(cache)),
+        requests: Arc::new(tokio::sync::Mutex::new(rx)),
+        tx_volatile,
+    };
+
+    Ok((DHTManager { tx }, task, rx_volatile))
+}
+
+impl DHTTask {
+    /// Serves the requests of the other tasks and forwards the volatile
+    /// messages received from the DHT.
+    pub async fn run(self) -> TaskResult {
+        let mut cache = self.cache.lock().await;
+        let mut requests = self.requests.lock().await;
+
+        loop {
+            tokio::select! {
+                request = requests.recv() => {
+                    let request = match request {
+                        Some(request) => request,
+                        None => return Ok(()),
+                    };
+
+                    let _timer = metrics().event_loop_latency.with_label_values(&["dht_request"]).start_timer();
+
+                    serve(&mut cache, request).await;
+                }
+                event = next_event(&mut cache) => {
+                    match event {
+                        Ok(DomoEvent::VolatileData(message)) => {
+                            // the DHT task never waits for the command task,
+                            // which waits for the DHT task
+                            if let Err(e) = self.tx_volatile.try_send(message) {
+                                log::warn!("Dropping DHT message: {}", e);
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => log::warn!("DHT event loop error: {}", e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn next_event(cache: &mut DomoCache) -> Result<DomoEvent, String> {
+    cache.cache_event_loop().await.map_err(|e| e.to_string())
+}
+
+async fn serve(cache: &mut DomoCache, request: DHTRequest) {
+    match request {
+        DHTRequest::GetTopicName {
+            topic_name,
+            responder,
+        } => {
+            let topics = cache.get_topic_name(&topic_name).map_err(|e| e.to_string());
+            let _ret = responder.send(topics);
+        }
+        DHTRequest::GetTopicUuid {
+            topic_name,
+            topic_uuid,
+            responder,
+        } => {
+            let topic = cache
+                .get_topic_uuid(&topic_name, &topic_uuid)
+                .map_err(|e| e.to_string());
+            let _ret = responder.send(topic);
+        }
+        DHTRequest::Write {
+            topic_name,
+            topic_uuid,
+            value,
+        } => cache.write_value(&topic_name, &topic_uuid, value).await,
+        DHTRequest::Publish { message } => cache.pub_value(message).await,
+        DHTRequest::Flush { responder } => {
+            let _ret = responder.send(());
+        }
+    }
 }
 
 impl DHTManager {
-    pub async fn new(cache_config: sifis_config::Cache) -> Result<DHTManager, Box<dyn Error>> {
-        let sifis_cache = sifis_dht::domocache::DomoCache::new(cache_config).await?;
+    pub async fn get_topic_name(
+        &self,
+        topic_name: &str,
+    ) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+        let (responder, rx) = oneshot::channel();
+
+        self.read(
+            DHTRequest::GetTopicName {
+                topic_name: topic_name.to_owned(),
+                responder,
+            },
+            rx,
+        )
+        .await
+    }
+
+    pub async fn get_topic_uuid(
+        &self,
+        topic_name: &str,
+        topic_uuid: &str,
+    ) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+        let (responder, rx) = oneshot::channel();
+
+        self.read(
+            DHTRequest::GetTopicUuid {
+                topic_name: topic_name.to_owned(),
+                topic_uuid: topic_uuid.to_owned(),
+                responder,
+            },
+            rx,
+        )
+        .await
+    }
+
+    async fn read(
+        &self,
+        request: DHTRequest,
+        rx: oneshot::Receiver<Result<serde_json::Value, String>>,
+    ) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+        self.tx
+            .send(request)
+            .await
+            .map_err(|_| "DHT task stopped")?;
+
+        let topics = rx.await.map_err(|_| "DHT task stopped")?;
 
-        Ok(DHTManager { cache: sifis_cache })
+        Ok(topics?)
     }
 
+    async fn request(&self, request: DHTRequest) {
+        if self.tx.send(request).await.is_err() {
+            log::warn!("DHT task not accepting requests");
+        }
+    }
+
+    /// Waits until the writes queued so far are handed to the DHT.
+    pub async fn flush(&self) {
+        let (responder, rx) = oneshot::channel();
+
+        self.request(DHTRequest::Flush { responder }).await;
+        let _ret = rx.await;
+    }
+
+    /// Checks Basic credentials against the ESP32 actuator topics. The
+    /// password is verified against `user_password_hash`, or against the
+    /// plaintext `user_password` of topics not migrated yet when
+    /// `allow_plaintext` is set.
     pub async fn get_auth_cred(
-        &mut self,
+        &self,
         user: &str,
         password: &str,
-    ) -> Result<serde_json::Value, Box<dyn Error>> {
-        let shelly_plus_topic_names = vec!["shelly_1plus", "shelly_1pm_plus", "shelly_2pm_plus"];
-
-        for topic in shelly_plus_topic_names {
-            let shelly_plus_topics = self.cache.get_topic_name(topic)?;
+        allow_plaintext: bool,
+    ) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+        for topic in drivers::topic_names_with_transport(Transport::Esp32) {
+            let shelly_plus_topics = self.get_topic_name(topic).await?;
 
             let topics = shelly_plus_topics.as_array().unwrap();
             for t in topics.iter() {
                 if let Some(value) = t.get("value") {
                     if let Some(user_login) = value.get("user_login") {
-                        if let Some(user_password) = value.get("user_password") {
-                            let user_login_str = user_login.as_str().unwrap();
-                            let user_password_str = user_password.as_str().unwrap();
-                            if user_login_str == user && user_password_str == password {
-                                let mac = value.get("mac_address").unwrap().as_str().unwrap();
-                                if let Some(topic_name) = t.get("topic_name") {
-                                    let topic_name = topic_name.as_str().unwrap().to_owned();
-                                    let json_ret = serde_json::json!({ "mac_address": mac, "topic": topic_name });
-                                    return Ok(json_ret);
+                        if user_login.as_str() != Some(user) {
+                            continue;
+                        }
+
+                        let verified = match value.get("user_password_hash") {
+                            Some(hash) => {
+                                credentials::verify_password(password, hash.as_str().unwrap_or(""))
+                            }
+                            None => match value.get("user_password").and_then(|p| p.as_str()) {
+                                Some(stored) if allow_plaintext => {
+                                    log::warn!(
+                                        "Plaintext password of {} should be migrated to user_password_hash",
+                                        user
+                                    );
+                                    credentials::plaintext_matches(password, stored)
                                 }
+                                _ => false,
+                            },
+                        };
+
+                        if verified {
+                            let mac = value.get("mac_address").unwrap().as_str().unwrap();
+                            if let Some(topic_name) = t.get("topic_name") {
+                                let topic_name = topic_name.as_str().unwrap().to_owned();
+                                let json_ret =
+                                    serde_json::json!({ "mac_address": mac, "topic": topic_name });
+                                return Ok(json_ret);
                             }
                         }
                     }
@@ -53,12 +285,79 @@ impl DHTManager {
         Err("cred not found".into())
     }
 
-    pub fn get_topic(
-        &mut self,
+    /// Tells whether the certificate serial or the device mac address is
+    /// listed in the `domo_revoked_certificate` topics. A certificate that
+    /// cannot be checked counts as revoked.
+    pub async fn is_certificate_revoked(&self, mac_address: &str, serial: &str) -> bool {
+        let revoked = match self.get_topic_name(REVOKED_CERTIFICATE_TOPIC_NAME).await {
+            Ok(revoked) => revoked,
+            Err(e) => {
+                log::error!(
+                    "Cannot read {}, refusing certificate {} of {}: {}",
+                    REVOKED_CERTIFICATE_TOPIC_NAME,
+                    serial,
+                    mac_address,
+                    e
+                );
+                return true;
+            }
+        };
+
+        let matches = |value: &serde_json::Value, field: &str, expected: &str| {
+            value[field]
+                .as_str()
+                .map(|v| {
+                    v.replace(':', "")
+                        .eq_ignore_ascii_case(&expected.replace(':', ""))
+                })
+                .unwrap_or(false)
+        };
+
+        revoked
+            .as_array()
+            .map(|topics| {
+                topics.iter().any(|t| {
+                    matches(&t["value"], "serial", serial)
+                        || matches(&t["value"], "mac_address", mac_address)
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Looks up the ESP32 actuator owning the mac address of a verified
+    /// client certificate.
+    pub async fn get_auth_cert(
+        &self,
+        mac_address: &str,
+        serial: &str,
+    ) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+        if self.is_certificate_revoked(mac_address, serial).await {
+            return Err("cert revoked".into());
+        }
+
+        let mac_address = mac_address.replace(':', "");
+
+        for topic in drivers::topic_names_with_transport(Transport::Esp32) {
+            if let Ok(topics) = self.get_topic_name(topic).await {
+                for t in topics.as_array().unwrap() {
+                    if let Some(mac) = t["value"]["mac_address"].as_str() {
+                        if mac.replace(':', "").eq_ignore_ascii_case(&mac_address) {
+                            return Ok(serde_json::json!({ "mac_address": mac, "topic": topic }));
+                        }
+                    }
+                }
+            }
+        }
+
+        Err("cert not found".into())
+    }
+
+    pub async fn get_topic(
+        &self,
         topic_name: &str,
         mac_address: &str,
-    ) -> Result<serde_json::Value, Box<dyn Error>> {
-        if let Ok(actuators) = self.cache.get_topic_name(topic_name) {
+    ) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+        if let Ok(actuators) = self.get_topic_name(topic_name).await {
             for act in actuators.as_array().unwrap() {
                 if let Some(value) = act.get("value") {
                     if let Some(mac) = value.get("mac_address") {
@@ -74,26 +373,11 @@ impl DHTManager {
     }
 
     pub async fn get_actuator_from_mac_address(
-        &mut self,
+        &self,
         mac_address_req: &str,
-    ) -> Result<serde_json::Value, Box<dyn Error>> {
-        let actuator_topics = [
-            "shelly_1",
-            "shelly_1pm",
-            "shelly_1plus",
-            "shelly_em",
-            "shelly_1pm_plus",
-            "shelly_2pm_plus",
-            "shelly_25",
-            "shelly_dimmer",
-            "shelly_rgbw",
-            "domo_ble_thermometer",
-            "domo_ble_valve",
-            "domo_ble_contact",
-        ];
-
-        for act_type in actuator_topics {
-            if let Ok(actuators) = self.cache.get_topic_name(act_type) {
+    ) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+        for act_type in drivers::topic_names() {
+            if let Ok(actuators) = self.get_topic_name(act_type).await {
                 for act in actuators.as_array().unwrap() {
                     if let Some(value) = act.get("value") {
                         if let Some(mac) = value.get("mac_address") {
@@ -109,68 +393,155 @@ impl DHTManager {
         Err("err".into())
     }
 
-    pub async fn write_topic(
-        &mut self,
-        topic_name: &str,
-        topic_uuid: &str,
-        value: &serde_json::Value,
-    ) {
-        self.cache
-            .write_value(topic_name, topic_uuid, value.to_owned())
-            .await;
+    pub async fn write_topic(&self, topic_name: &str, topic_uuid: &str, value: &serde_json::Value) {
+        self.request(DHTRequest::Write {
+            topic_name: topic_name.to_owned(),
+            topic_uuid: topic_uuid.to_owned(),
+            value: value.to_owned(),
+        })
+        .await;
+
+        metrics().dht_writes.with_label_values(&[topic_name]).inc();
     }
 
-    async fn handle_volatile_command(
+    /// Updates `online` and `last_seen` in the topic of the actuator or BLE
+    /// device with `mac_address`, keeping the rest of its value.
+    pub async fn write_presence(
         &self,
-        command: serde_json::Value,
-    ) -> Result<DHTCommand, Box<dyn Error>> {
-        if let Some(command) = command.get("command") {
-            if let Some(command_type) = command.get("command_type") {
-                if command_type == "shelly_actuator_command" {
-                    if let Some(value) = command.get("value") {
-                        return Ok(DHTCommand::ActuatorCommand(value.to_owned()));
-                    }
-                }
+        mac_address: &str,
+        online: bool,
+        last_seen: DateTime<Local>,
+    ) {
+        if let Ok(topic) = self.get_actuator_from_mac_address(mac_address).await {
+            if let (Some(topic_name), Some(topic_uuid)) =
+                (topic["topic_name"].as_str(), topic["topic_uuid"].as_str())
+            {
+                let mut value = topic["value"].to_owned();
+                presence::stamp(&mut value, online, last_seen);
 
-                if command_type == "radiator_valve_command" {
-                    if let Some(value) = command.get("value") {
-                        return Ok(DHTCommand::ValveCommand(value.to_owned()));
-                    }
-                }
+                self.write_topic(topic_name, topic_uuid, &value).await;
+            }
+        }
+    }
 
-                if command_type == "turn_command" {
-                    return command_parser::handle_turn_command(self, command).await;
-                }
+    pub async fn get_energy_baseline(&self, key: &str) -> Option<f64> {
+        self.get_topic_uuid(energy::BASELINE_TOPIC_NAME, key)
+            .await
+            .ok()
+            .and_then(|topic| topic["value"]["counter"].as_f64())
+    }
 
-                if command_type == "valve_command" {
-                    return command_parser::handle_valve_command(self, command).await;
-                }
+    pub async fn write_energy_baseline(&self, key: &str, counter: f64) {
+        let value = serde_json::json!({ "counter": counter });
 
-                if command_type == "dim_command" {
-                    return command_parser::handle_dim_command(self, command).await;
-                }
+        self.write_topic(energy::BASELINE_TOPIC_NAME, key, &value)
+            .await;
+    }
 
-                if command_type == "rgbw_command" {
-                    return command_parser::handle_rgbw_command(self, command).await;
-                }
+    pub async fn get_energy_history(&self, topic_uuid: &str) -> Option<EnergyHistory> {
+        let topic = self
+            .get_topic_uuid(energy::HISTORY_TOPIC_NAME, topic_uuid)
+            .await
+            .ok()?;
 
-                if command_type == "shutter_command" {
-                    return command_parser::handle_shutter_command(self, command).await;
-                }
-            }
+        serde_json::from_value(topic["value"].to_owned()).ok()
+    }
+
+    pub async fn write_energy_history(&self, topic_uuid: &str, history: &EnergyHistory) {
+        if let Ok(value) = serde_json::to_value(history) {
+            self.write_topic(energy::HISTORY_TOPIC_NAME, topic_uuid, &value)
+                .await;
+        }
+    }
+
+    pub async fn write_bridge_status(&self, status: &BridgeStatus) {
+        if let Ok(value) = serde_json::to_value(status) {
+            self.write_topic(
+                heartbeat::STATUS_TOPIC_NAME,
+                &status.node_id.to_string(),
+                &value,
+            )
+            .await;
         }
+    }
+
+    /// Writes `entry` to the authentication audit log, replacing the entry
+    /// stored in `slot`.
+    pub async fn write_auth_log(&self, slot: usize, entry: &AuthLogEntry) {
+        if let Ok(value) = serde_json::to_value(entry) {
+            self.write_topic(authguard::AUTH_LOG_TOPIC_NAME, &slot.to_string(), &value)
+                .await;
+        }
+    }
 
-        Err("not able to parse message".into())
+    /// Slot of the audit log following the newest entry, so that a restarted
+    /// bridge overwrites the oldest entries first.
+    pub async fn auth_log_next_slot(&self, capacity: usize) -> usize {
+        let topics = match self.get_topic_name(authguard::AUTH_LOG_TOPIC_NAME).await {
+            Ok(topics) => topics,
+            Err(_) => return 0,
+        };
+
+        let newest = topics
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|topic| {
+                let slot = topic["topic_uuid"].as_str()?.parse::<usize>().ok()?;
+                let timestamp = topic["value"]["timestamp"].as_str()?;
+                let timestamp = DateTime::parse_from_rfc3339(timestamp).ok()?;
+                Some((timestamp, slot))
+            })
+            .filter(|(_, slot)| *slot < capacity)
+            .max();
+
+        newest.map_or(0, |(_, slot)| slot + 1)
     }
 
-    pub async fn wait_dht_messages(&mut self) -> Result<DHTCommand, Box<dyn Error>> {
-        let data = self.cache.cache_event_loop().await?;
+    /// Parses a `command` message and resolves its target.
+    pub async fn handle_volatile_command(
+        &self,
+        message: serde_json::Value,
+    ) -> Result<DHTCommand, CommandError> {
+        let message = CommandMessage::parse(&message)?;
+        let context = &message.context;
+
+        metrics()
+            .commands_received
+            .with_label_values(&[&context.command_type])
+            .inc();
 
-        if let DomoEvent::VolatileData(m) = data {
-            //println!("RECEIVED COMMAND{}", m);
-            return self.handle_volatile_command(m.to_owned()).await;
+        match &message.command {
+            Command::ShellyAction(action) => Ok(DHTCommand::ActuatorCommand(
+                action.to_owned(),
+                context.to_owned(),
+            )),
+            Command::RadiatorValve(action) => Ok(DHTCommand::ValveCommand(
+                action.to_owned(),
+                context.to_owned(),
+            )),
+            Command::Valve(command) => {
+                command_parser::handle_valve_command(self, context, command).await
+            }
+            Command::Turn(TurnCommand { topic_uuid, .. })
+            | Command::Dim(DimCommand { topic_uuid, .. })
+            | Command::Rgbw(RgbwCommand { topic_uuid, .. })
+            | Command::Shutter(ShutterCommand { topic_uuid, .. }) => {
+                command_parser::handle_actuator_command(self, context, topic_uuid, &message.command)
+                    .await
+            }
         }
+    }
+
+    pub async fn publish_command_result(&self, result: &CommandResult) {
+        let message = serde_json::json!({ "command_result": result });
+
+        self.request(DHTRequest::Publish { message }).await;
+    }
+
+    pub async fn publish_command_failure(&self, failure: &CommandFailure) {
+        let message = serde_json::json!({ "command_failure": failure });
 
-        Err("not a volatile message".into())
+        self.request(DHTRequest::Publish { message }).await;
     }
 }
@@ -0,0 +1,173 @@
+use chrono::Local;
+use futures_util::{pin_mut, stream::StreamExt};
+use mdns::{Record, RecordKind};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::api::{DiscoveredDevice, SharedDiscoveries};
+use crate::drivers::{self, Transport};
+use crate::metrics::metrics;
+use crate::supervisor::TaskResult;
+use crate::{BridgeHandles, ShellyDiscoveryResult};
+
+const SERVICE_NAME: &str = "_webthing._tcp.local";
+
+/// Connects the Shelly announced over mDNS on the bridge network.
+#[derive(Clone)]
+pub struct DiscoveryTask {
+    bridge: BridgeHandles,
+    node_id: u8,
+    discovered: SharedDiscoveries,
+}
+
+impl DiscoveryTask {
+    pub fn new(bridge: BridgeHandles, node_id: u8, discovered: SharedDiscoveries) -> Self {
+        DiscoveryTask {
+            bridge,
+            node_id,
+            discovered,
+        }
+    }
+
+    pub async fn run(self) -> TaskResult {
+        let mut shutdown = self.bridge.shutdown.clone();
+
+        let stream = mdns::discover::interface(
+            SERVICE_NAME,
+            Duration::from_secs(5),
+            Ipv4Addr::new(10, 0, self.node_id, 1),
+        )?
+        .listen();
+
+        pin_mut!(stream);
+
+        loop {
+            let response = tokio::select! {
+                _ = shutdown.changed() => return Ok(()),
+                response = stream.next() => response,
+            };
+
+            let response = match response {
+                Some(Ok(response)) => response,
+                Some(Err(e)) => {
+                    log::warn!("mDNS error: {}", e);
+                    continue;
+                }
+                None => return Err("mDNS stream ended".into()),
+            };
+
+            let shelly_res = response
+                .records()
+                .filter_map(get_shelly_discovery_result)
+                .next();
+
+            if let Some(shelly) = shelly_res {
+                let _timer = metrics()
+                    .event_loop_latency
+                    .with_label_values(&["mdns"])
+                    .start_timer();
+
+                self.connect(shelly).await;
+            }
+        }
+    }
+
+    /// Connects the announced Shelly with the credentials of its topic.
+    async fn connect(&self, shelly: ShellyDiscoveryResult) {
+        self.bridge.health.lock().unwrap().discovered();
+
+        //println!("{} {} {}", shelly.topic_name, shelly.mac_address, shelly.ip_address);
+
+        self.discovered.lock().unwrap().insert(
+            shelly.mac_address.to_owned(),
+            DiscoveredDevice {
+                topic_name: shelly.topic_name.to_owned(),
+                mac_address: shelly.mac_address.to_owned(),
+                ip_address: shelly.ip_address.to_owned(),
+                mdns_name: shelly.mdns_name.to_owned(),
+                last_seen: Local::now(),
+            },
+        );
+
+        let topic = self
+            .bridge
+            .dht_manager
+            .get_actuator_from_mac_address(&shelly.mac_address)
+            .await;
+
+        if let Ok(t) = topic {
+            if let Some(value) = t.get("value") {
+                if let Some(user_login) = value.get("user_login") {
+                    if let Some(user_password) = value.get("user_password") {
+                        let user_login_str = user_login.as_str();
+                        let user_password_str = user_password.as_str();
+
+                        if let Some(user) = user_login_str {
+                            if let Some(password) = user_password_str {
+                                self.bridge.shelly_manager.connect(
+                                    shelly,
+                                    user.to_owned(),
+                                    password.to_owned(),
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn get_shelly_discovery_result(record: &Record) -> Option<ShellyDiscoveryResult> {
+    // ESP32 based actuators connect to the bridge on their own
+    if drivers::topic_names_with_transport(Transport::Esp32).any(|t| record.name.contains(t)) {
+        return None;
+    }
+
+    if !record.name.contains("shelly") && !record.name.contains("geeklink") {
+        return None;
+    }
+
+    let record_name = record.name.replace(".local", "");
+
+    match record.kind {
+        RecordKind::A(addr) => {
+            let name_parts: Vec<&str> = record_name.split('-').collect();
+            let topic_name = name_parts[0];
+            let mac_address = name_parts[1].to_owned();
+
+            let mac_address_with_points = mac_address[0..2].to_owned()
+                + ":"
+                + &mac_address[2..4]
+                + ":"
+                + &mac_address[4..6]
+                + ":"
+                + &mac_address[6..8]
+                + ":"
+                + &mac_address[8..10]
+                + ":"
+                + &mac_address[10..12];
+
+            let res = ShellyDiscoveryResult {
+                ip_address: addr.to_string(),
+                topic_name: topic_name.to_string(),
+                mac_address: mac_address_with_points,
+                mdns_name: record.name.to_owned(),
+            };
+            Some(res)
+        }
+        RecordKind::AAAA(addr) => {
+            let name_parts: Vec<&str> = record.name.split('-').collect();
+            let topic_name = name_parts[0];
+            let mac_address = name_parts[1];
+            let res = ShellyDiscoveryResult {
+                ip_address: addr.to_string(),
+                topic_name: topic_name.to_string(),
+                mac_address: mac_address.to_string(),
+                mdns_name: record.name.to_owned(),
+            };
+            Some(res)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,440 @@
+use crate::commands::{Command, ExpectedStatus, ShellyAction};
+
+/// How the bridge talks to a device family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Shelly gen1 with DoMO firmware, discovered through mDNS, the bridge
+    /// opens the websocket.
+    ShellyGen1,
+    /// ESP32 based actuators, they open a websocket towards the bridge.
+    Esp32,
+    /// BLE sensors, their beacons are relayed by the ESP32 actuators.
+    Ble,
+}
+
+/// Firmware working modes, the value is the one expected by `change_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Relay = 0,
+    Shutter = 1,
+    Dimmer = 2,
+    Rgbw = 3,
+    LedDimmer = 4,
+}
+
+impl Mode {
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+}
+
+pub trait DeviceDriver: Sync {
+    /// DHT topic name of the devices of the family.
+    fn topic_name(&self) -> &'static str;
+
+    fn transport(&self) -> Transport;
+
+    /// Modes the firmware can be switched to, the first one is the default.
+    fn supported_modes(&self) -> &'static [Mode] {
+        &[]
+    }
+
+    /// Mode needed to drive a logical topic of type `source_topic_name`.
+    fn mode_for(&self, _source_topic_name: &str) -> Option<Mode> {
+        None
+    }
+
+    /// Encodes a command for `channel`, None when the family does not
+    /// support it.
+    fn encode_command(
+        &self,
+        command: &Command,
+        mac_address: &str,
+        channel: u64,
+    ) -> Option<ShellyAction> {
+        match command {
+            Command::Turn(turn) if self.transport() != Transport::Ble => {
+                Some(encode_turn(mac_address, channel, turn.desired_state))
+            }
+            Command::Shutter(shutter) if self.supported_modes().contains(&Mode::Shutter) => {
+                let action_payload = serde_json::json!({
+                    "shutter_command": shutter.shutter_command.code(),
+                });
+
+                // shutter_status follows the movement, which is over before
+                // a retry could compare it, so shutter commands are not
+                // queued and the next status of the shutter confirms them
+                Some(ShellyAction::new(
+                    mac_address,
+                    "set_shutter",
+                    &action_payload,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn encode_turn(mac_address: &str, channel: u64, desired_state: bool) -> ShellyAction {
+    let action_payload = serde_json::json!({
+        "output_number": channel,
+        "value": desired_state
+    });
+
+    let expected_status = ExpectedStatus::new(channel).with(
+        &format!("output{}", channel),
+        serde_json::Value::Bool(desired_state),
+    );
+
+    ShellyAction::new(mac_address, "set_output", &action_payload).expecting(expected_status)
+}
+
+/// Shelly 1, 1PM and EM running the DoMO firmware.
+pub struct Shelly1 {
+    topic_name: &'static str,
+}
+
+impl DeviceDriver for Shelly1 {
+    fn topic_name(&self) -> &'static str {
+        self.topic_name
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::ShellyGen1
+    }
+
+    fn supported_modes(&self) -> &'static [Mode] {
+        &[Mode::Relay]
+    }
+}
+
+/// Shelly 2.5, two relays or one roller shutter.
+pub struct Shelly25;
+
+impl DeviceDriver for Shelly25 {
+    fn topic_name(&self) -> &'static str {
+        "shelly_25"
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::ShellyGen1
+    }
+
+    fn supported_modes(&self) -> &'static [Mode] {
+        &[Mode::Relay, Mode::Shutter]
+    }
+
+    fn mode_for(&self, source_topic_name: &str) -> Option<Mode> {
+        match source_topic_name {
+            "domo_roller_shutter" | "domo_garage_gate" => Some(Mode::Shutter),
+            _ => None,
+        }
+    }
+}
+
+pub struct ShellyDimmer;
+
+impl DeviceDriver for ShellyDimmer {
+    fn topic_name(&self) -> &'static str {
+        "shelly_dimmer"
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::ShellyGen1
+    }
+
+    fn supported_modes(&self) -> &'static [Mode] {
+        &[Mode::Dimmer]
+    }
+
+    fn encode_command(
+        &self,
+        command: &Command,
+        mac_address: &str,
+        channel: u64,
+    ) -> Option<ShellyAction> {
+        match command {
+            Command::Turn(turn) => Some(encode_turn(mac_address, channel, turn.desired_state)),
+            Command::Dim(dim) => {
+                let action_payload = serde_json::json!({ "dim_value": dim.desired_state });
+
+                let expected_status = ExpectedStatus::new(channel)
+                    .with("dimmer_status", serde_json::json!(dim.desired_state));
+
+                Some(
+                    ShellyAction::new(mac_address, "set_dimmer", &action_payload)
+                        .expecting(expected_status),
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct ShellyRgbw;
+
+impl ShellyRgbw {
+    fn channel_name(channel: u64) -> &'static str {
+        match channel {
+            2 => "g",
+            3 => "b",
+            4 => "w",
+            _ => "r",
+        }
+    }
+}
+
+impl DeviceDriver for ShellyRgbw {
+    fn topic_name(&self) -> &'static str {
+        "shelly_rgbw"
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::ShellyGen1
+    }
+
+    fn supported_modes(&self) -> &'static [Mode] {
+        &[Mode::LedDimmer, Mode::Rgbw]
+    }
+
+    fn mode_for(&self, source_topic_name: &str) -> Option<Mode> {
+        match source_topic_name {
+            "domo_rgbw_light" => Some(Mode::Rgbw),
+            _ => None,
+        }
+    }
+
+    fn encode_command(
+        &self,
+        command: &Command,
+        mac_address: &str,
+        channel: u64,
+    ) -> Option<ShellyAction> {
+        match command {
+            Command::Turn(turn) => Some(encode_turn(mac_address, channel, turn.desired_state)),
+            Command::Dim(dim) => {
+                let channel_name = ShellyRgbw::channel_name(channel);
+
+                let action_payload = serde_json::json!({
+                    "led_dimmer_status": {
+                            "channel": channel_name,
+                            "value": dim.desired_state
+                    }
+                });
+
+                let expected_status = ExpectedStatus::new(channel).with(
+                    &format!("rgbw_status.{}", channel_name),
+                    serde_json::json!(dim.desired_state),
+                );
+
+                Some(
+                    ShellyAction::new(mac_address, "set_led_dimmer", &action_payload)
+                        .expecting(expected_status),
+                )
+            }
+            Command::Rgbw(rgbw) => {
+                let rgbw = &rgbw.desired_state;
+
+                let action_payload = serde_json::json!({ "rgbw_status": rgbw });
+
+                let expected_status = ExpectedStatus::new(0)
+                    .with("rgbw_status.r", serde_json::json!(rgbw.r))
+                    .with("rgbw_status.g", serde_json::json!(rgbw.g))
+                    .with("rgbw_status.b", serde_json::json!(rgbw.b))
+                    .with("rgbw_status.w", serde_json::json!(rgbw.w));
+
+                Some(
+                    ShellyAction::new(mac_address, "set_rgbw", &action_payload)
+                        .expecting(expected_status),
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Shelly Plus series, ESP32 based.
+pub struct ShellyPlus {
+    topic_name: &'static str,
+    shutter: bool,
+}
+
+impl DeviceDriver for ShellyPlus {
+    fn topic_name(&self) -> &'static str {
+        self.topic_name
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Esp32
+    }
+
+    fn supported_modes(&self) -> &'static [Mode] {
+        if self.shutter {
+            &[Mode::Relay, Mode::Shutter]
+        } else {
+            &[Mode::Relay]
+        }
+    }
+
+    fn mode_for(&self, source_topic_name: &str) -> Option<Mode> {
+        match source_topic_name {
+            "domo_roller_shutter" | "domo_garage_gate" if self.shutter => Some(Mode::Shutter),
+            _ => None,
+        }
+    }
+}
+
+/// BLE thermometers, valves and contact sensors.
+pub struct BleSensor {
+    topic_name: &'static str,
+}
+
+impl DeviceDriver for BleSensor {
+    fn topic_name(&self) -> &'static str {
+        self.topic_name
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Ble
+    }
+}
+
+static DRIVERS: &[&dyn DeviceDriver] = &[
+    &Shelly1 {
+        topic_name: "shelly_1",
+    },
+    &Shelly1 {
+        topic_name: "shelly_1pm",
+    },
+    &Shelly1 {
+        topic_name: "shelly_em",
+    },
+    &Shelly25,
+    &ShellyDimmer,
+    &ShellyRgbw,
+    &ShellyPlus {
+        topic_name: "shelly_1plus",
+        shutter: false,
+    },
+    &ShellyPlus {
+        topic_name: "shelly_1pm_plus",
+        shutter: false,
+    },
+    &ShellyPlus {
+        topic_name: "shelly_2pm_plus",
+        shutter: true,
+    },
+    &BleSensor {
+        topic_name: "domo_ble_thermometer",
+    },
+    &BleSensor {
+        topic_name: "domo_ble_valve",
+    },
+    &BleSensor {
+        topic_name: "domo_ble_contact",
+    },
+];
+
+pub fn get(topic_name: &str) -> Option<&'static dyn DeviceDriver> {
+    DRIVERS
+        .iter()
+        .find(|d| d.topic_name() == topic_name)
+        .copied()
+}
+
+pub fn topic_names() -> impl Iterator<Item = &'static str> {
+    DRIVERS.iter().map(|d| d.topic_name())
+}
+
+pub fn topic_names_with_transport(transport: Transport) -> impl Iterator<Item = &'static str> {
+    DRIVERS
+        .iter()
+        .filter(move |d| d.transport() == transport)
+        .map(|d| d.topic_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{
+        CommandContext, DimCommand, ShutterCommand, ShutterDirection, TurnCommand,
+    };
+    use crate::commandtracker::CommandTracker;
+    use std::time::Duration;
+
+    #[test]
+    fn registry_covers_transports() {
+        let esp32: Vec<&str> = topic_names_with_transport(Transport::Esp32).collect();
+        assert_eq!(
+            esp32,
+            ["shelly_1plus", "shelly_1pm_plus", "shelly_2pm_plus"]
+        );
+        assert_eq!(
+            get("domo_ble_valve").map(|d| d.transport()),
+            Some(Transport::Ble)
+        );
+        assert!(get("shelly_unknown").is_none());
+    }
+
+    #[test]
+    fn dim_depends_on_family() {
+        let dim = Command::Dim(DimCommand {
+            topic_uuid: "light-1".to_owned(),
+            desired_state: 40,
+        });
+
+        let rgbw = get("shelly_rgbw").unwrap();
+        let action = rgbw.encode_command(&dim, "aa:bb", 3).unwrap();
+        let status = serde_json::json!({ "rgbw_status": { "b": 40 } });
+        assert!(action.expected_status.unwrap().matches(&status));
+
+        assert!(get("shelly_1")
+            .unwrap()
+            .encode_command(&dim, "aa:bb", 1)
+            .is_none());
+
+        let turn = Command::Turn(TurnCommand {
+            topic_uuid: "light-1".to_owned(),
+            desired_state: true,
+        });
+        assert!(get("domo_ble_valve")
+            .unwrap()
+            .encode_command(&turn, "aa:bb", 1)
+            .is_none());
+    }
+
+    #[test]
+    fn shutter_commands_confirmed_after_the_move() {
+        let down = Command::Shutter(ShutterCommand {
+            topic_uuid: "shutter-1".to_owned(),
+            shutter_command: ShutterDirection::Down,
+        });
+
+        let action = get("shelly_25")
+            .unwrap()
+            .encode_command(&down, "aa:bb:cc:dd:ee:ff", 0)
+            .unwrap();
+        assert!(action.expected_status.is_none());
+
+        let context = CommandContext {
+            command_type: "shutter_command".to_owned(),
+            topic_uuid: Some("shutter-1".to_owned()),
+            request_id: Some("req-1".to_owned()),
+        };
+        let mut tracker = CommandTracker::new(Duration::from_secs(10));
+        tracker.track(&context, &action.mac_address, action.expected_status);
+
+        // status reported once the shutter stopped at the bottom
+        let status = serde_json::json!({
+            "mac_address": "aabbccddeeff",
+            "topic_name": "shelly_25",
+            "shutter_status": ShutterDirection::Stop.code(),
+        });
+        assert_eq!(tracker.confirm("aabbccddeeff", &status).len(), 1);
+
+        assert!(get("shelly_1pm_plus")
+            .unwrap()
+            .encode_command(&down, "aa:bb", 0)
+            .is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// DHT topic holding the last raw counter seen for every accumulated field.
+pub const BASELINE_TOPIC_NAME: &str = "domo_energy_baseline";
+
+/// DHT topic holding the consumption buckets of every logical device.
+pub const HISTORY_TOPIC_NAME: &str = "domo_energy_history";
+
+/// Identifies an accumulated field of a logical topic fed by an actuator
+/// channel.
+pub fn counter_key(
+    actuator_mac_address: &str,
+    channel: u64,
+    source_topic_uuid: &str,
+    field: &str,
+) -> String {
+    format!(
+        "{}-{}-{}-{}",
+        actuator_mac_address, channel, source_topic_uuid, field
+    )
+}
+
+/// Turns the cumulative counters reported by the actuators into increments.
+///
+/// The last raw counter is kept per key so that replayed status updates add
+/// nothing, and a counter going backwards is taken as a device reboot that
+/// restarted it from zero.
+#[derive(Default)]
+pub struct EnergyAccountant {
+    baselines: HashMap<String, f64>,
+}
+
+impl EnergyAccountant {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn baseline(&self, key: &str) -> Option<f64> {
+        self.baselines.get(key).copied()
+    }
+
+    /// Sets the baseline read back from the DHT after a restart.
+    pub fn restore(&mut self, key: &str, counter: f64) {
+        self.baselines.insert(key.to_owned(), counter);
+    }
+
+    /// Records `counter` and returns the energy to add to the total. The
+    /// first value seen for a key only sets the baseline.
+    pub fn update(&mut self, key: &str, counter: f64) -> f64 {
+        let delta = match self.baselines.get(key) {
+            Some(last) if counter >= *last => counter - last,
+            Some(_) => {
+                log::info!("Energy counter {} reset, restarting from {}", key, counter);
+                counter
+            }
+            None => 0.0,
+        };
+
+        self.baselines.insert(key.to_owned(), counter);
+
+        delta
+    }
+}
+
+/// Number of buckets kept for every period.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryRetention {
+    pub hours: usize,
+    pub days: usize,
+    pub months: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnergyBucket {
+    /// Local time the bucket starts at, RFC 3339.
+    pub start: String,
+    pub energy: f64,
+}
+
+/// Hourly, daily and monthly consumption of a logical device, oldest
+/// bucket first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EnergyHistory {
+    #[serde(default)]
+    pub hourly: Vec<EnergyBucket>,
+    #[serde(default)]
+    pub daily: Vec<EnergyBucket>,
+    #[serde(default)]
+    pub monthly: Vec<EnergyBucket>,
+}
+
+fn local_start(naive: NaiveDateTime, fallback: DateTime<Local>) -> DateTime<Local> {
+    // the earliest instant when the wall clock is repeated by a DST change
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .unwrap_or(fallback)
+}
+
+fn add_to_bucket(
+    buckets: &mut Vec<EnergyBucket>,
+    start: DateTime<Local>,
+    energy: f64,
+    retention: usize,
+) {
+    let start = start.to_rfc3339();
+
+    match buckets.last_mut() {
+        Some(last) if last.start == start => last.energy += energy,
+        _ => buckets.push(EnergyBucket { start, energy }),
+    }
+
+    if buckets.len() > retention {
+        buckets.drain(..buckets.len() - retention);
+    }
+}
+
+impl EnergyHistory {
+    /// Adds `energy` consumed at `at`, opening new buckets when a local
+    /// hour, day or month boundary has been crossed.
+    pub fn record(&mut self, at: DateTime<Local>, energy: f64, retention: &HistoryRetention) {
+        let date = at.date_naive();
+
+        if let (Some(hour), Some(day), Some(month)) = (
+            date.and_hms_opt(at.hour(), 0, 0),
+            date.and_hms_opt(0, 0, 0),
+            date.with_day(1).and_then(|d| d.and_hms_opt(0, 0, 0)),
+        ) {
+            add_to_bucket(
+                &mut self.hourly,
+                local_start(hour, at),
+                energy,
+                retention.hours,
+            );
+            add_to_bucket(
+                &mut self.daily,
+                local_start(day, at),
+                energy,
+                retention.days,
+            );
+            add_to_bucket(
+                &mut self.monthly,
+                local_start(month, at),
+                energy,
+                retention.months,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replayed_counter_adds_nothing() {
+        let mut accountant = EnergyAccountant::new();
+
+        assert_eq!(accountant.update("k", 100.0), 0.0);
+        assert_eq!(accountant.update("k", 103.5), 3.5);
+        assert_eq!(accountant.update("k", 103.5), 0.0);
+    }
+
+    #[test]
+    fn counter_reset_after_reboot() {
+        let mut accountant = EnergyAccountant::new();
+
+        accountant.restore("k", 250.0);
+
+        assert_eq!(accountant.update("k", 4.0), 4.0);
+        assert_eq!(accountant.update("k", 6.0), 2.0);
+        assert_eq!(accountant.baseline("k"), Some(6.0));
+    }
+
+    #[test]
+    fn history_rolls_over_at_local_boundaries() {
+        let retention = HistoryRetention {
+            hours: 2,
+            days: 10,
+            months: 10,
+        };
+        let at = |d, h, m| Local.with_ymd_and_hms(2023, 5, d, h, m, 0).unwrap();
+        let mut history = EnergyHistory::default();
+
+        history.record(at(31, 22, 10), 1.0, &retention);
+        history.record(at(31, 22, 50), 2.0, &retention);
+        history.record(at(31, 23, 5), 4.0, &retention);
+
+        assert_eq!(history.hourly.len(), 2);
+        assert_eq!(history.hourly[0].energy, 3.0);
+        assert_eq!(history.daily.len(), 1);
+        assert_eq!(history.daily[0].energy, 7.0);
+
+        history.record(
+            Local.with_ymd_and_hms(2023, 6, 1, 0, 1, 0).unwrap(),
+            8.0,
+            &retention,
+        );
+
+        assert_eq!(history.hourly.len(), 2);
+        assert_eq!(history.hourly[0].energy, 4.0);
+        assert_eq!(history.daily.len(), 2);
+        assert_eq!(history.monthly.len(), 2);
+        assert_eq!(history.monthly[0].energy, 7.0);
+        assert_eq!(history.monthly[1].energy, 8.0);
+    }
+}
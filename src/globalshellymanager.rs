@@ -1,20 +1,77 @@
-use crate::{ShellyDiscoveryResult, ShellyManager};
+use crate::metrics::metrics;
+use crate::shellymanager::{ShellyEvent, ShellyManager, ShellyTarget};
+use crate::ShellyDiscoveryResult;
 use futures::{stream::FuturesUnordered, StreamExt};
+use rand::Rng;
+use std::collections::HashMap;
 use std::error::Error;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+
+/// Shelly without a pong for longer than this are connected again.
+const PONG_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Delay after `attempts` failed connections, doubled at every failure
+    /// and shortened by up to a half at random, so that the Shelly dropped
+    /// together by a network outage do not reconnect in lockstep.
+    fn delay(&self, attempts: u32) -> Duration {
+        let exp = attempts.saturating_sub(1).min(16);
+        let delay = self
+            .initial_delay
+            .saturating_mul(2_u32.pow(exp))
+            .min(self.max_delay);
+
+        delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..=0.5))
+    }
+}
+
+/// Shelly waiting for its websocket to be opened, kept until it connects.
+pub struct OfflineShelly {
+    pub target: ShellyTarget,
+    /// Last pong of the closed websocket, `None` when it never connected.
+    pub last_pong: Option<SystemTime>,
+    pub attempts: u32,
+    next_attempt: Instant,
+    connecting: bool,
+}
+
+type ConnectResult = (String, Result<ShellyManager, String>);
 
 pub struct GlobalShellyManager {
     pub shelly_list: Vec<ShellyManager>,
+    /// Offline Shelly by mac address.
+    pub offline: HashMap<String, OfflineShelly>,
+    /// Connected Shelly not asked for their status yet.
+    pending_updates: Vec<String>,
+    policy: ReconnectPolicy,
+    tx_connect: mpsc::Sender<ConnectResult>,
+    rx_connect: mpsc::Receiver<ConnectResult>,
 }
 
 impl GlobalShellyManager {
-    pub async fn new() -> GlobalShellyManager {
+    pub async fn new(policy: ReconnectPolicy) -> GlobalShellyManager {
+        let (tx_connect, rx_connect) = mpsc::channel(16);
+
         GlobalShellyManager {
             shelly_list: vec![],
+            offline: HashMap::new(),
+            pending_updates: vec![],
+            policy,
+            tx_connect,
+            rx_connect,
         }
     }
 
-    pub async fn insert_shelly(
+    /// Connects an announced Shelly, unless it is already connected at
+    /// the same address.
+    pub fn insert_shelly(
         &mut self,
         shelly_disc_result: ShellyDiscoveryResult,
         user_login: String,
@@ -28,32 +85,187 @@ impl GlobalShellyManager {
             }
         }
 
-        let shelly_m = ShellyManager::new(
+        let target = ShellyTarget::new(
             &shelly_disc_result.ip_address,
             &shelly_disc_result.topic_name,
             &shelly_disc_result.mac_address,
             &shelly_disc_result.mdns_name,
             &user_login,
             &user_password,
-        )
-        .await;
-
-        if let Ok(mut shelly) = shelly_m {
-            shelly.send_get_update().await;
-            self.shelly_list.push(shelly);
-            println!(
-                "Shelly {} {} connected",
-                shelly_disc_result.topic_name, shelly_disc_result.mac_address
+        );
+
+        self.insert_target(target);
+    }
+
+    /// Connects `target` as soon as possible.
+    pub fn insert_target(&mut self, target: ShellyTarget) {
+        match self.offline.get_mut(&target.mac_address) {
+            // a new announce is worth an attempt right away
+            Some(offline) if !offline.connecting => {
+                offline.target = target;
+                offline.next_attempt = Instant::now();
+            }
+            Some(_) => {}
+            None => {
+                self.offline.insert(
+                    target.mac_address.to_owned(),
+                    OfflineShelly {
+                        target,
+                        last_pong: None,
+                        attempts: 0,
+                        next_attempt: Instant::now(),
+                        connecting: false,
+                    },
+                );
+            }
+        }
+
+        self.schedule_reconnects();
+    }
+
+    /// Connected and offline Shelly.
+    pub fn targets(&self) -> Vec<ShellyTarget> {
+        self.shelly_list
+            .iter()
+            .map(ShellyManager::target)
+            .chain(self.offline.values().map(|shelly| shelly.target.clone()))
+            .collect()
+    }
+
+    /// Forgets the Shelly with `mac_address`, connected or not.
+    pub fn remove(&mut self, mac_address: &str) {
+        self.shelly_list
+            .retain(|shelly| shelly.mac_address != mac_address);
+        self.offline.remove(mac_address);
+    }
+
+    /// Moves the Shelly with `mac_address` to the offline ones.
+    fn disconnected(&mut self, mac_address: &str) {
+        if let Some(idx) = self
+            .shelly_list
+            .iter()
+            .position(|shelly| shelly.mac_address == mac_address)
+        {
+            let shelly = self.shelly_list.remove(idx);
+
+            log::info!("Shelly {} offline", mac_address);
+
+            self.offline.insert(
+                mac_address.to_owned(),
+                OfflineShelly {
+                    target: shelly.target(),
+                    last_pong: Some(shelly.last_pong_timestamp),
+                    attempts: 0,
+                    next_attempt: Instant::now(),
+                    connecting: false,
+                },
             );
         }
     }
 
+    /// Starts in the background the connections whose backoff expired.
+    pub fn schedule_reconnects(&mut self) {
+        let now = Instant::now();
+
+        for (mac_address, offline) in self.offline.iter_mut() {
+            if offline.connecting || offline.next_attempt > now {
+                continue;
+            }
+
+            offline.connecting = true;
+
+            let mac_address = mac_address.to_owned();
+            let target = offline.target.clone();
+            let tx_connect = self.tx_connect.clone();
+
+            tokio::spawn(async move {
+                let result = ShellyManager::new(&target).await.map_err(|e| e.to_string());
+                let _ret = tx_connect.send((mac_address, result)).await;
+            });
+        }
+    }
+
+    /// Keeps the Shelly that connected in the background and reschedules
+    /// the others. It does not await, so that the connection is not lost
+    /// when the caller drops the future waiting for the messages.
+    fn connection_ended(&mut self, mac_address: String, result: Result<ShellyManager, String>) {
+        // forgotten while connecting
+        let offline = match self.offline.get_mut(&mac_address) {
+            Some(offline) => offline,
+            None => return,
+        };
+
+        let reconnect = offline.last_pong.is_some();
+
+        match result {
+            Ok(shelly) => {
+                self.offline.remove(&mac_address);
+
+                if reconnect {
+                    metrics().shelly_reconnects.with_label_values(&["ok"]).inc();
+                }
+
+                self.shelly_list
+                    .retain(|shelly| shelly.mac_address != mac_address);
+                println!("Shelly {} {} connected", shelly.topic_name, mac_address);
+                self.shelly_list.push(shelly);
+                self.pending_updates.push(mac_address);
+            }
+            Err(e) => {
+                if reconnect {
+                    metrics()
+                        .shelly_reconnects
+                        .with_label_values(&["failed"])
+                        .inc();
+                }
+
+                offline.attempts += 1;
+                offline.connecting = false;
+
+                let delay = self.policy.delay(offline.attempts);
+                offline.next_attempt = Instant::now() + delay;
+
+                log::warn!(
+                    "Cannot connect to Shelly {}: {}, retrying in {:?}",
+                    mac_address,
+                    e,
+                    delay
+                );
+            }
+        }
+    }
+
+    /// Asks the Shelly connected since the last call for their status.
+    pub async fn send_pending_updates(&mut self) {
+        while let Some(mac_address) = self.pending_updates.pop() {
+            if let Some(shelly) = self
+                .shelly_list
+                .iter_mut()
+                .find(|shelly| shelly.mac_address == mac_address)
+            {
+                shelly.send_get_update().await;
+            }
+        }
+    }
+
     pub async fn send_ping(&mut self) {
         for shelly in self.shelly_list.iter_mut() {
             shelly.send_ping().await;
         }
     }
 
+    /// Closes every websocket, giving up on the Shelly that do not answer
+    /// within `timeout`.
+    pub async fn close_all(&mut self, timeout: Duration) {
+        for shelly in self.shelly_list.iter_mut() {
+            if tokio::time::timeout(timeout, shelly.close()).await.is_err() {
+                log::warn!("Shelly {} did not close its websocket", shelly.mac_address);
+            }
+        }
+
+        self.shelly_list.clear();
+    }
+
     pub async fn send_action(
         &mut self,
         mac_address: &str,
@@ -63,85 +275,108 @@ impl GlobalShellyManager {
             if shelly.mac_address == mac_address {
                 shelly.send_action(action_payload).await;
                 //println!("DOMO: SHELLY_ACTION_SENT");
+                return Ok(shelly.mac_address.clone());
             }
         }
 
         Err("shelly not found".into())
     }
 
-    pub async fn sleep_long(&self) -> Result<serde_json::Value, Box<dyn Error>> {
-        tokio::time::sleep(Duration::from_secs(3600)).await;
-        Err("sleep long".into())
-    }
+    /// Waits for a message of a connected Shelly, handling meanwhile the
+    /// websockets that close and the background connections that end.
+    /// Returns `None` once a Shelly connects, so that the caller sends the
+    /// pending status requests. The future can be dropped at any await.
+    pub async fn wait_for_shelly_message(&mut self) -> Option<serde_json::Value> {
+        loop {
+            let mut disconnected = None;
 
-    pub async fn wait_for_shelly_message(&mut self) -> Result<serde_json::Value, Box<dyn Error>> {
-        let mut mac_address: String = String::from("");
+            let connection = {
+                let mut futures: FuturesUnordered<_> = self
+                    .shelly_list
+                    .iter_mut()
+                    .map(|shelly| shelly.wait_for_shelly_message())
+                    .collect();
 
-        if self.shelly_list.is_empty() {
-            let ret = self.sleep_long().await;
-            return ret;
-        } else {
-            let mut futures = FuturesUnordered::new();
-            for shelly in self.shelly_list.iter_mut() {
-                futures.push(shelly.wait_for_shelly_message());
-            }
-
-            let res = futures.next().await;
-
-            if let Some(res) = res {
-                match res {
-                    Ok(r) => {
-                        return Ok(r);
-                    }
-                    Err(e) => {
-                        let s = e.to_string();
-                        if s.contains(':') {
-                            mac_address = e.to_string();
+                tokio::select! {
+                    Some(event) = futures.next() => {
+                        match event {
+                            ShellyEvent::Message(message) => return Some(message),
+                            ShellyEvent::ParseError { mac_address, error } => {
+                                log::warn!("Ignoring malformed frame from Shelly {}: {}", mac_address, error);
+                            }
+                            ShellyEvent::Disconnected { mac_address, reason } => {
+                                log::info!("Shelly {} disconnected: {}", mac_address, reason);
+                                disconnected = Some(mac_address);
+                            }
+                            ShellyEvent::ProtocolError { mac_address, error } => {
+                                log::warn!("Websocket of Shelly {} failed: {}", mac_address, error);
+                                disconnected = Some(mac_address);
+                            }
                         }
+                        None
                     }
+                    connection = self.rx_connect.recv() => connection,
                 }
+            };
+
+            if let Some(mac_address) = disconnected {
+                self.disconnected(&mac_address);
+                self.schedule_reconnects();
             }
-        }
 
-        if !mac_address.is_empty() {
-            let mut to_remove: i32 = -1;
-            for (idx, shelly) in self.shelly_list.iter_mut().enumerate() {
-                if shelly.mac_address == mac_address {
-                    to_remove = idx as i32;
-                    break;
+            if let Some((mac_address, result)) = connection {
+                self.connection_ended(mac_address, result);
+
+                if !self.pending_updates.is_empty() {
+                    return None;
                 }
             }
-            if to_remove != -1 {
-                self.shelly_list.remove(to_remove as usize);
-            }
         }
+    }
+
+    /// Marks offline the Shelly that stopped answering the pings.
+    pub fn check_if_reconnect_needed(&mut self) {
+        let silent: Vec<String> = self
+            .shelly_list
+            .iter()
+            .filter(|shelly| {
+                shelly
+                    .last_pong_timestamp
+                    .elapsed()
+                    .map(|elapsed| elapsed > PONG_TIMEOUT)
+                    .unwrap_or(false)
+            })
+            .map(|shelly| shelly.mac_address.to_owned())
+            .collect();
 
-        Err("no messages".into())
+        for mac_address in silent {
+            self.disconnected(&mac_address);
+        }
+
+        self.schedule_reconnects();
     }
+}
 
-    pub async fn check_if_reconnect_needed(&mut self) {
-        let mut idx = 0_usize;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        while idx < self.shelly_list.len() {
-            if self.shelly_list[idx]
-                .last_pong_timestamp
-                .elapsed()
-                .unwrap()
-                .as_secs()
-                > 60
-            {
-                //println!("Reconnect to shelly {} ", self.shelly_list[idx].mac_address);
-                let ret = self.shelly_list[idx].reconnect().await;
-                match ret {
-                    Ok(_) => {}
-                    Err(_) => {
-                        self.shelly_list.remove(idx);
-                        continue;
-                    }
-                }
-            }
+    #[test]
+    fn reconnect_delay_has_jitter_and_bound() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(300),
+        };
+
+        for _ in 0..100 {
+            let first = policy.delay(1);
+            assert!(first >= Duration::from_secs(1) && first <= Duration::from_secs(2));
+
+            let fourth = policy.delay(4);
+            assert!(fourth >= Duration::from_secs(8) && fourth <= Duration::from_secs(16));
 
-            idx += 1;
+            let last = policy.delay(40);
+            assert!(last >= Duration::from_secs(150) && last <= Duration::from_secs(300));
         }
     }
 }
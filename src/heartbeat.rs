@@ -0,0 +1,128 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// DHT topic written periodically by every bridge, keyed by node_id.
+///
+/// The UI takes a bridge whose `updated_at` is older than a few
+/// `heartbeat_interval` as offline.
+pub const STATUS_TOPIC_NAME: &str = "domo_wot_bridge_status";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LastError {
+    pub at: DateTime<Local>,
+    pub message: String,
+}
+
+/// Connected devices, by transport and by topic name.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeviceCounts {
+    pub shelly_gen1: usize,
+    pub esp32: usize,
+    pub by_topic: BTreeMap<String, usize>,
+}
+
+impl DeviceCounts {
+    pub fn add_shelly_gen1(&mut self, topic_name: &str) {
+        self.shelly_gen1 += 1;
+        *self.by_topic.entry(topic_name.to_owned()).or_default() += 1;
+    }
+
+    pub fn add_esp32(&mut self, topic_name: &str) {
+        self.esp32 += 1;
+        *self.by_topic.entry(topic_name.to_owned()).or_default() += 1;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BridgeStatus {
+    pub node_id: u8,
+    /// False in the last status written before the bridge stops.
+    pub online: bool,
+    pub version: &'static str,
+    pub started_at: DateTime<Local>,
+    pub updated_at: DateTime<Local>,
+    pub uptime: u64,
+    pub heartbeat_interval: u64,
+    pub devices: DeviceCounts,
+    pub last_discovery: Option<DateTime<Local>>,
+    pub last_error: Option<LastError>,
+}
+
+pub type SharedHealth = Arc<Mutex<BridgeHealth>>;
+
+/// Events of the bridge reported in its heartbeat.
+pub struct BridgeHealth {
+    node_id: u8,
+    interval: Duration,
+    started: Instant,
+    started_at: DateTime<Local>,
+    last_discovery: Option<DateTime<Local>>,
+    last_error: Option<LastError>,
+}
+
+impl BridgeHealth {
+    pub fn new(node_id: u8, interval: Duration) -> Self {
+        BridgeHealth {
+            node_id,
+            interval,
+            started: Instant::now(),
+            started_at: Local::now(),
+            last_discovery: None,
+            last_error: None,
+        }
+    }
+
+    pub fn discovered(&mut self) {
+        self.last_discovery = Some(Local::now());
+    }
+
+    pub fn error(&mut self, message: impl ToString) {
+        self.last_error = Some(LastError {
+            at: Local::now(),
+            message: message.to_string(),
+        });
+    }
+
+    pub fn status(&self, devices: DeviceCounts) -> BridgeStatus {
+        BridgeStatus {
+            node_id: self.node_id,
+            online: true,
+            version: env!("CARGO_PKG_VERSION"),
+            started_at: self.started_at,
+            updated_at: Local::now(),
+            uptime: self.started.elapsed().as_secs(),
+            heartbeat_interval: self.interval.as_secs(),
+            devices,
+            last_discovery: self.last_discovery,
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_reports_devices_and_errors() {
+        let mut health = BridgeHealth::new(3, Duration::from_secs(30));
+        let mut devices = DeviceCounts::default();
+
+        devices.add_shelly_gen1("shelly_1");
+        devices.add_esp32("shelly_1plus");
+        devices.add_esp32("shelly_1plus");
+        health.error("actuator not found");
+
+        let status = serde_json::to_value(health.status(devices)).unwrap();
+
+        assert_eq!(status["node_id"], 3);
+        assert_eq!(status["online"], true);
+        assert_eq!(status["devices"]["esp32"], 2);
+        assert_eq!(status["devices"]["by_topic"]["shelly_1plus"], 2);
+        assert_eq!(status["last_error"]["message"], "actuator not found");
+        assert!(status["last_discovery"].is_null());
+    }
+}
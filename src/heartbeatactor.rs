@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use crate::heartbeat::DeviceCounts;
+use crate::metrics::metrics;
+use crate::shellyactor::ShellyHandle;
+use crate::supervisor::TaskResult;
+use crate::wssmanager::WssManager;
+use crate::BridgeHandles;
+
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Pings the ESP32 and writes the bridge status at every heartbeat.
+#[derive(Clone)]
+pub struct HeartbeatTask {
+    bridge: BridgeHandles,
+    interval: Duration,
+}
+
+impl HeartbeatTask {
+    pub fn new(bridge: BridgeHandles, interval: Duration) -> Self {
+        HeartbeatTask { bridge, interval }
+    }
+
+    pub async fn run(self) -> TaskResult {
+        let mut shutdown = self.bridge.shutdown.clone();
+
+        let BridgeHandles {
+            dht_manager,
+            wss_mgr,
+            shelly_manager,
+            health,
+            ..
+        } = &self.bridge;
+
+        let mut ping_timer = tokio::time::interval(PING_INTERVAL);
+
+        let mut heartbeat = tokio::time::interval(self.interval);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return Ok(()),
+                _ = ping_timer.tick() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["ping"]).start_timer();
+
+                    metrics().shelly_connected.set(shelly_manager.online().len() as i64);
+                    metrics().esp32_connected.set(wss_mgr.connected_actuators().len() as i64);
+
+                    wss_mgr.ping_all();
+                }
+                _ = heartbeat.tick() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["heartbeat"]).start_timer();
+
+                    let status = health.lock().unwrap().status(device_counts(shelly_manager, wss_mgr));
+                    dht_manager.write_bridge_status(&status).await;
+                }
+            }
+        }
+    }
+}
+
+pub fn device_counts(shelly_manager: &ShellyHandle, wss_mgr: &WssManager) -> DeviceCounts {
+    let mut devices = DeviceCounts::default();
+
+    for shelly in shelly_manager.online() {
+        devices.add_shelly_gen1(&shelly.topic_name);
+    }
+
+    for session in wss_mgr.sessions.lock().unwrap().sessions() {
+        if session.is_connected() {
+            devices.add_esp32(&session.topic_name);
+        }
+    }
+
+    devices
+}
@@ -1,32 +1,69 @@
-use crate::bleutils::ContactStatus;
-use crate::dhtmanager::{DHTCommand, DHTManager};
-use crate::globalshellymanager::GlobalShellyManager;
-use crate::messages::{AuthCredMessage, BleBeaconMessage, ESP32CommandMessage, ESP32CommandType};
-use crate::shellymanager::ShellyManager;
-use crate::utils::{ValveCommandManager, ValveData};
-use crate::wssmanager::WssManager;
+use crate::api::{ApiAdmin, SharedDiscoveries};
+use crate::authactor::AuthTask;
+use crate::authguard::{AuthGuard, AuthLog, LockoutPolicy, SharedAuthGuard, SharedAuthLog};
+use crate::commandactor::Commands;
+use crate::commandqueue::{CommandQueue, RetryPolicy};
+use crate::commandtracker::CommandTracker;
+use crate::dhtmanager::DHTManager;
+use crate::discoveryactor::DiscoveryTask;
+use crate::energy::{EnergyAccountant, HistoryRetention};
+use crate::globalshellymanager::ReconnectPolicy;
+use crate::heartbeat::{BridgeHealth, SharedHealth};
+use crate::heartbeatactor::HeartbeatTask;
+use crate::modeactor::ModeTask;
+use crate::presence::PresenceTracker;
+use crate::shellyactor::ShellyHandle;
+use crate::snapshot::SnapshotFile;
+use crate::statusactor::{StatusProjection, StatusTask};
+use crate::statusmapping::StatusMapping;
+use crate::supervisor::{RestartPolicy, Supervisor};
+use crate::tls::TlsSettings;
+use crate::utils::ValveCommandManager;
+use crate::wssmanager::{AuthMode, WssConfig, WssManager};
 use clap::Parser;
-use futures_util::{pin_mut, stream::StreamExt};
-use mdns::{Record, RecordKind};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Number};
 use sifis_config::{Cache, ConfigParser};
+use std::collections::HashMap;
 use std::error::Error;
-use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::time::Interval;
-
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+mod api;
+mod authactor;
+mod authguard;
 mod bleutils;
 mod command_parser;
+mod commandactor;
+mod commandqueue;
+mod commands;
+mod commandtracker;
+mod credentials;
 mod dhtmanager;
+mod discoveryactor;
+mod drivers;
+mod energy;
 mod globalshellymanager;
+mod heartbeat;
+mod heartbeatactor;
 mod messages;
+mod metrics;
+mod modeactor;
+mod presence;
+mod sessions;
+mod shellyactor;
 mod shellymanager;
+mod snapshot;
+mod statusactor;
+mod statusmapping;
+mod supervisor;
+mod tls;
 mod utils;
 mod wssmanager;
 
-const SERVICE_NAME: &str = "_webthing._tcp.local";
-
 pub struct ShellyDiscoveryResult {
     pub ip_address: String,
     pub topic_name: String,
@@ -34,21 +71,15 @@ pub struct ShellyDiscoveryResult {
     pub mdns_name: String,
 }
 
-struct PingManager {
-    ping_timer: Interval,
-}
-
-impl PingManager {
-    pub fn new(period_secs: u64) -> PingManager {
-        let interval = tokio::time::interval(tokio::time::Duration::from_secs(period_secs));
-        PingManager {
-            ping_timer: interval,
-        }
-    }
-
-    pub async fn wait_ping_timer(&mut self) {
-        self.ping_timer.tick().await;
-    }
+/// Handles shared by the tasks of the bridge.
+#[derive(Clone)]
+pub struct BridgeHandles {
+    pub dht_manager: DHTManager,
+    pub wss_mgr: Arc<WssManager>,
+    pub shelly_manager: ShellyHandle,
+    pub health: SharedHealth,
+    /// Changed once the bridge shuts down, the tasks then return.
+    pub shutdown: watch::Receiver<bool>,
 }
 
 #[derive(Parser, Debug, Serialize, Deserialize)]
@@ -59,1186 +90,425 @@ struct DomoWotBridge {
     /// node_id
     #[arg(short, long, default_value_t = 1)]
     pub node_id: u8,
-}
 
-#[derive(Parser, Debug, Serialize, Deserialize)]
-struct Opt {
-    #[clap(flatten)]
-    domo_wot_bridge: DomoWotBridge,
+    /// seconds to wait for the actuator status update confirming a command
+    #[arg(long, default_value_t = 10)]
+    pub command_timeout: u64,
+
+    /// seconds before the first re-send of an unconfirmed command
+    #[arg(long, default_value_t = 5)]
+    pub command_retry_initial_delay: u64,
+
+    /// upper bound in seconds of the exponential re-send backoff
+    #[arg(long, default_value_t = 60)]
+    pub command_retry_max_delay: u64,
+
+    /// attempts after which an unconfirmed command is dropped
+    #[arg(long, default_value_t = 20)]
+    pub command_retry_max_attempts: usize,
+
+    /// TOML file describing how actuator status is copied into the logical
+    /// topics, the built-in mapping is used when not set
+    #[arg(long)]
+    pub status_mapping: Option<String>,
+
+    /// hourly energy buckets kept in domo_energy_history
+    #[arg(long, default_value_t = 48)]
+    pub energy_history_hours: usize,
+
+    /// daily energy buckets kept in domo_energy_history
+    #[arg(long, default_value_t = 62)]
+    pub energy_history_days: usize,
+
+    /// monthly energy buckets kept in domo_energy_history
+    #[arg(long, default_value_t = 24)]
+    pub energy_history_months: usize,
+
+    /// address the ESP32 WebSocket server listens on
+    #[arg(long, default_value = "0.0.0.0")]
+    pub wss_bind_address: String,
+
+    /// port of the ESP32 WebSocket server
+    #[arg(long, default_value_t = 5000)]
+    pub wss_port: u16,
+
+    /// PEM certificate chain of the ESP32 WebSocket server
+    #[arg(long, default_value = "/etc/domo/Cert.pem")]
+    pub wss_cert: String,
+
+    /// PEM private key of the ESP32 WebSocket server
+    #[arg(long, default_value = "/etc/domo/Key.pem")]
+    pub wss_key: String,
+
+    /// PEM CA used to verify the client certificates of the ESP32
+    #[arg(long)]
+    pub wss_client_ca: Option<String>,
+
+    /// seconds between checks of the TLS files for changes, 0 to reload
+    /// only on SIGHUP
+    #[arg(long, default_value_t = 60)]
+    pub wss_cert_check_interval: u64,
+
+    /// authenticate the ESP32 with a client certificate signed by
+    /// wss_client_ca instead of Basic credentials, the REST API and
+    /// /metrics are still served to clients without a certificate
+    #[arg(long, default_value_t = false)]
+    pub wss_mtls: bool,
+
+    /// accept the plaintext user_password of devices without a
+    /// user_password_hash, disable once every device is migrated
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub auth_allow_plaintext: bool,
+
+    /// consecutive failed ESP32 logins from an address or for a user before
+    /// they are locked out, 0 disables the lockout
+    #[arg(long, default_value_t = 5)]
+    pub auth_max_failures: u32,
+
+    /// seconds of the first lockout, doubled at every further failure
+    #[arg(long, default_value_t = 30)]
+    pub auth_lockout: u64,
+
+    /// upper bound in seconds of the lockout
+    #[arg(long, default_value_t = 3600)]
+    pub auth_max_lockout: u64,
+
+    /// ESP32 login attempts kept in the domo_bridge_auth_log topic
+    #[arg(long, default_value_t = 1000)]
+    pub auth_log_size: usize,
+
+    /// user of the REST API under /api, disabled unless both the user and
+    /// the password hash are set
+    #[arg(long)]
+    pub api_admin_user: Option<String>,
+
+    /// PHC hash of the REST API password, see the hash-password command
+    #[arg(long)]
+    pub api_admin_password_hash: Option<String>,
+
+    /// seconds between two writes of domo_wot_bridge_status
+    #[arg(long, default_value_t = 30)]
+    pub heartbeat_interval: u64,
+
+    /// seconds before connecting again to a Shelly gen1 whose websocket
+    /// closed, doubled at every failed attempt
+    #[arg(long, default_value_t = 2)]
+    pub shelly_reconnect_initial_delay: u64,
+
+    /// upper bound in seconds of the Shelly gen1 reconnection backoff
+    #[arg(long, default_value_t = 300)]
+    pub shelly_reconnect_max_delay: u64,
+
+    /// seconds without pongs, status updates or beacons after which a
+    /// device is marked offline in its topic
+    #[arg(long, default_value_t = 90)]
+    pub presence_timeout: u64,
+
+    /// JSON file where queued commands and valve routing are saved and
+    /// restored from at startup, nothing is saved when not set
+    #[arg(long)]
+    pub snapshot_path: Option<String>,
+
+    /// seconds between two writes of the snapshot
+    #[arg(long, default_value_t = 10)]
+    pub snapshot_interval: u64,
+
+    /// seconds after which a saved command or best actuator is discarded
+    /// instead of restored
+    #[arg(long, default_value_t = 300)]
+    pub snapshot_max_age: u64,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let opt = ConfigParser::<Opt>::new()
-        .with_config_path("/etc/domo/domo_wot_bridge.toml")
-        .parse();
-
-    let opt = opt.domo_wot_bridge;
-
-    env_logger::init();
-
-    let mut ping_mgr = PingManager::new(10);
-
-    let mut check_shelly_mode = PingManager::new(10);
-
-    let mut check_radiator_valve_commands = PingManager::new(20);
-
-    let mut shelly_plus_actuators = vec![];
-
-    let mut valve_command_manager = ValveCommandManager::new();
-
-    let mut shelly_manager = GlobalShellyManager::new().await;
-
-    let mut dht_manager = dhtmanager::DHTManager::new(opt.cache).await?;
-
-    let mut wss_mgr = WssManager::new(5000).await;
-
-    let stream = mdns::discover::interface(
-        SERVICE_NAME,
-        Duration::from_secs(5),
-        Ipv4Addr::new(10, 0, opt.node_id, 1),
-    )?
-    .listen();
-
-    pin_mut!(stream);
-
-    let mut counter = 0;
-    loop {
-        counter += 1;
-        tokio::select! {
-            Some(auth_cred_message) = wss_mgr.rx_auth_cred.recv() => {
-                    //println!("Received auth cred from esp32");
-                    let ret = handle_cred_message(auth_cred_message, &mut dht_manager).await;
-                    if let Ok(m) = ret {
-                        if let Some(mac_address) = m.get("mac_address") {
-                            if let Some(topic) = m.get("topic") {
-                                //println!("TOPIC {} mac_address {}", topic, mac_address.to_string());
-                                let topic = topic.as_str().unwrap().to_owned();
-                                if topic == "shelly_1plus" || topic == "shelly_1pm_plus" || topic == "shelly_2pm_plus" {
-                                    println!("Shelly plus {}, {} connected" , topic, mac_address);
-                                    shelly_plus_actuators.push(mac_address.as_str().unwrap().to_owned());
-                                }
-                            }
-                        }
-                    }
-            },
-            esp32_actuator_update = wss_mgr.channel_of_actuator_updates_rx.recv() => {
-                //println!("Received esp32 actuator update");
-                if let Ok(msg) = esp32_actuator_update {
-                    handle_shelly_message(msg, &mut dht_manager).await;
-                }
-            }
-            // listener for ble beacons adv
-            ble_update = wss_mgr.channel_of_updates_rx.recv() => {
-
-                ////println!("Received ble beacon update");
-
-                if let Ok(msg) = ble_update {
-                    handle_ble_update_message(msg, &mut dht_manager, &mut valve_command_manager).await;
-                }
-
-            },
-            // mdns await
-            res = stream.next() =>  {
-
-                //println!("Received mdns message");
-
-                if let Some(Ok(response)) = res {
-
-                    let shelly_res = response.records()
-                                     .filter_map(get_shelly_discovery_result)
-                                     .next();
-
-                    if let Some(shelly) = shelly_res {
-
-                        //println!("{} {} {}", shelly.topic_name, shelly.mac_address, shelly.ip_address);
-
-                        let topic = dht_manager.get_actuator_from_mac_address(&shelly.mac_address).await;
-                        match topic {
-                            Ok(t) => {
-
-                                    if let Some(value) = t.get("value"){
-
-                                        if let Some(user_login) = value.get("user_login"){
-                                            if let Some(user_password) = value.get("user_password") {
-
-                                                let user_login_str = user_login.as_str();
-                                                let user_password_str = user_password.as_str();
-
-                                                if let Some(user) = user_login_str {
-                                                    if let Some(password) = user_password_str {
-                                                        shelly_manager.insert_shelly(shelly, user.to_owned(), password.to_owned()).await;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                            },
-                            Err(_e) => {
-                            }
-                        }
-
-                    }
-                }
-
-            },
-            _ = check_radiator_valve_commands.wait_ping_timer() => {
-                //println!("RADIATOR VALVE QUEUE CHECK");
-                if !valve_command_manager.valve_commands.is_empty() && !shelly_plus_actuators.is_empty() {
-
-                    let mut to_remove = vec![];
-                    let valves = dht_manager.cache.get_topic_name("domo_ble_valve").unwrap();
-
-                    let valve_commands = valve_command_manager.valve_commands.clone();
-
-                    for (key, val) in valve_commands {
-                            let mut ok = false;
-                                for valve in valves.as_array().unwrap() {
-                                    if let Some(value) = valve.get("value") {
-                                        if let Some(mac_address) = value.get("mac_address") {
-                                            let mac = mac_address.as_str().unwrap();
-                                            if mac == key {
-                                                if let Some(status) = value.get("status") {
-                                                   let status = status.as_bool().unwrap();
-                                                    //println!("Status: {} ", status);
-                                                    //println!("Desired state: {}", val.desired_state);
-
-                                                    if let Some(desired_state) = val.desired_state.get("desired_state") {
-                                                        let desired_state = desired_state.as_bool().unwrap();
-                                                        if status == desired_state {
-                                                            //println!("Removing valve command from queue");
-                                                            to_remove.push(key.clone());
-                                                            ok = true;
-                                                            break;
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                        if ok {
-                            continue;
-                        } else if val.attempts < 100 {
-
-                            if let Some(next_act_mac) = valve_command_manager.get_best_actuator_for_valve(&key) {
-                                //println!("RE-SEND VALVE COMMAND TO {}", next_act_mac.clone());
-                                let cmd = ESP32CommandMessage {
-                                        command_type: ESP32CommandType::Valve,
-                                        mac_address: key.to_string(),
-                                        payload: val.desired_state.clone(),
-                                        actuator_mac_address: next_act_mac
-                                };
-
-                                let _ret = wss_mgr.command_channel_tx.send(cmd);
-
-                                let mut val = val.clone();
-                                val.attempts += 1;
-                                valve_command_manager.valve_commands.insert(key, val);
-                            }
-
-                        }
-                        else {
-                            to_remove.push(key.clone());
-                        }
-
-                    }
-
-                    for r in to_remove {
-                        valve_command_manager.remove(&r);
-                    }
-
-                }
-            },
-            _ = ping_mgr.wait_ping_timer() => {
-                //println!("PING_TIMER {}", counter);
-
-                shelly_manager.send_ping().await;
-                shelly_manager.check_if_reconnect_needed().await;
-
-                let cmd = ESP32CommandMessage {
-                                                command_type: ESP32CommandType::Ping,
-                                                actuator_mac_address: String::from(""),
-                                                mac_address: String::from(""),
-                                                payload: json!({})
-                                        };
-
-                let _ret = wss_mgr.command_channel_tx.send(cmd);
-
-            },
-            _ = check_shelly_mode.wait_ping_timer() => {
-                log::info!("CHECK_SHELLY_MODE {}", counter);
-
-                if let Ok(actuator_connections) = dht_manager.cache.get_topic_name("domo_actuator_connection") {
-                    let actuator_connections = actuator_connections.as_array().unwrap();
-
-                    check_shelly_esp8266_mode(actuator_connections, &mut shelly_manager, &mut dht_manager).await;
-
-                    check_shelly_esp32_mode(actuator_connections, &shelly_plus_actuators, &mut dht_manager, &mut wss_mgr).await;
-
-                }
-            },
-            command = dht_manager.wait_dht_messages() => {
-
-                if let Ok(cmd) = command {
-                        //println!("Received command from dht");
-                        match cmd {
-                            DHTCommand::ActuatorCommand(value) => {
-
-                                //println!("Received actuator command");
-
-                                if let Some(mac_address) = value.get("mac_address") {
-                                    let mac_string = mac_address.as_str().unwrap();
-                                    let cmd = ESP32CommandMessage {
-                                        command_type: ESP32CommandType::Actuator,
-                                        mac_address: mac_string.to_owned(),
-                                        payload: value.clone(),
-                                        actuator_mac_address: String::from("")
-                                    };
-
-                                    let _ret = wss_mgr.command_channel_tx.send(cmd);
-
-                                }
-
-                                handle_shelly_command(value, &mut dht_manager, &mut shelly_manager).await;
-                            }
-                            DHTCommand::ValveCommand(value) => {
-
-                                if !shelly_plus_actuators.is_empty() {
-                                    if let Some(mac_address) = value.get("mac_address") {
-
-                                        //println!("Valve command {}", value);
-
-                                        let mac_string = mac_address.as_str().unwrap();
-
-                                        if let Some(best_act) = valve_command_manager.get_best_actuator_for_valve(mac_string) {
-
-                                            let vd = ValveData {
-                                                desired_state: value.clone(),
-                                                attempts: 1
-                                            };
-
-                                            valve_command_manager.insert(mac_string, vd);
-
-                                            let cmd = ESP32CommandMessage {
-                                                command_type: ESP32CommandType::Valve,
-                                                mac_address: mac_string.to_owned(),
-                                                payload: value,
-                                                actuator_mac_address: best_act.clone()
-                                            };
-
-                                            //println!("SENDING VALVE COMMAND TO {} ", best_act);
-
-                                            let _ret = wss_mgr.command_channel_tx.send(cmd);
-                                        } else {
-                                            //println!("NO ACTUATOR for {} ", mac_string);
-
-                                            let vd = ValveData {
-                                                desired_state: value.clone(),
-                                                attempts: 0
-                                            };
-
-                                            valve_command_manager.insert(mac_string, vd);
-
-                                        }
-
-                                    }
-                                }
-                            }
-                        }
-                    }
-            },
-
-            shelly_message = shelly_manager.wait_for_shelly_message() => {
-                //println!("Received shelly message");
-
-                if let Ok(message) = shelly_message {
-                        handle_shelly_message(message, &mut dht_manager).await;
-                }
-            }
-
-        }
-    }
+/// Prints the user_password_hash to store in the DHT topic of a device
+#[derive(Parser, Debug)]
+#[command(name = "hash-password")]
+struct HashPassword {
+    /// password of the device, read from stdin when omitted
+    password: Option<String>,
 }
 
-async fn handle_cred_message(
-    auth_cred_message: AuthCredMessage,
-    dht_manager: &mut DHTManager,
-) -> Result<serde_json::Value, Box<dyn Error>> {
-    let ret = dht_manager
-        .get_auth_cred(&auth_cred_message.user, &auth_cred_message.pass)
-        .await;
-
-    match ret {
-        Ok(m) => {
-            let _r = auth_cred_message.responder.send(Ok(m.clone()));
-            Ok(m)
-        }
-        _ => {
-            let _r = auth_cred_message
-                .responder
-                .send(Err("cred not found".to_owned()));
-            Err("cred not found".into())
-        }
-    }
-}
+fn hash_password_command(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let cmd = HashPassword::parse_from(args);
 
-async fn handle_shelly_message(shelly_message: serde_json::Value, dht_manager: &mut DHTManager) {
-    if let Some(message_type) = shelly_message.get("messageType") {
-        if message_type.as_str().unwrap() == "propertyStatus" {
-            if let Some(data) = shelly_message.get("data") {
-                if let Some(status) = data.get("status") {
-                    let status_string = status.as_str().unwrap();
-
-                    if let Ok(status_result) =
-                        serde_json::from_str::<serde_json::Value>(status_string)
-                    {
-                        let mac_address =
-                            status_result.get("mac_address").unwrap().as_str().unwrap();
-
-                        let mac_address_with_points = mac_address[0..2].to_owned()
-                            + ":"
-                            + &mac_address[2..4]
-                            + ":"
-                            + &mac_address[4..6]
-                            + ":"
-                            + &mac_address[6..8]
-                            + ":"
-                            + &mac_address[8..10]
-                            + ":"
-                            + &mac_address[10..12];
-
-                        let topic_name = status_result.get("topic_name").unwrap().as_str().unwrap();
-
-                        if let Ok(topic) =
-                            dht_manager.get_topic(topic_name, &mac_address_with_points)
-                        {
-                            let mut new_status = status_result.clone();
-
-                            if let Some(value) = topic.get("value") {
-                                if let Some(user_login) = value.get("user_login") {
-                                    let user_login = user_login.as_str().unwrap();
-
-                                    if let Some(user_password) = value.get("user_password") {
-                                        let user_password = user_password.as_str().unwrap();
-                                        if let Some(mac_address) = value.get("mac_address") {
-                                            let mac_address = mac_address.as_str().unwrap();
-                                            if let Some(id) = value.get("id") {
-                                                new_status["user_login"] =
-                                                    serde_json::Value::String(
-                                                        user_login.to_owned(),
-                                                    );
-                                                new_status["user_password"] =
-                                                    serde_json::Value::String(
-                                                        user_password.to_string(),
-                                                    );
-
-                                                new_status["mac_address"] =
-                                                    serde_json::Value::String(
-                                                        mac_address.to_string(),
-                                                    );
-
-                                                new_status["id"] = id.to_owned();
-
-                                                new_status["last_update_timestamp"] =
-                                                    serde_json::Value::Number(Number::from(
-                                                        sifis_dht::utils::get_epoch_ms() as u64,
-                                                    ));
-
-                                                let topic_uuid =
-                                                    topic["topic_uuid"].as_str().unwrap();
-                                                dht_manager
-                                                    .write_topic(
-                                                        topic_name,
-                                                        topic_uuid,
-                                                        &new_status,
-                                                    )
-                                                    .await;
-
-                                                let _ret = update_actuator_connection(
-                                                    dht_manager,
-                                                    topic_name,
-                                                    topic_uuid,
-                                                    &new_status,
-                                                )
-                                                .await;
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+    let password = match cmd.password {
+        Some(password) => password,
+        None => {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_owned()
         }
-    }
-}
+    };
 
-async fn get_topic_from_actuator_topic(
-    dht_manager: &DHTManager,
-    source_topic_name: &str,
-    source_topic_uuid: &str,
-    channel_number: u64,
-    actuator_topic: &serde_json::Value,
-    target_topic_name: &str,
-) -> Result<serde_json::Value, Box<dyn Error>> {
-    //println!("ACTUATOR_TOPIC {}", actuator_topic);
-    let mut source_topic = dht_manager
-        .cache
-        .get_topic_uuid(source_topic_name, source_topic_uuid)?;
-
-    let channel_number_str = channel_number.to_string();
-
-    let channel_number_str = channel_number_str.as_str();
-
-    if source_topic_name == "domo_power_energy_sensor" {
-        let updated_props = actuator_topic["updated_properties"].as_array().unwrap();
-
-        let mut found = false;
-        for prop in updated_props {
-            let prop = prop.as_str().unwrap();
-            if prop == "power_data" {
-                found = true;
-            }
-        }
-
-        if !found {
-            return Err("not update".into());
-        }
-
-        source_topic["value"]["power"] = actuator_topic["power_data"]
-            ["channel".to_owned() + channel_number_str]["active_power"]
-            .clone();
-
-        let old_ene = source_topic["value"]["energy"].as_f64();
-
-        let mut old_value: f64 = 0.0;
-        if let Some(old_ene) = old_ene {
-            old_value = old_ene;
-        }
-
-        let current_ene = actuator_topic["power_data"]["channel".to_owned() + channel_number_str]
-            ["energy"]
-            .as_f64()
-            .unwrap();
-
-        let total_ene = old_value + current_ene;
-
-        source_topic["value"]["energy"] = serde_json::Value::from(total_ene);
-
-        let props = vec![
-            serde_json::Value::String("power".to_owned()),
-            serde_json::Value::String("energy".to_owned()),
-        ];
-
-        source_topic["value"]["updated_properties"] = serde_json::Value::Array(props);
+    if password.is_empty() {
+        return Err("empty password".into());
     }
 
-    if source_topic_name == "domo_light_dimmable" {
-        if target_topic_name == "shelly_dimmer" {
-            source_topic["value"]["status"] = actuator_topic["dimmer_status"].clone();
-            source_topic["value"]["power"] = actuator_topic["power1"].clone();
-
-            let old_ene = source_topic["value"]["energy"].as_f64();
-
-            let mut old_value: f64 = 0.0;
-            if let Some(old_ene) = old_ene {
-                old_value = old_ene;
-            }
-
-            let current_ene = actuator_topic["energy1"].as_f64().unwrap();
-
-            let total_ene = old_value + current_ene;
-
-            source_topic["value"]["energy"] = serde_json::Value::from(total_ene);
-
-            let updated_props = actuator_topic["updated_properties"].as_array().unwrap();
-
-            let mut props = Vec::new();
-
-            for prop in updated_props {
-                if prop == "power1" {
-                    props.push(serde_json::Value::String("power".to_owned()));
-                }
-                if prop == "energy1" {
-                    props.push(serde_json::Value::String("energy".to_owned()));
-                }
-            }
-
-            source_topic["value"]["updated_properties"] = serde_json::Value::Array(props);
-        } else if target_topic_name == "shelly_rgbw" {
-            let _val = 0;
-            if channel_number == 1 {
-                source_topic["value"]["status"] = actuator_topic["rgbw_status"]["r"].clone();
-            }
-            if channel_number == 2 {
-                source_topic["value"]["status"] = actuator_topic["rgbw_status"]["g"].clone();
-            }
+    println!("{}", credentials::hash_password(&password)?);
 
-            if channel_number == 3 {
-                source_topic["value"]["status"] = actuator_topic["rgbw_status"]["b"].clone();
-            }
+    Ok(())
+}
 
-            if channel_number == 4 {
-                source_topic["value"]["status"] = actuator_topic["rgbw_status"]["w"].clone();
-            }
-        }
-    }
+#[derive(Parser, Debug, Serialize, Deserialize)]
+#[command(
+    after_help = "Run `domo-wot-bridge hash-password [PASSWORD]` to print the user_password_hash of a device."
+)]
+struct Opt {
+    #[clap(flatten)]
+    domo_wot_bridge: DomoWotBridge,
+}
 
-    if source_topic_name == "domo_rgbw_light" {
-        source_topic["value"]["r"] = actuator_topic["rgbw_status"]["r"].clone();
-        source_topic["value"]["g"] = actuator_topic["rgbw_status"]["g"].clone();
-        source_topic["value"]["b"] = actuator_topic["rgbw_status"]["b"].clone();
-        source_topic["value"]["w"] = actuator_topic["rgbw_status"]["w"].clone();
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // hash-password needs no configuration, so it is matched before the
+    // ConfigParser, which merges the config file into Opt and has no
+    // notion of subcommands. Opt takes no positional argument: a bridge
+    // invocation always starts with an option, never with a value.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("hash-password") {
+        return hash_password_command(&args[1..]);
     }
 
-    if source_topic_name == "domo_light"
-        || source_topic_name == "domo_siren"
-        || source_topic_name == "domo_switch"
-    {
-        source_topic["value"]["status"] =
-            actuator_topic["output".to_owned() + channel_number_str].clone();
-
-        if target_topic_name != "shelly_1" && target_topic_name != "shelly_1plus" {
-            source_topic["value"]["power"] =
-                actuator_topic["power".to_owned() + channel_number_str].clone();
-
-            let old_ene = source_topic["value"]["energy"].as_f64();
-
-            let mut old_value: f64 = 0.0;
-            if let Some(old_ene) = old_ene {
-                old_value = old_ene;
-            }
-
-            let current_ene = actuator_topic["energy".to_owned() + channel_number_str]
-                .as_f64()
-                .unwrap();
-
-            let total_ene = old_value + current_ene;
-
-            source_topic["value"]["energy"] = serde_json::Value::from(total_ene);
-        }
-
-        let updated_props = actuator_topic["updated_properties"].as_array().unwrap();
-
-        //println!("UPDATED PROPS {:?}", updated_props);
-
-        let mut props = Vec::new();
-
-        for prop in updated_props {
-            let prop_str = prop.as_str().unwrap();
+    let opt = ConfigParser::<Opt>::new()
+        .with_config_path("/etc/domo/domo_wot_bridge.toml")
+        .parse();
 
-            //println!("prop_str {}", prop_str);
+    let opt = opt.domo_wot_bridge;
 
-            if prop_str == ("power".to_owned() + channel_number_str) {
-                //println!("pushing power {}", channel_number_str);
-                props.push(serde_json::Value::String("power".to_owned()));
-            }
+    env_logger::init();
 
-            if prop_str == ("energy".to_owned() + channel_number_str) {
-                //println!("pushing power {}", channel_number_str);
-                props.push(serde_json::Value::String("energy".to_owned()));
+    let status_projection = StatusProjection {
+        mapping: StatusMapping::load(opt.status_mapping.as_deref())?,
+        energy: EnergyAccountant::new(),
+        histories: HashMap::new(),
+        history_retention: HistoryRetention {
+            hours: opt.energy_history_hours,
+            days: opt.energy_history_days,
+            months: opt.energy_history_months,
+        },
+    };
+
+    let auth_guard: SharedAuthGuard = Arc::new(Mutex::new(AuthGuard::new(LockoutPolicy {
+        max_failures: opt.auth_max_failures,
+        initial_lockout: Duration::from_secs(opt.auth_lockout),
+        max_lockout: Duration::from_secs(opt.auth_max_lockout),
+    })));
+
+    let heartbeat_interval = opt.heartbeat_interval.max(1);
+
+    let health: SharedHealth = Arc::new(Mutex::new(BridgeHealth::new(
+        opt.node_id,
+        Duration::from_secs(heartbeat_interval),
+    )));
+
+    let presence = PresenceTracker::new(Duration::from_secs(opt.presence_timeout));
+
+    let mut commands = Commands {
+        queue: CommandQueue::new(RetryPolicy {
+            initial_delay: Duration::from_secs(opt.command_retry_initial_delay),
+            max_delay: Duration::from_secs(opt.command_retry_max_delay),
+            max_attempts: opt.command_retry_max_attempts,
+        }),
+        tracker: CommandTracker::new(Duration::from_secs(opt.command_timeout)),
+        valves: ValveCommandManager::new(),
+        snapshot_file: opt.snapshot_path.as_deref().map(SnapshotFile::new),
+    };
+
+    if let Some(file) = &commands.snapshot_file {
+        match file.load() {
+            Ok(Some(snapshot)) => {
+                let (restored_commands, best_actuators) = snapshot.restore(
+                    Duration::from_secs(opt.snapshot_max_age),
+                    &mut commands.queue,
+                    &mut commands.valves,
+                );
+                log::info!(
+                    "Restored {} queued commands and {} best actuators from {}",
+                    restored_commands,
+                    best_actuators,
+                    file.path
+                );
             }
+            Ok(None) => {}
+            Err(e) => log::warn!("Ignoring snapshot: {}", e),
         }
-
-        source_topic["value"]["updated_properties"] = serde_json::Value::Array(props);
     }
 
-    if source_topic_name == "domo_floor_valve" {
-        source_topic["value"]["status"] =
-            actuator_topic["output".to_owned() + channel_number_str].clone();
-    }
-
-    if source_topic_name == "domo_roller_shutter" || source_topic_name == "domo_garage_gate" {
-        source_topic["value"]["shutter_status"] = actuator_topic["shutter_status"].clone();
-    }
-
-    if source_topic_name == "domo_pir_sensor"
-        || source_topic_name == "domo_radar_sensor"
-        || source_topic_name == "domo_button"
-        || source_topic_name == "domo_bistable_button"
-    {
-        let updated_props = actuator_topic["updated_properties"].as_array().unwrap();
-
-        //println!(
-        //    "UPDATED_PROPS {:?} channel_number_str {}",
-        //    updated_props, channel_number_str
-        //);
-
-        let mut found = false;
-        for prop in updated_props {
-            let prop = prop.as_str().unwrap();
-            if prop == ("input".to_owned() + channel_number_str) {
-                source_topic["value"]["status"] =
-                    actuator_topic["input".to_owned() + channel_number_str].clone();
-                found = true;
-            }
-        }
-
-        if !found {
-            return Err("not update".into());
-        }
-    }
+    let supervisor = Supervisor::new(RestartPolicy {
+        initial_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(60),
+    });
 
-    if source_topic_name == "domo_window_sensor" || source_topic_name == "domo_door_sensor" {
-        if target_topic_name == "domo_ble_contact" {
-            source_topic["value"]["status"] = actuator_topic["status"].clone();
+    let (dht_manager, dht_task, rx_volatile) = dhtmanager::channel(opt.cache).await?;
+    // not joined on shutdown: the DHT task serves the last status write
+    // and the flush, the process exits once the flush is answered
+    supervisor.spawn("dht", move || dht_task.clone().run());
+
+    let auth_log: SharedAuthLog = Arc::new(Mutex::new(AuthLog::new(
+        opt.auth_log_size,
+        dht_manager.auth_log_next_slot(opt.auth_log_size).await,
+    )));
+
+    let wss_config = WssConfig {
+        bind_address: opt
+            .wss_bind_address
+            .parse()
+            .map_err(|e| format!("invalid wss_bind_address {}: {}", opt.wss_bind_address, e))?,
+        port: opt.wss_port,
+        tls: TlsSettings {
+            cert_path: opt.wss_cert.into(),
+            key_path: opt.wss_key.into(),
+            client_ca_path: opt.wss_client_ca.map(Into::into),
+            require_client_cert: opt.wss_mtls,
+        },
+        auth_mode: if opt.wss_mtls {
+            AuthMode::Certificate
         } else {
-            source_topic["value"]["status"] =
-                actuator_topic["input".to_owned() + channel_number_str].clone();
-        }
-    }
-
-    Ok(source_topic["value"].clone())
-}
-
-async fn update_actuator_connection(
-    dht_manager: &mut DHTManager,
-    topic_name: &str,
-    topic_uuid: &str,
-    actuator_topic: &serde_json::Value,
-) -> Result<(), Box<dyn Error>> {
-    let topics = dht_manager
-        .cache
-        .get_topic_name("domo_actuator_connection")?;
-
-    let topics = topics.as_array().unwrap();
-    for topic in topics.iter() {
-        if let Some(value) = topic.get("value") {
-            if let Some(target_topic_name) = value.get("target_topic_name") {
-                if let Some(target_topic_uuid) = value.get("target_topic_uuid") {
-                    if let Some(target_channel_number) = value.get("target_channel_number") {
-                        if let Some(source_topic_name) = value.get("source_topic_name") {
-                            let target_topic_name = target_topic_name.as_str().unwrap();
-                            let target_topic_uuid = target_topic_uuid.as_str().unwrap();
-                            let target_channel_number = target_channel_number.as_u64().unwrap();
-                            let source_topic_name = source_topic_name.as_str().unwrap();
-                            let source_topic_uuid = topic["topic_uuid"].as_str().unwrap();
-
-                            if topic_uuid == target_topic_uuid && topic_name == target_topic_name {
-                                //println!(
-                                //    "target_topic_name {} target_topic_uuid {}",
-                                //    target_topic_name, target_topic_uuid
-                                //);
-                                //println!(
-                                //    "source_topic_name {} source_topic_uuid {}",
-                                //    source_topic_name, source_topic_uuid
-                                //);
-                                //println!("target_channel_number {}", target_channel_number);
-
-                                if let Ok(status) = get_topic_from_actuator_topic(
-                                    dht_manager,
-                                    source_topic_name,
-                                    source_topic_uuid,
-                                    target_channel_number,
-                                    actuator_topic,
-                                    target_topic_name,
-                                )
-                                .await
-                                {
-                                    dht_manager
-                                        .write_topic(source_topic_name, source_topic_uuid, &status)
-                                        .await;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
+            AuthMode::Basic
+        },
+        cert_check_interval: Duration::from_secs(opt.wss_cert_check_interval),
+        api_admin: match (opt.api_admin_user, opt.api_admin_password_hash) {
+            (Some(user), Some(password_hash)) => Some(ApiAdmin {
+                user,
+                password_hash,
+            }),
+            _ => None,
+        },
+        auth_guard: auth_guard.clone(),
+        auth_log: auth_log.clone(),
+    };
+
+    let (wss_mgr, wss_requests) = WssManager::new(wss_config)
+        .await
+        .map_err(|e| format!("cannot start the ESP32 WebSocket server: {}", e))?;
+
+    let (shelly_manager, shelly_task, rx_shelly_messages) = shellyactor::channel(ReconnectPolicy {
+        initial_delay: Duration::from_secs(opt.shelly_reconnect_initial_delay),
+        max_delay: Duration::from_secs(opt.shelly_reconnect_max_delay),
+    });
+    let shelly = supervisor.spawn("shelly", move || shelly_task.clone().run());
+
+    let (tx_shutdown, rx_shutdown) = watch::channel(false);
+
+    let bridge = BridgeHandles {
+        dht_manager,
+        wss_mgr: Arc::new(wss_mgr),
+        shelly_manager,
+        health,
+        shutdown: rx_shutdown,
+    };
+
+    let discovered: SharedDiscoveries = Arc::new(Mutex::new(HashMap::new()));
+
+    let (tx_command_events, command_task) = commandactor::channel(
+        bridge.clone(),
+        commands,
+        Duration::from_secs(opt.snapshot_interval.max(1)),
+        discovered.clone(),
+        rx_volatile,
+        wss_requests.rx_api,
+    );
+
+    let status_task = StatusTask::new(
+        bridge.clone(),
+        status_projection,
+        presence,
+        rx_shelly_messages,
+        tx_command_events,
+    );
+
+    let auth_task = AuthTask::new(
+        bridge.clone(),
+        wss_requests.rx_auth_cred,
+        auth_guard,
+        auth_log,
+        opt.auth_allow_plaintext,
+    );
+
+    let heartbeat_task =
+        HeartbeatTask::new(bridge.clone(), Duration::from_secs(heartbeat_interval));
+
+    let mode_task = ModeTask::new(bridge.clone());
+
+    let discovery_task = DiscoveryTask::new(bridge.clone(), opt.node_id, discovered);
+
+    let tasks = vec![
+        supervisor.spawn("commands", move || command_task.clone().run()),
+        supervisor.spawn("status", move || status_task.clone().run()),
+        supervisor.spawn("auth", move || auth_task.clone().run()),
+        supervisor.spawn("heartbeat", move || heartbeat_task.clone().run()),
+        supervisor.spawn("shelly_mode", move || mode_task.clone().run()),
+        supervisor.spawn("mdns", move || discovery_task.clone().run()),
+    ];
+
+    shutdown_signal().await?;
+
+    shutdown(&bridge, tx_shutdown, tasks, shelly).await;
 
     Ok(())
 }
 
-async fn handle_shelly_command(
-    shelly_command: serde_json::Value,
-    _dht_manager: &mut DHTManager,
-    shelly_manager: &mut GlobalShellyManager,
-) {
-    if let Some(mac_address) = shelly_command.get("mac_address") {
-        if let Some(shelly_action_payload) = shelly_command.get("shelly_action") {
-            let shelly_action = serde_json::json!({ "shelly_action": shelly_action_payload });
-
-            let message = serde_json::json!({
-                "messageType": "requestAction",
-                "data": shelly_action
-            });
-
-            let mac_address_str = mac_address.as_str().unwrap();
-
-            //println!("DOMO: SENDING ACTION");
-
-            let _ret = shelly_manager.send_action(mac_address_str, &message).await;
-        }
-    }
-}
-
-pub fn get_shelly_discovery_result(record: &Record) -> Option<ShellyDiscoveryResult> {
-    if record.name.contains("shelly_1plus") {
-        return None;
-    }
-
-    if record.name.contains("shelly_1pm_plus") {
-        return None;
-    }
-
-    if record.name.contains("shelly_2pm_plus") {
-        return None;
-    }
+/// Waits for SIGTERM or SIGINT.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
 
-    if !record.name.contains("shelly") && !record.name.contains("geeklink") {
-        return None;
+    tokio::select! {
+        _ = sigterm.recv() => log::info!("SIGTERM received, shutting down"),
+        _ = sigint.recv() => log::info!("SIGINT received, shutting down"),
     }
 
-    let record_name = record.name.replace(".local", "");
-
-    match record.kind {
-        RecordKind::A(addr) => {
-            let name_parts: Vec<&str> = record_name.split('-').collect();
-            let topic_name = name_parts[0];
-            let mac_address = name_parts[1].to_owned();
-
-            let mac_address_with_points = mac_address[0..2].to_owned()
-                + ":"
-                + &mac_address[2..4]
-                + ":"
-                + &mac_address[4..6]
-                + ":"
-                + &mac_address[6..8]
-                + ":"
-                + &mac_address[8..10]
-                + ":"
-                + &mac_address[10..12];
-
-            let res = ShellyDiscoveryResult {
-                ip_address: addr.to_string(),
-                topic_name: topic_name.to_string(),
-                mac_address: mac_address_with_points,
-                mdns_name: record.name.to_owned(),
-            };
-            Some(res)
-        }
-        RecordKind::AAAA(addr) => {
-            let name_parts: Vec<&str> = record.name.split('-').collect();
-            let topic_name = name_parts[0];
-            let mac_address = name_parts[1];
-            let res = ShellyDiscoveryResult {
-                ip_address: addr.to_string(),
-                topic_name: topic_name.to_string(),
-                mac_address: mac_address.to_string(),
-                mdns_name: record.name.to_owned(),
-            };
-            Some(res)
-        }
-        _ => None,
-    }
-}
-
-async fn handle_ble_update_message(
-    message: BleBeaconMessage,
-    dht_manager: &mut DHTManager,
-    valve_command_manager: &mut ValveCommandManager,
-) {
-    let ret = dht_manager
-        .get_actuator_from_mac_address(&message.mac_address)
-        .await;
-
-    if let Ok(topic) = ret {
-        let topic_name = topic["topic_name"].as_str().unwrap();
-
-        if topic_name == "domo_ble_thermometer" {
-            //println!("THERMO UPDATE {}", message.payload);
-
-            if let Ok(bytes) = base64::decode(&message.payload) {
-                use hex::ToHex;
-                let beacon_adv_string = bytes.encode_hex::<String>();
-                //println!("BEACON THERMO ADV from {}: {}", message.mac_address, beacon_adv_string);
-
-                handle_ble_thermometer_update(
-                    dht_manager,
-                    &message.mac_address,
-                    &beacon_adv_string,
-                    &topic,
-                )
-                .await;
-            }
-        }
-
-        if topic_name == "domo_ble_contact" {
-            //println!("CONTACT UPDATE {}", message.payload);
-
-            if let Ok(bytes) = base64::decode(&message.payload) {
-                use hex::ToHex;
-                let beacon_adv_string = bytes.encode_hex::<String>();
-                //println!("BEACON CONTACT ADV from {}: {}", message.mac_address, beacon_adv_string);
-
-                handle_ble_contact_update(
-                    dht_manager,
-                    &message.mac_address,
-                    &beacon_adv_string,
-                    &message.rssi,
-                    &topic,
-                )
-                .await;
-            }
-        }
-
-        if topic_name == "domo_ble_valve" {
-            if message.payload == "0" || message.payload == "1" {
-                handle_ble_valve_update(
-                    dht_manager,
-                    &message.mac_address,
-                    &message.payload,
-                    &topic,
-                )
-                .await;
-            } else {
-                // update best actuator to use for valve depending on rssi
-                valve_command_manager.update_best_actuator(
-                    &message.mac_address,
-                    &message.actuator,
-                    message.rssi,
-                );
-            }
-        }
-    }
+    Ok(())
 }
 
-async fn handle_ble_thermometer_update(
-    dht_manager: &mut DHTManager,
-    _mac_address: &str,
-    message: &str,
-    topic: &serde_json::Value,
-) {
-    let topic_uuid = topic["topic_uuid"].as_str().unwrap();
-    let value_of_topic = &topic["value"];
-    let token = value_of_topic["token"].as_str().unwrap();
-    let mac_address = value_of_topic["mac_address"].as_str().unwrap();
-    let name = value_of_topic["name"].as_str().unwrap();
-    let area_name = value_of_topic["area_name"].as_str().unwrap();
-
-    let ret = bleutils::parse_atc(mac_address, message, token);
-
-    if let Ok(m) = ret {
-        //println!("DECRITTATO {} {} {}", m.temperature, m.humidity, m.battery);
-        let value = serde_json::json!({
-            "temperature": m.temperature,
-            "humidity": m.humidity,
-            "battery":  m.battery,
-            "token": token,
-            "mac_address": mac_address,
-            "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
-            "name": name,
-            "area_name": area_name
-        });
-
-        dht_manager
-            .write_topic("domo_ble_thermometer", topic_uuid, &value)
-            .await;
-    }
-}
+/// Waits for Ctrl-C, the only shutdown request without Unix signals.
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    log::info!("Ctrl-C received, shutting down");
 
-async fn handle_ble_contact_update(
-    dht_manager: &mut DHTManager,
-    _mac_address: &str,
-    message: &str,
-    rssi: &i64,
-    topic: &serde_json::Value,
-) {
-    if message.len() >= 58 {
-        println!("MESSAGE: {}", message);
-        let topic_uuid = topic["topic_uuid"].as_str().unwrap();
-        let value_of_topic = &topic["value"];
-        let token = value_of_topic["token"].as_str().unwrap();
-        let id = value_of_topic["id"].as_str().unwrap();
-        let mac_address = value_of_topic["mac_address"].as_str().unwrap();
-        let area_name = value_of_topic["area_name"].as_str().unwrap();
-
-        let len_hex_value = "1d";
-        let rssi_i = *rssi as i8;
-        let rssi_hex = format!("{:02x}", rssi_i);
-
-        let rssi_hex = rssi_hex.as_str();
-
-        let data = len_hex_value.to_owned() + message + rssi_hex;
-
-        //println!("mac {} token {} payload {}", mac_address, token, message);
-
-        let ret = bleutils::parse_contact_sensor(mac_address, &data, token);
-        if let Ok(m) = ret {
-            let val = u64::from(m.state != ContactStatus::Open);
-            //println!("Value_of_topic {}", value_of_topic);
-            if let Some(val_in_topic) = value_of_topic.get("status") {
-                //println!("{}", val_in_topic);
-                let val_in_topic = val_in_topic.as_u64().unwrap();
-                //println!("val {}, value_of_topic {}", val, val_in_topic);
-                if val != val_in_topic {
-                    let value = serde_json::json!({
-                    "status": val,
-                    "token": token,
-                    "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
-                    "mac_address": mac_address,
-                        "id": id,
-                        "area_name": area_name
-                     });
-
-                    dht_manager
-                        .write_topic("domo_ble_contact", topic_uuid, &value)
-                        .await;
-                    let _ret = update_actuator_connection(
-                        dht_manager,
-                        "domo_ble_contact",
-                        topic_uuid,
-                        &value,
-                    )
-                    .await;
-                }
-            } else {
-                let value = serde_json::json!({
-                "status": val,
-                "token": token,
-                "mac_address": mac_address,
-                "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
-                "id": id,
-                "area_name": area_name
-                });
-
-                dht_manager
-                    .write_topic("domo_ble_contact", topic_uuid, &value)
-                    .await;
-                let _ret =
-                    update_actuator_connection(dht_manager, "domo_ble_contact", topic_uuid, &value)
-                        .await;
-            }
-        }
-    }
+    Ok(())
 }
 
-async fn handle_ble_valve_update(
-    dht_manager: &mut DHTManager,
-    _mac_address: &str,
-    message: &String,
-    topic: &serde_json::Value,
+/// Time given to the devices to close their connections on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stops the tasks, the command task saving the pending commands, closes
+/// the connections of the devices and writes the last bridge status,
+/// marked offline. The Shelly task stops on close, after the other tasks
+/// that may still send it actions.
+async fn shutdown(
+    bridge: &BridgeHandles,
+    tx_shutdown: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+    shelly: JoinHandle<()>,
 ) {
-    let topic_uuid = topic["topic_uuid"].as_str().unwrap();
-    let value_of_topic = &topic["value"];
-    let mac_address = value_of_topic["mac_address"].as_str().unwrap();
-    let name = value_of_topic["name"].as_str().unwrap();
-    let area_name = value_of_topic["area_name"].as_str().unwrap();
-
-    let value: bool = message == "1";
-
-    let value = serde_json::json!(
-    {   "status": value,
-        "mac_address": mac_address,
-        "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
-        "name": name,
-        "area_name": area_name
-    });
+    let devices = heartbeatactor::device_counts(&bridge.shelly_manager, &bridge.wss_mgr);
 
-    dht_manager
-        .write_topic("domo_ble_valve", topic_uuid, &value)
-        .await;
-}
+    let _ret = tx_shutdown.send(true);
 
-async fn calculate_mode(
-    act_connections: &Vec<serde_json::Value>,
-    act_topic_name: &str,
-    act_topic_uuid: &str,
-) -> u64 {
-    if [
-        "shelly_1",
-        "shelly_1plus",
-        "shelly_1pm",
-        "shelly_em",
-        "shelly_1pm_plus",
-    ]
-    .contains(&act_topic_name)
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, futures::future::join_all(tasks))
+        .await
+        .is_err()
     {
-        return 0; // RELAY
+        log::warn!("Tasks still running after {:?}", SHUTDOWN_TIMEOUT);
     }
 
-    if ["shelly_dimmer"].contains(&act_topic_name) {
-        return 2; // DIMMER
-    }
-
-    let mut ret = 0;
-
-    if act_topic_name == "shelly_25" || act_topic_name == "shelly_2pm_plus" {
-        ret = 0; // RELAY
-    }
-
-    if act_topic_name == "shelly_rgbw" {
-        ret = 4; // LED_DIMMER
-    }
-
-    for conn in act_connections {
-        if let Some(value) = conn.get("value") {
-            if let Some(connection_type) = value.get("connection_type") {
-                let connection_type = connection_type.as_str().unwrap();
-                if connection_type != "output" {
-                    continue;
-                }
-            }
-
-            if let Some(target_topic_name) = value.get("target_topic_name") {
-                if let Some(target_topic_uuid) = value.get("target_topic_uuid") {
-                    if let Some(source_topic_name) = value.get("source_topic_name") {
-                        if let Some(source_topic_uuid) = value.get("source_topic_uuid") {
-                            let target_topic_name = target_topic_name.as_str().unwrap();
-                            let target_topic_uuid = target_topic_uuid.as_str().unwrap();
-                            let source_topic_name = source_topic_name.as_str().unwrap();
-                            let _source_topic_uuid = source_topic_uuid.as_str().unwrap();
-
-                            if target_topic_uuid == act_topic_uuid
-                                && target_topic_name == act_topic_name
-                            {
-                                if (target_topic_name == "shelly_25"
-                                    || target_topic_name == "shelly_2pm_plus")
-                                    && (source_topic_name == "domo_roller_shutter"
-                                        || source_topic_name == "domo_garage_gate")
-                                {
-                                    return 1; // SHUTTER
-                                }
-                                if target_topic_name == "shelly_rgbw"
-                                    && source_topic_name == "domo_rgbw_light"
-                                {
-                                    return 3; // RGBW
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    bridge.wss_mgr.shutdown(SHUTDOWN_TIMEOUT).await;
+    bridge.shelly_manager.close(SHUTDOWN_TIMEOUT).await;
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, shelly)
+        .await
+        .is_err()
+    {
+        log::warn!("Shelly task still running after {:?}", SHUTDOWN_TIMEOUT);
     }
 
-    ret
-}
-
-async fn check_shelly_esp8266_mode(
-    actuator_connections: &Vec<serde_json::Value>,
-    shelly_manager: &mut GlobalShellyManager,
-    dht_manager: &mut DHTManager,
-) {
-    let mut to_remove = Vec::new();
-    for (idx, act) in &mut shelly_manager.shelly_list.iter_mut().enumerate() {
-        if let Ok(topic_of_act) = dht_manager
-            .get_actuator_from_mac_address(&act.mac_address)
-            .await
-        {
-            if let Some(value) = topic_of_act.get("value") {
-                if let Some(mode) = value.get("mode") {
-                    let mode = mode.as_u64().unwrap();
-                    let act_topic_name = topic_of_act["topic_name"].as_str().unwrap();
-                    let act_topic_uuid = topic_of_act["topic_uuid"].as_str().unwrap();
-                    let desired_mode =
-                        calculate_mode(actuator_connections, act_topic_name, act_topic_uuid).await;
-
-                    let mut inverted = false;
-                    if let Some(inv) = value.get("inverted") {
-                        inverted = inv.as_bool().unwrap();
-                    }
-
-                    if mode != desired_mode {
-                        //println!(
-                        //    "Change mode of {} {} to {} ",
-                        //    act_topic_name, act_topic_uuid, desired_mode
-                        //);
-
-                        let action_payload = serde_json::json!({
-                            "mode": desired_mode,
-                            "inverted": inverted
-                        });
-
-                        let action_payload_string = action_payload.to_string();
-
-                        let shelly_action = serde_json::json!({
-                            "shelly_action" : {
-                                "input" : {
-                                    "action": {
-                                        "action_name": "change_mode",
-                                        "action_payload": action_payload_string
-                                    }
-                                }
-                            }
-                        });
-
-                        let message = serde_json::json!({
-                            "messageType": "requestAction",
-                            "data": shelly_action
-                        });
-
-                        act.send_action(&message).await;
-                        to_remove.push(idx);
-                    }
-                }
-            }
-        }
-    }
+    let mut status = bridge.health.lock().unwrap().status(devices);
+    status.online = false;
+    bridge.dht_manager.write_bridge_status(&status).await;
 
-    for id in to_remove {
-        shelly_manager.shelly_list.remove(id);
-    }
-}
+    // the DHT task hands the queued writes to the DHT before the exit
+    bridge.dht_manager.flush().await;
 
-async fn check_shelly_esp32_mode(
-    actuator_connections: &Vec<serde_json::Value>,
-    shelly_plus_list: &Vec<String>,
-    dht_manager: &mut DHTManager,
-    wss_mgr: &mut WssManager,
-) {
-    for act in shelly_plus_list {
-        if let Ok(topic_of_act) = dht_manager.get_actuator_from_mac_address(act).await {
-            if let Some(value) = topic_of_act.get("value") {
-                if let Some(mode) = value.get("mode") {
-                    let mode = mode.as_u64().unwrap();
-                    let act_topic_name = topic_of_act["topic_name"].as_str().unwrap();
-                    let act_topic_uuid = topic_of_act["topic_uuid"].as_str().unwrap();
-                    let desired_mode =
-                        calculate_mode(actuator_connections, act_topic_name, act_topic_uuid).await;
-
-                    let mut inverted = false;
-                    if let Some(inv) = value.get("inverted") {
-                        inverted = inv.as_bool().unwrap();
-                    }
-
-                    if mode != desired_mode {
-                        //println!(
-                        //    "Change mode of {} {} to {} ",
-                        //    act_topic_name, act_topic_uuid, desired_mode
-                        //);
-                        let action_payload = serde_json::json!({
-                            "mode": desired_mode,
-                            "inverted": inverted
-                        });
-
-                        let action_payload_string = action_payload.to_string();
-
-                        let shelly_action = serde_json::json!({
-                            "shelly_action" : {
-                                "input" : {
-                                    "action": {
-                                        "action_name": "change_mode",
-                                        "action_payload": action_payload_string
-                                    }
-                                }
-                            }
-                        });
-
-                        let cmd = ESP32CommandMessage {
-                            command_type: ESP32CommandType::Actuator,
-                            mac_address: act.to_owned(),
-                            payload: shelly_action.clone(),
-                            actuator_mac_address: String::from(""),
-                        };
-
-                        let _ret = wss_mgr.command_channel_tx.send(cmd);
-                    }
-                }
-            }
-        }
-    }
+    log::info!("Shutdown complete");
 }
@@ -1,13 +1,41 @@
 use serde::Serialize;
+use std::net::IpAddr;
 
 use tokio::sync::oneshot;
 
 type AuthCredResponder = oneshot::Sender<Result<serde_json::Value, String>>;
 
+#[derive(Debug)]
+pub enum Credentials {
+    /// HTTP Basic user and password.
+    Basic { user: String, pass: String },
+    /// Identity taken from a verified client certificate.
+    Certificate { mac_address: String, serial: String },
+}
+
+impl Credentials {
+    /// Login method, as written in the audit log.
+    pub fn method(&self) -> &'static str {
+        match self {
+            Credentials::Basic { .. } => "basic",
+            Credentials::Certificate { .. } => "certificate",
+        }
+    }
+
+    /// Name counted by the lockout, the mac address for certificates.
+    pub fn user(&self) -> &str {
+        match self {
+            Credentials::Basic { user, .. } => user,
+            Credentials::Certificate { mac_address, .. } => mac_address,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AuthCredMessage {
-    pub user: String,
-    pub pass: String,
+    pub credentials: Credentials,
+    /// Address the WebSocket connection comes from.
+    pub source_ip: IpAddr,
     pub responder: AuthCredResponder,
 }
 
@@ -21,6 +49,7 @@ pub enum ESP32CommandType {
 pub struct ESP32CommandMessage {
     pub command_type: ESP32CommandType,
     pub mac_address: String,
+    /// `requestAction` message forwarded as is to the ESP32
     pub payload: serde_json::Value,
     pub actuator_mac_address: String,
 }
@@ -0,0 +1,154 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::OnceLock;
+
+/// Counters exposed on `/metrics`, registered once in a private registry.
+pub struct Metrics {
+    registry: Registry,
+    pub shelly_connected: IntGauge,
+    pub esp32_connected: IntGauge,
+    /// Reconnections of the Shelly gen1, by result.
+    pub shelly_reconnects: IntCounterVec,
+    pub commands_received: IntCounterVec,
+    /// Commands not handed to an actuator and not sent again, by reason.
+    pub commands_dropped: IntCounterVec,
+    pub valve_retries: IntCounter,
+    pub valve_give_ups: IntCounter,
+    /// BLE advertisements of known sensors, by topic name.
+    pub ble_beacons: IntCounterVec,
+    pub ble_decrypt_failures: IntCounterVec,
+    pub dht_writes: IntCounterVec,
+    /// Time spent handling an event of a task, by event.
+    pub event_loop_latency: HistogramVec,
+    /// Restarts of the supervised tasks, by task.
+    pub task_restarts: IntCounterVec,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+fn opts(name: &str, help: &str) -> Opts {
+    Opts::new(name, help).namespace("domo_wot_bridge")
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let gauge = |name, help| {
+            let gauge = IntGauge::with_opts(opts(name, help)).unwrap();
+            registry.register(Box::new(gauge.clone())).unwrap();
+            gauge
+        };
+
+        let counter = |name, help| {
+            let counter = IntCounter::with_opts(opts(name, help)).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+
+        let counter_vec = |name, help, labels: &[&str]| {
+            let counter = IntCounterVec::new(opts(name, help), labels).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+
+        let event_loop_latency = HistogramVec::new(
+            HistogramOpts::from(opts(
+                "event_loop_latency_seconds",
+                "time spent handling an event of a task",
+            ))
+            .buckets(vec![
+                0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+            ]),
+            &["event"],
+        )
+        .unwrap();
+        registry
+            .register(Box::new(event_loop_latency.clone()))
+            .unwrap();
+
+        Metrics {
+            shelly_connected: gauge("shelly_connected", "connected Shelly gen1"),
+            esp32_connected: gauge("esp32_connected", "ESP32 with an open session"),
+            shelly_reconnects: counter_vec(
+                "shelly_reconnects_total",
+                "reconnections of the Shelly gen1",
+                &["result"],
+            ),
+            commands_received: counter_vec(
+                "commands_received_total",
+                "commands received from the DHT and the REST API",
+                &["command_type"],
+            ),
+            commands_dropped: counter_vec(
+                "commands_dropped_total",
+                "commands not handed to an actuator",
+                &["reason"],
+            ),
+            valve_retries: counter("valve_retries_total", "valve commands sent again"),
+            valve_give_ups: counter(
+                "valve_give_ups_total",
+                "valve commands abandoned after the last attempt",
+            ),
+            ble_beacons: counter_vec(
+                "ble_beacons_total",
+                "BLE advertisements of known sensors",
+                &["sensor_type"],
+            ),
+            ble_decrypt_failures: counter_vec(
+                "ble_decrypt_failures_total",
+                "BLE advertisements that failed to decrypt",
+                &["sensor_type"],
+            ),
+            dht_writes: counter_vec(
+                "dht_writes_total",
+                "persistent values written to the DHT",
+                &["topic_name"],
+            ),
+            event_loop_latency,
+            task_restarts: counter_vec(
+                "task_restarts_total",
+                "restarts of the supervised tasks",
+                &["task"],
+            ),
+            registry,
+        }
+    }
+
+    /// Text exposition format of every metric.
+    pub fn render(&self) -> Result<String, String> {
+        let mut buffer = Vec::new();
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| e.to_string())?;
+
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered() {
+        metrics()
+            .commands_received
+            .with_label_values(&["turn_command"])
+            .inc();
+
+        let text = metrics().render().unwrap();
+
+        assert!(
+            text.contains("domo_wot_bridge_commands_received_total{command_type=\"turn_command\"}")
+        );
+        assert!(text.contains("# TYPE domo_wot_bridge_shelly_connected gauge"));
+    }
+}
@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use crate::commands::{request_action_message, shelly_action};
+use crate::dhtmanager::DHTManager;
+use crate::drivers::{self, Mode};
+use crate::messages::{ESP32CommandMessage, ESP32CommandType};
+use crate::metrics::metrics;
+use crate::shellyactor::ShellyHandle;
+use crate::supervisor::TaskResult;
+use crate::wssmanager::WssManager;
+use crate::BridgeHandles;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Changes the mode of the actuators whose outputs are wired to topics
+/// needing another mode.
+#[derive(Clone)]
+pub struct ModeTask {
+    bridge: BridgeHandles,
+}
+
+impl ModeTask {
+    pub fn new(bridge: BridgeHandles) -> Self {
+        ModeTask { bridge }
+    }
+
+    pub async fn run(self) -> TaskResult {
+        let mut shutdown = self.bridge.shutdown.clone();
+
+        let BridgeHandles {
+            dht_manager,
+            wss_mgr,
+            shelly_manager,
+            ..
+        } = &self.bridge;
+
+        let mut check_shelly_mode = tokio::time::interval(CHECK_INTERVAL);
+
+        let mut counter = 0;
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return Ok(()),
+                _ = check_shelly_mode.tick() => {
+                    counter += 1;
+                    let _timer = metrics().event_loop_latency.with_label_values(&["shelly_mode"]).start_timer();
+                    log::info!("CHECK_SHELLY_MODE {}", counter);
+
+                    if let Ok(actuator_connections) = dht_manager.get_topic_name("domo_actuator_connection").await {
+                        let actuator_connections = actuator_connections.as_array().unwrap();
+
+                        check_shelly_esp8266_mode(actuator_connections, shelly_manager, dht_manager).await;
+
+                        let shelly_plus_actuators = wss_mgr.connected_actuators();
+
+                        check_shelly_esp32_mode(actuator_connections, &shelly_plus_actuators, dht_manager, wss_mgr).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn calculate_mode(
+    act_connections: &Vec<serde_json::Value>,
+    act_topic_name: &str,
+    act_topic_uuid: &str,
+) -> u64 {
+    let driver = match drivers::get(act_topic_name) {
+        Some(driver) => driver,
+        None => return Mode::Relay.code(),
+    };
+
+    let default_mode = driver
+        .supported_modes()
+        .first()
+        .copied()
+        .unwrap_or(Mode::Relay);
+
+    for conn in act_connections {
+        if let Some(value) = conn.get("value") {
+            if let Some(connection_type) = value.get("connection_type") {
+                let connection_type = connection_type.as_str().unwrap();
+                if connection_type != "output" {
+                    continue;
+                }
+            }
+
+            if let Some(target_topic_name) = value.get("target_topic_name") {
+                if let Some(target_topic_uuid) = value.get("target_topic_uuid") {
+                    if let Some(source_topic_name) = value.get("source_topic_name") {
+                        let target_topic_name = target_topic_name.as_str().unwrap();
+                        let target_topic_uuid = target_topic_uuid.as_str().unwrap();
+                        let source_topic_name = source_topic_name.as_str().unwrap();
+
+                        if target_topic_uuid == act_topic_uuid
+                            && target_topic_name == act_topic_name
+                        {
+                            if let Some(mode) = driver.mode_for(source_topic_name) {
+                                return mode.code();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    default_mode.code()
+}
+
+async fn check_shelly_esp8266_mode(
+    actuator_connections: &Vec<serde_json::Value>,
+    shelly_manager: &ShellyHandle,
+    dht_manager: &DHTManager,
+) {
+    for act in shelly_manager.online() {
+        if let Ok(topic_of_act) = dht_manager
+            .get_actuator_from_mac_address(&act.mac_address)
+            .await
+        {
+            if let Some(value) = topic_of_act.get("value") {
+                if let Some(mode) = value.get("mode") {
+                    let mode = mode.as_u64().unwrap();
+                    let act_topic_name = topic_of_act["topic_name"].as_str().unwrap();
+                    let act_topic_uuid = topic_of_act["topic_uuid"].as_str().unwrap();
+                    let desired_mode =
+                        calculate_mode(actuator_connections, act_topic_name, act_topic_uuid).await;
+
+                    let mut inverted = false;
+                    if let Some(inv) = value.get("inverted") {
+                        inverted = inv.as_bool().unwrap();
+                    }
+
+                    if mode != desired_mode {
+                        //println!(
+                        //    "Change mode of {} {} to {} ",
+                        //    act_topic_name, act_topic_uuid, desired_mode
+                        //);
+
+                        let action_payload = serde_json::json!({
+                            "mode": desired_mode,
+                            "inverted": inverted
+                        });
+
+                        let message =
+                            request_action_message(&shelly_action("change_mode", &action_payload));
+
+                        shelly_manager.change_mode(&act.mac_address, &message);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn check_shelly_esp32_mode(
+    actuator_connections: &Vec<serde_json::Value>,
+    shelly_plus_list: &Vec<String>,
+    dht_manager: &DHTManager,
+    wss_mgr: &WssManager,
+) {
+    for act in shelly_plus_list {
+        if let Ok(topic_of_act) = dht_manager.get_actuator_from_mac_address(act).await {
+            if let Some(value) = topic_of_act.get("value") {
+                if let Some(mode) = value.get("mode") {
+                    let mode = mode.as_u64().unwrap();
+                    let act_topic_name = topic_of_act["topic_name"].as_str().unwrap();
+                    let act_topic_uuid = topic_of_act["topic_uuid"].as_str().unwrap();
+                    let desired_mode =
+                        calculate_mode(actuator_connections, act_topic_name, act_topic_uuid).await;
+
+                    let mut inverted = false;
+                    if let Some(inv) = value.get("inverted") {
+                        inverted = inv.as_bool().unwrap();
+                    }
+
+                    if mode != desired_mode {
+                        //println!(
+                        //    "Change mode of {} {} to {} ",
+                        //    act_topic_name, act_topic_uuid, desired_mode
+                        //);
+                        let action_payload = serde_json::json!({
+                            "mode": desired_mode,
+                            "inverted": inverted
+                        });
+
+                        let cmd = ESP32CommandMessage {
+                            command_type: ESP32CommandType::Actuator,
+                            mac_address: act.to_owned(),
+                            payload: request_action_message(&shelly_action(
+                                "change_mode",
+                                &action_payload,
+                            )),
+                            actuator_mac_address: String::from(""),
+                        };
+
+                        if let Err(e) = wss_mgr.send_command(cmd).await {
+                            log::warn!("Cannot change the mode of {}: {}", act, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Sets the presence fields written in the actuator topics.
+pub fn stamp(value: &mut serde_json::Value, online: bool, last_seen: DateTime<Local>) {
+    value["online"] = serde_json::Value::Bool(online);
+    value["last_seen"] = serde_json::Value::String(last_seen.to_rfc3339());
+}
+
+struct DeviceState {
+    last_seen: DateTime<Local>,
+    online: bool,
+}
+
+/// Online state of the actuators and BLE devices, keyed by the mac address
+/// stored in their topic.
+///
+/// A device is online when it was seen, through a pong, a status update or
+/// a beacon, within the silence timeout, and offline once the timeout
+/// expires or its session closes. Every method returns the transitions the
+/// caller has to write in the DHT.
+pub struct PresenceTracker {
+    timeout: chrono::Duration,
+    devices: HashMap<String, DeviceState>,
+}
+
+impl PresenceTracker {
+    pub fn new(timeout: Duration) -> Self {
+        PresenceTracker {
+            timeout: chrono::Duration::from_std(timeout).unwrap_or(chrono::Duration::MAX),
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Records that `mac_address` was alive at `at`, returns true when it
+    /// comes online.
+    pub fn seen(&mut self, mac_address: &str, at: DateTime<Local>) -> bool {
+        let recent = Local::now() - at <= self.timeout;
+
+        let device = self
+            .devices
+            .entry(mac_address.to_owned())
+            .or_insert(DeviceState {
+                last_seen: at,
+                online: false,
+            });
+
+        if at > device.last_seen {
+            device.last_seen = at;
+        }
+
+        if recent && !device.online {
+            device.online = true;
+            return true;
+        }
+
+        false
+    }
+
+    /// The connection to `mac_address` closed, returns the last time it
+    /// was seen when it goes offline.
+    pub fn disconnected(&mut self, mac_address: &str) -> Option<DateTime<Local>> {
+        match self.devices.get_mut(mac_address) {
+            Some(device) if device.online => {
+                device.online = false;
+                Some(device.last_seen)
+            }
+            _ => None,
+        }
+    }
+
+    /// Devices silent for longer than the timeout, with the last time they
+    /// were seen.
+    pub fn expired(&mut self, now: DateTime<Local>) -> Vec<(String, DateTime<Local>)> {
+        let mut expired = Vec::new();
+
+        for (mac_address, device) in self.devices.iter_mut() {
+            if device.online && now - device.last_seen > self.timeout {
+                device.online = false;
+                expired.push((mac_address.to_owned(), device.last_seen));
+            }
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silence_marks_devices_offline() {
+        let mut presence = PresenceTracker::new(Duration::from_secs(60));
+        let now = Local::now();
+
+        assert!(presence.seen("AA", now));
+        assert!(!presence.seen("AA", now));
+        assert!(presence
+            .expired(now + chrono::Duration::seconds(30))
+            .is_empty());
+
+        let expired = presence.expired(now + chrono::Duration::seconds(61));
+        assert_eq!(expired, [("AA".to_owned(), now)]);
+        assert!(presence
+            .expired(now + chrono::Duration::seconds(120))
+            .is_empty());
+
+        // a stale pong does not bring a device back
+        assert!(!presence.seen("BB", now - chrono::Duration::seconds(300)));
+    }
+
+    #[test]
+    fn closed_session_marks_device_offline() {
+        let mut presence = PresenceTracker::new(Duration::from_secs(60));
+        let now = Local::now();
+
+        presence.seen("AA", now);
+
+        assert_eq!(presence.disconnected("AA"), Some(now));
+        assert_eq!(presence.disconnected("AA"), None);
+        assert!(presence.seen("AA", Local::now()));
+    }
+}
@@ -0,0 +1,255 @@
+use crate::messages::ESP32CommandMessage;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// Commands queued for a device before senders have to wait.
+const COMMAND_QUEUE_SIZE: usize = 32;
+
+/// Connection of an ESP32, the last one stays listed after it closes.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub mac_address: String,
+    pub topic_name: String,
+    pub source_ip: IpAddr,
+    pub connected_at: DateTime<Local>,
+    pub last_pong: Option<DateTime<Local>>,
+    pub disconnected_at: Option<DateTime<Local>>,
+}
+
+impl SessionInfo {
+    pub fn is_connected(&self) -> bool {
+        self.disconnected_at.is_none()
+    }
+}
+
+struct Session {
+    id: u64,
+    info: SessionInfo,
+    /// Dropped or fired to make the socket task of the session return.
+    evict: Option<oneshot::Sender<()>>,
+    commands: Option<mpsc::Sender<ESP32CommandMessage>>,
+}
+
+/// What the socket task of a session receives from the registry.
+pub struct SessionHandle {
+    pub id: u64,
+    /// Completed when a newer session of the device evicts this one, or
+    /// when the bridge shuts down.
+    pub evicted: oneshot::Receiver<()>,
+    /// Commands addressed to the device.
+    pub commands: mpsc::Receiver<ESP32CommandMessage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// No session is open for the mac address.
+    NotConnected(String),
+    /// The command queue of the device stayed full.
+    Timeout(String),
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::NotConnected(mac_address) => write!(f, "{} not connected", mac_address),
+            DeliveryError::Timeout(mac_address) => {
+                write!(f, "command queue of {} is full", mac_address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// WebSocket sessions of the ESP32, at most one per mac address.
+///
+/// A device reconnecting before its old socket times out evicts the older
+/// session, so that commands are forwarded by a single task.
+#[derive(Default)]
+pub struct SessionRegistry {
+    next_id: u64,
+    sessions: HashMap<String, Session>,
+}
+
+pub type SharedSessions = Arc<Mutex<SessionRegistry>>;
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the session of `mac_address`, evicting the previous one.
+    pub fn register(
+        &mut self,
+        mac_address: &str,
+        topic_name: &str,
+        source_ip: IpAddr,
+    ) -> SessionHandle {
+        let (evict_tx, evict_rx) = oneshot::channel();
+        let (commands_tx, commands_rx) = mpsc::channel(COMMAND_QUEUE_SIZE);
+
+        self.next_id += 1;
+
+        let session = Session {
+            id: self.next_id,
+            info: SessionInfo {
+                mac_address: mac_address.to_owned(),
+                topic_name: topic_name.to_owned(),
+                source_ip,
+                connected_at: Local::now(),
+                last_pong: None,
+                disconnected_at: None,
+            },
+            evict: Some(evict_tx),
+            commands: Some(commands_tx),
+        };
+
+        if let Some(old) = self.sessions.insert(mac_address.to_owned(), session) {
+            if let Some(evict) = old.evict {
+                log::info!(
+                    "{} reconnected from {}, closing its session from {}",
+                    mac_address,
+                    source_ip,
+                    old.info.source_ip
+                );
+                let _ = evict.send(());
+            }
+        }
+
+        SessionHandle {
+            id: self.next_id,
+            evicted: evict_rx,
+            commands: commands_rx,
+        }
+    }
+
+    /// Marks session `id` of `mac_address` as closed, unless a newer
+    /// session has replaced it.
+    pub fn unregister(&mut self, mac_address: &str, id: u64) {
+        if let Some(session) = self.sessions.get_mut(mac_address) {
+            if session.id == id {
+                session.evict = None;
+                session.commands = None;
+                session.info.disconnected_at = Some(Local::now());
+            }
+        }
+    }
+
+    /// Asks the socket task of every open session to close its socket.
+    pub fn close_all(&mut self) {
+        for session in self.sessions.values_mut() {
+            if let Some(evict) = session.evict.take() {
+                let _ = evict.send(());
+            }
+        }
+    }
+
+    pub fn pong(&mut self, mac_address: &str, id: u64) {
+        if let Some(session) = self.sessions.get_mut(mac_address) {
+            if session.id == id {
+                session.info.last_pong = Some(Local::now());
+            }
+        }
+    }
+
+    /// Sender of the commands addressed to `mac_address`.
+    pub fn sender(
+        &self,
+        mac_address: &str,
+    ) -> Result<mpsc::Sender<ESP32CommandMessage>, DeliveryError> {
+        self.sessions
+            .get(mac_address)
+            .and_then(|s| s.commands.clone())
+            .ok_or_else(|| DeliveryError::NotConnected(mac_address.to_owned()))
+    }
+
+    /// Senders of every connected device.
+    pub fn senders(&self) -> Vec<mpsc::Sender<ESP32CommandMessage>> {
+        self.sessions
+            .values()
+            .filter_map(|s| s.commands.clone())
+            .collect()
+    }
+
+    /// Mac addresses of the connected devices.
+    pub fn connected(&self) -> Vec<String> {
+        self.sessions
+            .values()
+            .filter(|s| s.info.is_connected())
+            .map(|s| s.info.mac_address.to_owned())
+            .collect()
+    }
+
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions.values().map(|s| s.info.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_evicts_the_older_session() {
+        let mut registry = SessionRegistry::new();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let mut first = registry.register("AA", "shelly_1plus", ip);
+        let mut second = registry.register("AA", "shelly_1plus", ip);
+
+        assert!(first.evicted.try_recv().is_ok());
+        assert!(second.evicted.try_recv().is_err());
+
+        // the evicted task closing must not end the new session
+        registry.unregister("AA", first.id);
+        assert_eq!(registry.connected(), ["AA"]);
+        assert!(registry.sender("AA").is_ok());
+
+        registry.unregister("AA", second.id);
+        assert!(registry.connected().is_empty());
+        assert_eq!(
+            registry.sender("AA").err(),
+            Some(DeliveryError::NotConnected("AA".to_owned()))
+        );
+        assert!(registry.sessions["AA"].info.disconnected_at.is_some());
+    }
+
+    #[test]
+    fn close_all_evicts_every_session() {
+        let mut registry = SessionRegistry::new();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let mut aa = registry.register("AA", "shelly_1plus", ip);
+        let mut bb = registry.register("BB", "shelly_1plus", ip);
+
+        registry.close_all();
+
+        assert!(aa.evicted.try_recv().is_ok());
+        assert!(bb.evicted.try_recv().is_ok());
+    }
+
+    #[test]
+    fn commands_reach_only_the_addressed_device() {
+        let mut registry = SessionRegistry::new();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let mut aa = registry.register("AA", "shelly_1plus", ip);
+        let mut bb = registry.register("BB", "shelly_1plus", ip);
+
+        let cmd = ESP32CommandMessage {
+            command_type: crate::messages::ESP32CommandType::Actuator,
+            mac_address: "BB".to_owned(),
+            payload: serde_json::json!({}),
+            actuator_mac_address: String::new(),
+        };
+        registry.sender("BB").unwrap().try_send(cmd).unwrap();
+
+        assert!(aa.commands.try_recv().is_err());
+        assert_eq!(bb.commands.try_recv().unwrap().mac_address, "BB");
+    }
+}
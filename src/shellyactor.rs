@@ -0,0 +1,278 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::globalshellymanager::{GlobalShellyManager, ReconnectPolicy};
+use crate::shellymanager::ShellyTarget;
+use crate::supervisor::TaskResult;
+use crate::ShellyDiscoveryResult;
+
+/// Requests queued for the Shelly task before new ones are dropped.
+const REQUEST_QUEUE_SIZE: usize = 32;
+
+const MESSAGE_QUEUE_SIZE: usize = 32;
+
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Resolution of the reconnection backoff.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connection to a Shelly gen1, as published by the Shelly task.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShellyLink {
+    pub mac_address: String,
+    pub topic_name: String,
+    pub ip: String,
+    pub url: String,
+    /// False while the websocket is being opened again.
+    pub online: bool,
+    pub last_pong: Option<DateTime<Local>>,
+    /// Failed connections since the Shelly went offline.
+    pub reconnect_attempts: u32,
+}
+
+enum ShellyRequest {
+    Connect {
+        discovery: ShellyDiscoveryResult,
+        user_login: String,
+        user_password: String,
+    },
+    Action {
+        mac_address: String,
+        message: serde_json::Value,
+    },
+    /// Sends the action and forgets the Shelly, it reboots in the new mode
+    /// and is connected again once announced.
+    ChangeMode {
+        mac_address: String,
+        message: serde_json::Value,
+    },
+    Close {
+        timeout: Duration,
+        responder: oneshot::Sender<()>,
+    },
+}
+
+/// Handle of the task owning the Shelly gen1 websockets.
+///
+/// Requests are queued without waiting, so that a Shelly slow to reconnect
+/// holds up the Shelly task only.
+#[derive(Clone)]
+pub struct ShellyHandle {
+    tx: mpsc::Sender<ShellyRequest>,
+    links: Arc<Mutex<Vec<ShellyLink>>>,
+}
+
+impl ShellyHandle {
+    /// Connected and offline Shelly.
+    pub fn links(&self) -> Vec<ShellyLink> {
+        self.links.lock().unwrap().clone()
+    }
+
+    pub fn online(&self) -> Vec<ShellyLink> {
+        self.links
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|link| link.online)
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_connected(&self, mac_address: &str) -> bool {
+        self.links
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|link| link.online && link.mac_address == mac_address)
+    }
+
+    pub fn connect(
+        &self,
+        discovery: ShellyDiscoveryResult,
+        user_login: String,
+        user_password: String,
+    ) {
+        self.request(ShellyRequest::Connect {
+            discovery,
+            user_login,
+            user_password,
+        });
+    }
+
+    /// Queues `message` for the Shelly with `mac_address`, returns false
+    /// when it is not connected.
+    pub fn send_action(&self, mac_address: &str, message: &serde_json::Value) -> bool {
+        self.is_connected(mac_address)
+            && self.request(ShellyRequest::Action {
+                mac_address: mac_address.to_owned(),
+                message: message.to_owned(),
+            })
+    }
+
+    pub fn change_mode(&self, mac_address: &str, message: &serde_json::Value) {
+        self.request(ShellyRequest::ChangeMode {
+            mac_address: mac_address.to_owned(),
+            message: message.to_owned(),
+        });
+    }
+
+    /// Closes every websocket and stops the task.
+    pub async fn close(&self, timeout: Duration) {
+        let (responder, rx) = oneshot::channel();
+
+        if self
+            .tx
+            .send(ShellyRequest::Close { timeout, responder })
+            .await
+            .is_ok()
+        {
+            let _ret = rx.await;
+        }
+    }
+
+    fn request(&self, request: ShellyRequest) -> bool {
+        match self.tx.try_send(request) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Shelly task not accepting requests: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// State shared by the successive runs of the Shelly task.
+#[derive(Clone)]
+pub struct ShellyTask {
+    policy: ReconnectPolicy,
+    requests: Arc<tokio::sync::Mutex<mpsc::Receiver<ShellyRequest>>>,
+    links: Arc<Mutex<Vec<ShellyLink>>>,
+    /// Known Shelly, connected again by a restarted task.
+    targets: Arc<Mutex<Vec<ShellyTarget>>>,
+    tx_messages: mpsc::Sender<serde_json::Value>,
+}
+
+/// Creates the Shelly task, its handle and the receiver of the messages
+/// of the Shelly.
+pub fn channel(
+    policy: ReconnectPolicy,
+) -> (ShellyHandle, ShellyTask, mpsc::Receiver<serde_json::Value>) {
+    let (tx, rx) = mpsc::channel(REQUEST_QUEUE_SIZE);
+    let (tx_messages, rx_messages) = mpsc::channel(MESSAGE_QUEUE_SIZE);
+    let links = Arc::new(Mutex::new(Vec::new()));
+
+    let handle = ShellyHandle {
+        tx,
+        links: links.clone(),
+    };
+
+    let task = ShellyTask {
+        policy,
+        requests: Arc::new(tokio::sync::Mutex::new(rx)),
+        links,
+        targets: Arc::new(Mutex::new(Vec::new())),
+        tx_messages,
+    };
+
+    (handle, task, rx_messages)
+}
+
+impl ShellyTask {
+    /// Connects the announced Shelly, forwards their messages and keeps
+    /// the connections alive. A restarted task connects again the Shelly
+    /// known before the failure.
+    pub async fn run(self) -> TaskResult {
+        let mut requests = self.requests.lock().await;
+
+        let mut shelly_manager = GlobalShellyManager::new(self.policy).await;
+
+        let targets = self.targets.lock().unwrap().clone();
+        for target in targets {
+            shelly_manager.insert_target(target);
+        }
+
+        let mut ping_timer = tokio::time::interval(PING_INTERVAL);
+
+        let mut reconnect_timer = tokio::time::interval(RECONNECT_INTERVAL);
+
+        self.publish(&shelly_manager);
+
+        loop {
+            tokio::select! {
+                request = requests.recv() => {
+                    let request = match request {
+                        Some(request) => request,
+                        None => return Ok(()),
+                    };
+
+                    match request {
+                        ShellyRequest::Connect { discovery, user_login, user_password } => {
+                            shelly_manager.insert_shelly(discovery, user_login, user_password);
+                        }
+                        ShellyRequest::Action { mac_address, message } => {
+                            let _ret = shelly_manager.send_action(&mac_address, &message).await;
+                        }
+                        ShellyRequest::ChangeMode { mac_address, message } => {
+                            let _ret = shelly_manager.send_action(&mac_address, &message).await;
+                            shelly_manager.remove(&mac_address);
+                        }
+                        ShellyRequest::Close { timeout, responder } => {
+                            shelly_manager.close_all(timeout).await;
+                            self.publish(&shelly_manager);
+                            let _ret = responder.send(());
+                            return Ok(());
+                        }
+                    }
+                }
+                message = shelly_manager.wait_for_shelly_message() => {
+                    if let Some(message) = message {
+                        if self.tx_messages.send(message).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = ping_timer.tick() => {
+                    shelly_manager.send_ping().await;
+                    shelly_manager.check_if_reconnect_needed();
+                }
+                _ = reconnect_timer.tick() => {
+                    shelly_manager.schedule_reconnects();
+                }
+            }
+
+            shelly_manager.send_pending_updates().await;
+
+            self.publish(&shelly_manager);
+        }
+    }
+
+    fn publish(&self, shelly_manager: &GlobalShellyManager) {
+        let online = shelly_manager.shelly_list.iter().map(|shelly| ShellyLink {
+            mac_address: shelly.mac_address.to_owned(),
+            topic_name: shelly.topic_name.to_owned(),
+            ip: shelly.ip.to_owned(),
+            url: shelly.url.to_owned(),
+            online: true,
+            last_pong: Some(shelly.last_pong_timestamp.into()),
+            reconnect_attempts: 0,
+        });
+
+        let offline = shelly_manager.offline.values().map(|shelly| ShellyLink {
+            mac_address: shelly.target.mac_address.to_owned(),
+            topic_name: shelly.target.topic_name.to_owned(),
+            ip: shelly.target.ip.to_owned(),
+            url: shelly.target.url.to_owned(),
+            online: false,
+            last_pong: shelly.last_pong.map(Into::into),
+            reconnect_attempts: shelly.attempts,
+        });
+
+        let links = online.chain(offline).collect();
+
+        *self.links.lock().unwrap() = links;
+        *self.targets.lock().unwrap() = shelly_manager.targets();
+    }
+}
@@ -1,3 +1,4 @@
+use crate::commands::{request_action_message, shelly_action};
 use base64::encode;
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
@@ -5,11 +6,103 @@ use std::error::Error;
 use std::time::SystemTime;
 use tokio::net::TcpStream;
 use tokio::time::Duration;
-use tokio_tungstenite::tungstenite::{http, Message};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{self, http, Message};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
+/// What the websocket of a Shelly gen1 delivered.
+#[derive(Debug)]
+pub enum ShellyEvent {
+    Message(serde_json::Value),
+    /// The Shelly closed the websocket or the stream ended.
+    Disconnected {
+        mac_address: String,
+        reason: String,
+    },
+    /// The websocket failed and cannot be read anymore.
+    ProtocolError {
+        mac_address: String,
+        error: String,
+    },
+    /// A text frame is not JSON, the websocket stays usable.
+    ParseError {
+        mac_address: String,
+        error: String,
+    },
+}
+
+impl ShellyEvent {
+    /// Event of a frame read from the Shelly with `mac_address`, `None`
+    /// for the frames that are not reported.
+    fn from_frame(
+        mac_address: &str,
+        frame: Option<Result<Message, tungstenite::Error>>,
+    ) -> Option<ShellyEvent> {
+        match frame {
+            Some(Ok(Message::Text(t))) => Some(match serde_json::from_str(&t) {
+                Ok(message) => ShellyEvent::Message(message),
+                Err(e) => ShellyEvent::ParseError {
+                    mac_address: mac_address.to_owned(),
+                    error: e.to_string(),
+                },
+            }),
+            Some(Ok(Message::Close(frame))) => Some(ShellyEvent::Disconnected {
+                mac_address: mac_address.to_owned(),
+                reason: frame
+                    .map(|f| format!("closed with {}", f.code))
+                    .unwrap_or_else(|| "closed".to_owned()),
+            }),
+            Some(Ok(_)) => None,
+            Some(Err(e)) => Some(ShellyEvent::ProtocolError {
+                mac_address: mac_address.to_owned(),
+                error: e.to_string(),
+            }),
+            None => Some(ShellyEvent::Disconnected {
+                mac_address: mac_address.to_owned(),
+                reason: "stream ended".to_owned(),
+            }),
+        }
+    }
+}
+
+/// Address and credentials of the websocket of a Shelly gen1.
+#[derive(Debug, Clone)]
+pub struct ShellyTarget {
+    pub ip: String,
+    pub topic_name: String,
+    pub mac_address: String,
+    pub url: String,
+    pub user_login: String,
+    pub user_password: String,
+}
+
+impl ShellyTarget {
+    pub fn new(
+        ip: &str,
+        topic_name: &str,
+        mac_address: &str,
+        mdns_name: &str,
+        user_login: &str,
+        user_password: &str,
+    ) -> Self {
+        let mac = mac_address.replace(':', "");
+        let url = "wss://".to_owned() + mdns_name + "/things/" + topic_name + "-" + &mac;
+
+        ShellyTarget {
+            ip: ip.to_owned(),
+            topic_name: topic_name.to_owned(),
+            mac_address: mac_address.to_owned(),
+            url,
+            user_login: user_login.to_owned(),
+            user_password: user_password.to_owned(),
+        }
+    }
+}
+
 pub struct ShellyManager {
     pub ip: String,
+    pub topic_name: String,
     pub mac_address: String,
     pub url: String,
     pub write_shelly: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
@@ -94,50 +187,39 @@ impl ShellyManager {
         }
     }
 
-    pub async fn new(
-        ip: &str,
-        topic_name: &str,
-        mac_address: &String,
-        mdns_name: &str,
-        user_login: &str,
-        user_password: &str,
-    ) -> Result<ShellyManager, Box<dyn Error>> {
-        let mac = mac_address.replace(':', "");
-        let mac = mac.as_str();
-        let url = "wss://".to_owned() + mdns_name + "/things/" + topic_name + "-" + mac;
-
-        let (write_shelly, read_shelly) =
-            ShellyManager::connect_to_shelly(ip, &url, user_login, user_password).await?;
+    /// Opens the websocket of `target`.
+    pub async fn new(target: &ShellyTarget) -> Result<ShellyManager, Box<dyn Error>> {
+        let (write_shelly, read_shelly) = ShellyManager::connect_to_shelly(
+            &target.ip,
+            &target.url,
+            &target.user_login,
+            &target.user_password,
+        )
+        .await?;
 
         Ok(ShellyManager {
-            ip: ip.to_owned(),
-            mac_address: mac_address.to_owned(),
-            url: url.to_owned(),
+            ip: target.ip.to_owned(),
+            topic_name: target.topic_name.to_owned(),
+            mac_address: target.mac_address.to_owned(),
+            url: target.url.to_owned(),
             write_shelly,
             read_shelly,
             last_pong_timestamp: SystemTime::now(),
             last_action_timestamp: SystemTime::UNIX_EPOCH,
-            user_login: user_login.to_owned(),
-            user_password: user_password.to_owned(),
+            user_login: target.user_login.to_owned(),
+            user_password: target.user_password.to_owned(),
         })
     }
 
-    pub async fn reconnect(&mut self) -> Result<(), Box<dyn Error>> {
-        let (write_shelly, read_shelly) = ShellyManager::connect_to_shelly(
-            &self.ip,
-            &self.url,
-            &self.user_login,
-            &self.user_password,
-        )
-        .await?;
-        self.last_pong_timestamp = SystemTime::now();
-        self.last_action_timestamp = SystemTime::UNIX_EPOCH;
-        self.write_shelly = write_shelly;
-        self.read_shelly = read_shelly;
-
-        self.send_get_update().await;
-
-        Ok(())
+    pub fn target(&self) -> ShellyTarget {
+        ShellyTarget {
+            ip: self.ip.to_owned(),
+            topic_name: self.topic_name.to_owned(),
+            mac_address: self.mac_address.to_owned(),
+            url: self.url.to_owned(),
+            user_login: self.user_login.to_owned(),
+            user_password: self.user_password.to_owned(),
+        }
     }
 
     pub async fn send_ping(&mut self) {
@@ -145,27 +227,23 @@ impl ShellyManager {
         //println!("ping message to shelly sent ");
     }
 
+    /// Sends a Close frame and closes the websocket.
+    pub async fn close(&mut self) {
+        let _ret = self
+            .write_shelly
+            .send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: "bridge shutting down".into(),
+            })))
+            .await;
+        let _ret = self.write_shelly.close().await;
+    }
+
     pub async fn send_get_update(&mut self) {
         //println!("Requesting status update");
         let action_payload = serde_json::json!({});
 
-        let action_payload_string = action_payload.to_string();
-
-        let shelly_action = serde_json::json!({
-            "shelly_action" : {
-                "input" : {
-                    "action": {
-                        "action_name": "get_status_update",
-                        "action_payload": action_payload_string
-                    }
-                }
-            }
-        });
-
-        let message = serde_json::json!({
-            "messageType": "requestAction",
-            "data": shelly_action
-        });
+        let message = request_action_message(&shelly_action("get_status_update", &action_payload));
 
         self.send_action(&message).await;
     }
@@ -187,29 +265,45 @@ impl ShellyManager {
         self.last_action_timestamp = SystemTime::now();
     }
 
-    pub async fn wait_for_shelly_message(&mut self) -> Result<serde_json::Value, Box<dyn Error>> {
+    /// Waits for the next event of the websocket, answering the pongs.
+    pub async fn wait_for_shelly_message(&mut self) -> ShellyEvent {
         loop {
             let data = self.read_shelly.next().await;
-            match data {
-                Some(Ok(Message::Text(t))) => {
-                    let message: serde_json::Value = serde_json::from_str(&t)?;
-                    return Ok(message);
-                }
-                Some(Ok(Message::Pong(_t))) => {
-                    //println!("Received Pong Message from shelly");
-                    self.last_pong_timestamp = SystemTime::now();
-                }
-                Some(Ok(Message::Close(_t))) => {
-                    let ret = self.mac_address.clone();
-                    return Err(ret.into());
-                }
-                Some(Ok(Message::Ping(_t))) => {}
-                Some(Err(_m)) => {
-                    let ret = self.mac_address.clone();
-                    return Err(ret.into());
-                }
-                _ => {}
+
+            if let Some(Ok(Message::Pong(_t))) = data {
+                //println!("Received Pong Message from shelly");
+                self.last_pong_timestamp = SystemTime::now();
+                continue;
+            }
+
+            if let Some(event) = ShellyEvent::from_frame(&self.mac_address, data) {
+                return event;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_frames_are_not_disconnections() {
+        let event = ShellyEvent::from_frame("AA", Some(Ok(Message::Text("{\"id\"".to_owned()))));
+        assert!(matches!(event, Some(ShellyEvent::ParseError { .. })));
+
+        let event = ShellyEvent::from_frame("AA", Some(Ok(Message::Text("{}".to_owned()))));
+        assert!(matches!(event, Some(ShellyEvent::Message(_))));
+
+        let event = ShellyEvent::from_frame("AA", Some(Ok(Message::Close(None))));
+        assert!(
+            matches!(event, Some(ShellyEvent::Disconnected { mac_address, .. }) if mac_address == "AA")
+        );
+
+        assert!(matches!(
+            ShellyEvent::from_frame("AA", None),
+            Some(ShellyEvent::Disconnected { .. })
+        ));
+        assert!(ShellyEvent::from_frame("AA", Some(Ok(Message::Ping(vec![])))).is_none());
+    }
+}
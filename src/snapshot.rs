@@ -0,0 +1,278 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::commandqueue::{CommandQueue, PendingCommand, QueuedAction};
+use crate::commands::{CommandContext, ExpectedStatus};
+use crate::utils::{BestActuatorData, ValveCommandManager};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedCommand {
+    pub action: QueuedAction,
+    pub expected_status: ExpectedStatus,
+    pub context: CommandContext,
+    pub attempts: usize,
+    pub queued_at: DateTime<Local>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedBestActuator {
+    pub valve_mac_address: String,
+    pub actuator_mac_address: String,
+    pub rssi: i64,
+    pub updated_at: DateTime<Local>,
+}
+
+/// In-memory state written to a local file, so that queued commands and
+/// the valve routing survive a restart.
+///
+/// The ESP32 sessions are not part of it, the devices open new ones when
+/// they reconnect.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub saved_at: DateTime<Local>,
+    pub commands: Vec<SavedCommand>,
+    pub best_actuators: Vec<SavedBestActuator>,
+}
+
+impl Snapshot {
+    pub fn capture(
+        command_queue: &CommandQueue,
+        valve_command_manager: &ValveCommandManager,
+    ) -> Self {
+        let commands = command_queue
+            .commands
+            .values()
+            .map(|pending| SavedCommand {
+                action: pending.action.to_owned(),
+                expected_status: pending.expected_status.to_owned(),
+                context: pending.context.to_owned(),
+                attempts: pending.attempts,
+                queued_at: pending.queued_at.into(),
+            })
+            .collect();
+
+        let best_actuators = valve_command_manager
+            .best_actuator
+            .iter()
+            .map(|(valve_mac_address, data)| SavedBestActuator {
+                valve_mac_address: valve_mac_address.to_owned(),
+                actuator_mac_address: data.actuator_mac_address.to_owned(),
+                rssi: data.rssi,
+                updated_at: data.timestamp.into(),
+            })
+            .collect();
+
+        Snapshot {
+            saved_at: Local::now(),
+            commands,
+            best_actuators,
+        }
+    }
+
+    /// Reads the snapshot at `path`, `None` when there is none yet.
+    pub fn load(path: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path, e).into()),
+        };
+
+        let snapshot = serde_json::from_str(&content).map_err(|e| format!("{}: {}", path, e))?;
+
+        Ok(Some(snapshot))
+    }
+
+    /// Writes the snapshot next to `path` and renames it, so that a crash
+    /// while saving leaves the previous one in place.
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let tmp_path = format!("{}.tmp", path);
+
+        std::fs::write(&tmp_path, serde_json::to_vec(self)?)
+            .map_err(|e| format!("{}: {}", tmp_path, e))?;
+        std::fs::rename(&tmp_path, path).map_err(|e| format!("{}: {}", path, e))?;
+
+        Ok(())
+    }
+
+    /// Puts back the entries younger than `max_age`, returns how many
+    /// commands and best actuators were restored. Entries dated in the
+    /// future, saved before the clock was set, are discarded as well.
+    pub fn restore(
+        self,
+        max_age: Duration,
+        command_queue: &mut CommandQueue,
+        valve_command_manager: &mut ValveCommandManager,
+    ) -> (usize, usize) {
+        let is_fresh = |at: DateTime<Local>| {
+            SystemTime::from(at)
+                .elapsed()
+                .map(|age| age <= max_age)
+                .unwrap_or(false)
+        };
+
+        let mut commands = 0;
+        for saved in self.commands {
+            if is_fresh(saved.queued_at) {
+                command_queue.restore(PendingCommand {
+                    action: saved.action,
+                    expected_status: saved.expected_status,
+                    context: saved.context,
+                    attempts: saved.attempts,
+                    next_attempt: Instant::now(),
+                    queued_at: saved.queued_at.into(),
+                });
+                commands += 1;
+            }
+        }
+
+        let mut best_actuators = 0;
+        for saved in self.best_actuators {
+            if is_fresh(saved.updated_at) {
+                valve_command_manager.best_actuator.insert(
+                    saved.valve_mac_address,
+                    BestActuatorData {
+                        actuator_mac_address: saved.actuator_mac_address,
+                        rssi: saved.rssi,
+                        timestamp: saved.updated_at.into(),
+                    },
+                );
+                best_actuators += 1;
+            }
+        }
+
+        (commands, best_actuators)
+    }
+}
+
+/// Snapshot file, written only when the state changed since the last
+/// write so that an idle bridge does not wear the flash.
+pub struct SnapshotFile {
+    pub path: String,
+    /// Commands and best actuators of the last snapshot written.
+    last_saved: Option<String>,
+}
+
+impl SnapshotFile {
+    pub fn new(path: &str) -> Self {
+        SnapshotFile {
+            path: path.to_owned(),
+            last_saved: None,
+        }
+    }
+
+    pub fn load(&self) -> Result<Option<Snapshot>, Box<dyn Error>> {
+        Snapshot::load(&self.path)
+    }
+
+    /// Writes `snapshot` unless it matches the last one written, returns
+    /// whether the file was written.
+    pub fn save(&mut self, snapshot: &Snapshot) -> Result<bool, Box<dyn Error>> {
+        let content = serde_json::to_string(&(&snapshot.commands, &snapshot.best_actuators))?;
+
+        if self.last_saved.as_ref() == Some(&content) {
+            return Ok(false);
+        }
+
+        snapshot.save(&self.path)?;
+        self.last_saved = Some(content);
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commandqueue::RetryPolicy;
+    use crate::commands::ValveAction;
+
+    fn queue() -> CommandQueue {
+        CommandQueue::new(RetryPolicy {
+            initial_delay: Duration::from_secs(5),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 5,
+        })
+    }
+
+    #[test]
+    fn stale_entries_are_discarded() {
+        let mut command_queue = queue();
+        let mut valve_command_manager = ValveCommandManager::new();
+
+        let action = ValveAction {
+            mac_address: "valve".to_owned(),
+            desired_state: true,
+            shelly_action: serde_json::json!({}),
+        };
+        command_queue.insert(
+            QueuedAction::Valve(action),
+            ExpectedStatus::new(0).with("status", serde_json::json!(true)),
+            &CommandContext::default(),
+            true,
+        );
+        valve_command_manager.update_best_actuator("valve", "esp-1", -60);
+        valve_command_manager.update_best_actuator("old-valve", "esp-2", -60);
+        valve_command_manager.update_best_actuator("future-valve", "esp-3", -60);
+
+        let mut snapshot = Snapshot::capture(&command_queue, &valve_command_manager);
+        for saved in snapshot.best_actuators.iter_mut() {
+            if saved.valve_mac_address == "old-valve" {
+                saved.updated_at = Local::now() - chrono::Duration::hours(1);
+            }
+            if saved.valve_mac_address == "future-valve" {
+                saved.updated_at = Local::now() + chrono::Duration::hours(1);
+            }
+        }
+
+        let content = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&content).unwrap();
+
+        let mut command_queue = queue();
+        let mut valve_command_manager = ValveCommandManager::new();
+        let restored = snapshot.restore(
+            Duration::from_secs(600),
+            &mut command_queue,
+            &mut valve_command_manager,
+        );
+
+        assert_eq!(restored, (1, 1));
+        assert_eq!(command_queue.commands[&("valve".to_owned(), 0)].attempts, 1);
+        assert_eq!(
+            valve_command_manager.get_best_actuator_for_valve("valve"),
+            Some("esp-1".to_owned())
+        );
+        assert!(valve_command_manager
+            .get_best_actuator_for_valve("old-valve")
+            .is_none());
+        assert!(valve_command_manager
+            .get_best_actuator_for_valve("future-valve")
+            .is_none());
+    }
+
+    #[test]
+    fn unchanged_state_is_not_written_again() {
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", std::process::id()));
+        let mut file = SnapshotFile::new(path.to_str().unwrap());
+        let mut command_queue = queue();
+        let valve_command_manager = ValveCommandManager::new();
+
+        let snapshot = Snapshot::capture(&command_queue, &valve_command_manager);
+        assert!(file.save(&snapshot).unwrap());
+        let snapshot = Snapshot::capture(&command_queue, &valve_command_manager);
+        assert!(!file.save(&snapshot).unwrap());
+
+        command_queue.insert(
+            QueuedAction::Valve(ValveAction::new("valve", true)),
+            ExpectedStatus::new(0).with("status", serde_json::json!(true)),
+            &CommandContext::default(),
+            false,
+        );
+        let snapshot = Snapshot::capture(&command_queue, &valve_command_manager);
+        assert!(file.save(&snapshot).unwrap());
+        assert_eq!(file.load().unwrap().unwrap().commands.len(), 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,664 @@
+use chrono::{DateTime, Local};
+use serde_json::Number;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+
+use crate::bleutils::{self, ContactStatus};
+use crate::commandactor::CommandEvent;
+use crate::dhtmanager::DHTManager;
+use crate::energy::{self, EnergyAccountant, EnergyHistory, HistoryRetention};
+use crate::messages::BleBeaconMessage;
+use crate::metrics::metrics;
+use crate::presence::{self, PresenceTracker};
+use crate::shellyactor::ShellyHandle;
+use crate::statusmapping::StatusMapping;
+use crate::supervisor::TaskResult;
+use crate::wssmanager::WssManager;
+use crate::BridgeHandles;
+
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// State needed to copy actuator updates into the logical topics.
+pub struct StatusProjection {
+    pub mapping: StatusMapping,
+    pub energy: EnergyAccountant,
+    pub histories: HashMap<String, EnergyHistory>,
+    pub history_retention: HistoryRetention,
+}
+
+/// State shared by the successive runs of the status task.
+#[derive(Clone)]
+pub struct StatusTask {
+    bridge: BridgeHandles,
+    projection: Arc<tokio::sync::Mutex<StatusProjection>>,
+    presence: Arc<tokio::sync::Mutex<PresenceTracker>>,
+    shelly_messages: Arc<tokio::sync::Mutex<mpsc::Receiver<serde_json::Value>>>,
+    tx_command_events: mpsc::Sender<CommandEvent>,
+}
+
+impl StatusTask {
+    pub fn new(
+        bridge: BridgeHandles,
+        projection: StatusProjection,
+        presence: PresenceTracker,
+        rx_shelly_messages: mpsc::Receiver<serde_json::Value>,
+        tx_command_events: mpsc::Sender<CommandEvent>,
+    ) -> Self {
+        StatusTask {
+            bridge,
+            projection: Arc::new(tokio::sync::Mutex::new(projection)),
+            presence: Arc::new(tokio::sync::Mutex::new(presence)),
+            shelly_messages: Arc::new(tokio::sync::Mutex::new(rx_shelly_messages)),
+            tx_command_events,
+        }
+    }
+
+    /// Copies the status updates of the Shelly, of the ESP32 and of the BLE
+    /// devices into their topics, reports them to the command task and
+    /// marks offline the silent devices. The updates of the ESP32 received
+    /// while the task restarts are lost.
+    pub async fn run(self) -> TaskResult {
+        let mut projection = self.projection.lock().await;
+        let mut presence = self.presence.lock().await;
+        let mut shelly_messages = self.shelly_messages.lock().await;
+        let mut shutdown = self.bridge.shutdown.clone();
+
+        let BridgeHandles {
+            dht_manager,
+            wss_mgr,
+            shelly_manager,
+            ..
+        } = &self.bridge;
+
+        let mut actuator_updates = wss_mgr.subscribe_actuator_updates();
+
+        let mut ble_updates = wss_mgr.subscribe_ble_updates();
+
+        let mut presence_timer = tokio::time::interval(PRESENCE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return Ok(()),
+                esp32_actuator_update = actuator_updates.recv() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["esp32_update"]).start_timer();
+                    //println!("Received esp32 actuator update");
+                    match esp32_actuator_update {
+                        Ok(msg) => {
+                            handle_shelly_message(msg, dht_manager, &mut projection, &mut presence, &self.tx_command_events).await;
+                        }
+                        Err(RecvError::Lagged(missed)) => log::warn!("Missed {} ESP32 status updates", missed),
+                        Err(RecvError::Closed) => return Ok(()),
+                    }
+                }
+                // listener for ble beacons adv
+                ble_update = ble_updates.recv() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["ble_update"]).start_timer();
+
+                    ////println!("Received ble beacon update");
+
+                    match ble_update {
+                        Ok(msg) => {
+                            handle_ble_update_message(msg, dht_manager, &mut projection, &mut presence, &self.tx_command_events).await;
+                        }
+                        Err(RecvError::Lagged(missed)) => log::warn!("Missed {} BLE beacons", missed),
+                        Err(RecvError::Closed) => return Ok(()),
+                    }
+                }
+                Some(message) = shelly_messages.recv() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["shelly_message"]).start_timer();
+                    //println!("Received shelly message");
+
+                    handle_shelly_message(message, dht_manager, &mut projection, &mut presence, &self.tx_command_events).await;
+                }
+                _ = presence_timer.tick() => {
+                    let _timer = metrics().event_loop_latency.with_label_values(&["presence"]).start_timer();
+
+                    update_presence(&mut presence, shelly_manager, wss_mgr, dht_manager).await;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_shelly_message(
+    shelly_message: serde_json::Value,
+    dht_manager: &DHTManager,
+    status_projection: &mut StatusProjection,
+    presence: &mut PresenceTracker,
+    tx_command_events: &mpsc::Sender<CommandEvent>,
+) {
+    if let Some(message_type) = shelly_message.get("messageType") {
+        if message_type.as_str().unwrap() == "propertyStatus" {
+            if let Some(data) = shelly_message.get("data") {
+                if let Some(status) = data.get("status") {
+                    let status_string = status.as_str().unwrap();
+
+                    if let Ok(status_result) =
+                        serde_json::from_str::<serde_json::Value>(status_string)
+                    {
+                        let mac_address =
+                            status_result.get("mac_address").unwrap().as_str().unwrap();
+
+                        let mac_address_with_points = mac_address[0..2].to_owned()
+                            + ":"
+                            + &mac_address[2..4]
+                            + ":"
+                            + &mac_address[4..6]
+                            + ":"
+                            + &mac_address[6..8]
+                            + ":"
+                            + &mac_address[8..10]
+                            + ":"
+                            + &mac_address[10..12];
+
+                        let _ret = tx_command_events
+                            .send(CommandEvent::Status {
+                                mac_address: mac_address_with_points.to_owned(),
+                                status: status_result.clone(),
+                            })
+                            .await;
+
+                        let topic_name = status_result.get("topic_name").unwrap().as_str().unwrap();
+
+                        if let Ok(topic) = dht_manager
+                            .get_topic(topic_name, &mac_address_with_points)
+                            .await
+                        {
+                            let mut new_status = status_result.clone();
+
+                            if let Some(value) = topic.get("value") {
+                                if let Some(user_login) = value.get("user_login") {
+                                    let user_login = user_login.as_str().unwrap();
+
+                                    if value.get("user_password").is_some()
+                                        || value.get("user_password_hash").is_some()
+                                    {
+                                        if let Some(mac_address) = value.get("mac_address") {
+                                            let mac_address = mac_address.as_str().unwrap();
+                                            if let Some(id) = value.get("id") {
+                                                new_status["user_login"] =
+                                                    serde_json::Value::String(
+                                                        user_login.to_owned(),
+                                                    );
+                                                for field in ["user_password", "user_password_hash"]
+                                                {
+                                                    if let Some(secret) = value.get(field) {
+                                                        new_status[field] = secret.to_owned();
+                                                    }
+                                                }
+
+                                                new_status["mac_address"] =
+                                                    serde_json::Value::String(
+                                                        mac_address.to_string(),
+                                                    );
+
+                                                new_status["id"] = id.to_owned();
+
+                                                let now = Local::now();
+                                                presence.seen(mac_address, now);
+                                                presence::stamp(&mut new_status, true, now);
+
+                                                new_status["last_update_timestamp"] =
+                                                    serde_json::Value::Number(Number::from(
+                                                        sifis_dht::utils::get_epoch_ms() as u64,
+                                                    ));
+
+                                                let topic_uuid =
+                                                    topic["topic_uuid"].as_str().unwrap();
+                                                dht_manager
+                                                    .write_topic(
+                                                        topic_name,
+                                                        topic_uuid,
+                                                        &new_status,
+                                                    )
+                                                    .await;
+
+                                                let _ret = update_actuator_connection(
+                                                    dht_manager,
+                                                    status_projection,
+                                                    topic_name,
+                                                    topic_uuid,
+                                                    &new_status,
+                                                )
+                                                .await;
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn get_topic_from_actuator_topic(
+    dht_manager: &DHTManager,
+    status_projection: &mut StatusProjection,
+    source_topic_name: &str,
+    source_topic_uuid: &str,
+    channel_number: u64,
+    actuator_topic: &serde_json::Value,
+    target_topic_name: &str,
+) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+    let mut source_topic = dht_manager
+        .get_topic_uuid(source_topic_name, source_topic_uuid)
+        .await?;
+
+    let counters = status_projection
+        .mapping
+        .project(
+            source_topic_name,
+            target_topic_name,
+            channel_number,
+            actuator_topic,
+            &mut source_topic["value"],
+        )
+        .map_err(|e| e.to_string())?;
+
+    for counter in counters {
+        let mac_address = match actuator_topic["mac_address"].as_str() {
+            Some(mac_address) => mac_address,
+            None => return Err("mac_address missing".into()),
+        };
+
+        let key = energy::counter_key(
+            mac_address,
+            channel_number,
+            source_topic_uuid,
+            &counter.field,
+        );
+
+        if status_projection.energy.baseline(&key).is_none() {
+            if let Some(stored) = dht_manager.get_energy_baseline(&key).await {
+                status_projection.energy.restore(&key, stored);
+            }
+        }
+
+        let previous = status_projection.energy.baseline(&key);
+        let delta = status_projection.energy.update(&key, counter.value);
+
+        let old_value = source_topic["value"][&counter.field]
+            .as_f64()
+            .unwrap_or(0.0);
+        source_topic["value"][&counter.field] = serde_json::Value::from(old_value + delta);
+
+        if previous != Some(counter.value) {
+            dht_manager.write_energy_baseline(&key, counter.value).await;
+        }
+
+        // an idle device reports the same counter at every status update
+        if counter.history && delta > 0.0 {
+            let history = match status_projection
+                .histories
+                .entry(source_topic_uuid.to_owned())
+            {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    dht_manager
+                        .get_energy_history(source_topic_uuid)
+                        .await
+                        .unwrap_or_default(),
+                ),
+            };
+
+            history.record(Local::now(), delta, &status_projection.history_retention);
+
+            dht_manager
+                .write_energy_history(source_topic_uuid, history)
+                .await;
+        }
+    }
+
+    Ok(source_topic["value"].clone())
+}
+
+async fn update_actuator_connection(
+    dht_manager: &DHTManager,
+    status_projection: &mut StatusProjection,
+    topic_name: &str,
+    topic_uuid: &str,
+    actuator_topic: &serde_json::Value,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let topics = dht_manager
+        .get_topic_name("domo_actuator_connection")
+        .await?;
+
+    let topics = topics.as_array().unwrap();
+    for topic in topics.iter() {
+        if let Some(value) = topic.get("value") {
+            if let Some(target_topic_name) = value.get("target_topic_name") {
+                if let Some(target_topic_uuid) = value.get("target_topic_uuid") {
+                    if let Some(target_channel_number) = value.get("target_channel_number") {
+                        if let Some(source_topic_name) = value.get("source_topic_name") {
+                            let target_topic_name = target_topic_name.as_str().unwrap();
+                            let target_topic_uuid = target_topic_uuid.as_str().unwrap();
+                            let target_channel_number = target_channel_number.as_u64().unwrap();
+                            let source_topic_name = source_topic_name.as_str().unwrap();
+                            let source_topic_uuid = topic["topic_uuid"].as_str().unwrap();
+
+                            if topic_uuid == target_topic_uuid && topic_name == target_topic_name {
+                                //println!(
+                                //    "target_topic_name {} target_topic_uuid {}",
+                                //    target_topic_name, target_topic_uuid
+                                //);
+                                //println!(
+                                //    "source_topic_name {} source_topic_uuid {}",
+                                //    source_topic_name, source_topic_uuid
+                                //);
+                                //println!("target_channel_number {}", target_channel_number);
+
+                                if let Ok(status) = get_topic_from_actuator_topic(
+                                    dht_manager,
+                                    status_projection,
+                                    source_topic_name,
+                                    source_topic_uuid,
+                                    target_channel_number,
+                                    actuator_topic,
+                                    target_topic_name,
+                                )
+                                .await
+                                {
+                                    dht_manager
+                                        .write_topic(source_topic_name, source_topic_uuid, &status)
+                                        .await;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn mark_seen(
+    presence: &mut PresenceTracker,
+    dht_manager: &DHTManager,
+    mac_address: &str,
+    at: DateTime<Local>,
+) {
+    if presence.seen(mac_address, at) {
+        dht_manager.write_presence(mac_address, true, at).await;
+    }
+}
+
+/// Refreshes the presence of the Shelly gen1 from their pongs and of the
+/// ESP32 from their sessions, then marks the silent devices offline.
+async fn update_presence(
+    presence: &mut PresenceTracker,
+    shelly_manager: &ShellyHandle,
+    wss_mgr: &WssManager,
+    dht_manager: &DHTManager,
+) {
+    for shelly in shelly_manager.online() {
+        if let Some(last_pong) = shelly.last_pong {
+            mark_seen(presence, dht_manager, &shelly.mac_address, last_pong).await;
+        }
+    }
+
+    let sessions = wss_mgr.sessions.lock().unwrap().sessions();
+
+    for session in sessions {
+        if session.is_connected() {
+            let last_seen = session.last_pong.unwrap_or(session.connected_at);
+            mark_seen(presence, dht_manager, &session.mac_address, last_seen).await;
+        } else if let Some(last_seen) = presence.disconnected(&session.mac_address) {
+            log::info!("{} offline, its session closed", session.mac_address);
+            dht_manager
+                .write_presence(&session.mac_address, false, last_seen)
+                .await;
+        }
+    }
+
+    for (mac_address, last_seen) in presence.expired(Local::now()) {
+        log::info!("{} offline, not seen since {}", mac_address, last_seen);
+        dht_manager
+            .write_presence(&mac_address, false, last_seen)
+            .await;
+    }
+}
+
+async fn handle_ble_update_message(
+    message: BleBeaconMessage,
+    dht_manager: &DHTManager,
+    status_projection: &mut StatusProjection,
+    presence: &mut PresenceTracker,
+    tx_command_events: &mpsc::Sender<CommandEvent>,
+) {
+    let ret = dht_manager
+        .get_actuator_from_mac_address(&message.mac_address)
+        .await;
+
+    if let Ok(topic) = ret {
+        let topic_name = topic["topic_name"].as_str().unwrap();
+
+        metrics().ble_beacons.with_label_values(&[topic_name]).inc();
+
+        mark_seen(presence, dht_manager, &message.mac_address, Local::now()).await;
+
+        if topic_name == "domo_ble_thermometer" {
+            //println!("THERMO UPDATE {}", message.payload);
+
+            if let Ok(bytes) = base64::decode(&message.payload) {
+                use hex::ToHex;
+                let beacon_adv_string = bytes.encode_hex::<String>();
+                //println!("BEACON THERMO ADV from {}: {}", message.mac_address, beacon_adv_string);
+
+                handle_ble_thermometer_update(
+                    dht_manager,
+                    &message.mac_address,
+                    &beacon_adv_string,
+                    &topic,
+                )
+                .await;
+            }
+        }
+
+        if topic_name == "domo_ble_contact" {
+            //println!("CONTACT UPDATE {}", message.payload);
+
+            if let Ok(bytes) = base64::decode(&message.payload) {
+                use hex::ToHex;
+                let beacon_adv_string = bytes.encode_hex::<String>();
+                //println!("BEACON CONTACT ADV from {}: {}", message.mac_address, beacon_adv_string);
+
+                handle_ble_contact_update(
+                    dht_manager,
+                    status_projection,
+                    &message.mac_address,
+                    &beacon_adv_string,
+                    &message.rssi,
+                    &topic,
+                )
+                .await;
+            }
+        }
+
+        if topic_name == "domo_ble_valve" {
+            if message.payload == "0" || message.payload == "1" {
+                let status = serde_json::json!({ "status": message.payload == "1" });
+                let _ret = tx_command_events
+                    .send(CommandEvent::Status {
+                        mac_address: message.mac_address.to_owned(),
+                        status,
+                    })
+                    .await;
+
+                handle_ble_valve_update(
+                    dht_manager,
+                    &message.mac_address,
+                    &message.payload,
+                    &topic,
+                )
+                .await;
+            } else {
+                let _ret = tx_command_events
+                    .send(CommandEvent::ValveBeacon {
+                        valve_mac_address: message.mac_address.to_owned(),
+                        actuator_mac_address: message.actuator.to_owned(),
+                        rssi: message.rssi,
+                    })
+                    .await;
+            }
+        }
+    }
+}
+
+async fn handle_ble_thermometer_update(
+    dht_manager: &DHTManager,
+    _mac_address: &str,
+    message: &str,
+    topic: &serde_json::Value,
+) {
+    let topic_uuid = topic["topic_uuid"].as_str().unwrap();
+    let value_of_topic = &topic["value"];
+    let token = value_of_topic["token"].as_str().unwrap();
+    let mac_address = value_of_topic["mac_address"].as_str().unwrap();
+    let name = value_of_topic["name"].as_str().unwrap();
+    let area_name = value_of_topic["area_name"].as_str().unwrap();
+
+    // the parse error is not kept across the write
+    let ret = bleutils::parse_atc(mac_address, message, token).ok();
+
+    if let Some(m) = ret {
+        //println!("DECRITTATO {} {} {}", m.temperature, m.humidity, m.battery);
+        let mut value = serde_json::json!({
+            "temperature": m.temperature,
+            "humidity": m.humidity,
+            "battery":  m.battery,
+            "token": token,
+            "mac_address": mac_address,
+            "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
+            "name": name,
+            "area_name": area_name
+        });
+        presence::stamp(&mut value, true, Local::now());
+
+        dht_manager
+            .write_topic("domo_ble_thermometer", topic_uuid, &value)
+            .await;
+    }
+}
+
+async fn handle_ble_contact_update(
+    dht_manager: &DHTManager,
+    status_projection: &mut StatusProjection,
+    _mac_address: &str,
+    message: &str,
+    rssi: &i64,
+    topic: &serde_json::Value,
+) {
+    if message.len() >= 58 {
+        println!("MESSAGE: {}", message);
+        let topic_uuid = topic["topic_uuid"].as_str().unwrap();
+        let value_of_topic = &topic["value"];
+        let token = value_of_topic["token"].as_str().unwrap();
+        let id = value_of_topic["id"].as_str().unwrap();
+        let mac_address = value_of_topic["mac_address"].as_str().unwrap();
+        let area_name = value_of_topic["area_name"].as_str().unwrap();
+
+        let len_hex_value = "1d";
+        let rssi_i = *rssi as i8;
+        let rssi_hex = format!("{:02x}", rssi_i);
+
+        let rssi_hex = rssi_hex.as_str();
+
+        let data = len_hex_value.to_owned() + message + rssi_hex;
+
+        //println!("mac {} token {} payload {}", mac_address, token, message);
+
+        let ret = bleutils::parse_contact_sensor(mac_address, &data, token).ok();
+        if let Some(m) = ret {
+            let val = u64::from(m.state != ContactStatus::Open);
+            //println!("Value_of_topic {}", value_of_topic);
+            if let Some(val_in_topic) = value_of_topic.get("status") {
+                //println!("{}", val_in_topic);
+                let val_in_topic = val_in_topic.as_u64().unwrap();
+                //println!("val {}, value_of_topic {}", val, val_in_topic);
+                if val != val_in_topic {
+                    let mut value = serde_json::json!({
+                    "status": val,
+                    "token": token,
+                    "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
+                    "mac_address": mac_address,
+                        "id": id,
+                        "area_name": area_name
+                     });
+                    presence::stamp(&mut value, true, Local::now());
+
+                    dht_manager
+                        .write_topic("domo_ble_contact", topic_uuid, &value)
+                        .await;
+                    let _ret = update_actuator_connection(
+                        dht_manager,
+                        status_projection,
+                        "domo_ble_contact",
+                        topic_uuid,
+                        &value,
+                    )
+                    .await;
+                }
+            } else {
+                let mut value = serde_json::json!({
+                "status": val,
+                "token": token,
+                "mac_address": mac_address,
+                "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
+                "id": id,
+                "area_name": area_name
+                });
+                presence::stamp(&mut value, true, Local::now());
+
+                dht_manager
+                    .write_topic("domo_ble_contact", topic_uuid, &value)
+                    .await;
+                let _ret = update_actuator_connection(
+                    dht_manager,
+                    status_projection,
+                    "domo_ble_contact",
+                    topic_uuid,
+                    &value,
+                )
+                .await;
+            }
+        }
+    }
+}
+
+async fn handle_ble_valve_update(
+    dht_manager: &DHTManager,
+    _mac_address: &str,
+    message: &String,
+    topic: &serde_json::Value,
+) {
+    let topic_uuid = topic["topic_uuid"].as_str().unwrap();
+    let value_of_topic = &topic["value"];
+    let mac_address = value_of_topic["mac_address"].as_str().unwrap();
+    let name = value_of_topic["name"].as_str().unwrap();
+    let area_name = value_of_topic["area_name"].as_str().unwrap();
+
+    let value: bool = message == "1";
+
+    let mut value = serde_json::json!(
+    {   "status": value,
+        "mac_address": mac_address,
+        "last_update_timestamp": serde_json::Value::Number(Number::from(sifis_dht::utils::get_epoch_ms() as u64)),
+        "name": name,
+        "area_name": area_name
+    });
+    presence::stamp(&mut value, true, Local::now());
+
+    dht_manager
+        .write_topic("domo_ble_valve", topic_uuid, &value)
+        .await;
+}
@@ -0,0 +1,284 @@
+use serde::Deserialize;
+use std::error::Error;
+
+const DEFAULT_MAPPING: &str = include_str!("../status_mapping.toml");
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FieldMapping {
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub accumulate: bool,
+    #[serde(default)]
+    pub report_update: bool,
+    pub updated_by: Option<String>,
+    /// Also record the increments in the energy history of the device.
+    #[serde(default)]
+    pub history: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Projection {
+    pub source_topic: String,
+    #[serde(default)]
+    pub actuators: Vec<String>,
+    pub require_updated: Option<String>,
+    /// Writes `updated_properties` even without a `report_update` field,
+    /// so that the list left by another actuator is cleared.
+    #[serde(default)]
+    pub updated_properties: bool,
+    pub fields: Vec<FieldMapping>,
+}
+
+/// Raw value of a cumulative counter, the caller turns it into an
+/// increment of `field`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counter {
+    pub field: String,
+    pub value: f64,
+    pub history: bool,
+}
+
+impl Projection {
+    fn applies_to(&self, source_topic_name: &str, target_topic_name: &str) -> bool {
+        self.source_topic == source_topic_name
+            && (self.actuators.is_empty() || self.actuators.iter().any(|a| a == target_topic_name))
+    }
+}
+
+/// Declarative table describing how the status of an actuator is copied
+/// into the logical topics connected to it, see `status_mapping.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatusMapping {
+    #[serde(rename = "projection", default)]
+    pub projections: Vec<Projection>,
+}
+
+fn channel_letter(channel: u64) -> &'static str {
+    match channel {
+        1 => "r",
+        2 => "g",
+        3 => "b",
+        4 => "w",
+        _ => "",
+    }
+}
+
+fn expand(template: &str, channel: u64) -> String {
+    template
+        .replace("{channel}", &channel.to_string())
+        .replace("{channel_letter}", channel_letter(channel))
+}
+
+fn lookup<'a>(status: &'a serde_json::Value, path: &str) -> &'a serde_json::Value {
+    path.split('.').fold(status, |value, key| &value[key])
+}
+
+fn is_updated(status: &serde_json::Value, property: &str) -> bool {
+    status["updated_properties"]
+        .as_array()
+        .map(|props| props.iter().any(|p| p == property))
+        .unwrap_or(false)
+}
+
+impl StatusMapping {
+    /// Loads the mapping from `path`, or the built-in one when no path is
+    /// configured.
+    pub fn load(path: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)?;
+                Self::parse(&content).map_err(|e| format!("{}: {}", path, e).into())
+            }
+            None => Self::parse(DEFAULT_MAPPING),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Self, Box<dyn Error>> {
+        let mapping: StatusMapping = toml::from_str(content)?;
+
+        for projection in &mapping.projections {
+            if projection.fields.is_empty() {
+                return Err(
+                    format!("projection of {} has no fields", projection.source_topic).into(),
+                );
+            }
+        }
+
+        Ok(mapping)
+    }
+
+    /// Copies the fields of the actuator `status` into `source`, the value
+    /// of the logical topic connected to `channel`, and returns the
+    /// accumulated ones. Logical topics without a projection are left
+    /// untouched.
+    pub fn project(
+        &self,
+        source_topic_name: &str,
+        target_topic_name: &str,
+        channel: u64,
+        status: &serde_json::Value,
+        source: &mut serde_json::Value,
+    ) -> Result<Vec<Counter>, Box<dyn Error>> {
+        let projection = match self
+            .projections
+            .iter()
+            .find(|p| p.applies_to(source_topic_name, target_topic_name))
+        {
+            Some(projection) => projection,
+            None => return Ok(Vec::new()),
+        };
+
+        if let Some(property) = &projection.require_updated {
+            if !is_updated(status, &expand(property, channel)) {
+                return Err("not update".into());
+            }
+        }
+
+        let mut updated_props = Vec::new();
+        let mut counters = Vec::new();
+
+        for field in &projection.fields {
+            let from = expand(&field.from, channel);
+            let value = lookup(status, &from);
+
+            if field.accumulate {
+                match value.as_f64() {
+                    Some(value) => counters.push(Counter {
+                        field: field.to.to_owned(),
+                        value,
+                        history: field.history,
+                    }),
+                    None => return Err(format!("{} missing", from).into()),
+                }
+            } else {
+                source[&field.to] = value.clone();
+            }
+
+            if field.report_update {
+                let updated_by = match &field.updated_by {
+                    Some(updated_by) => expand(updated_by, channel),
+                    None => from,
+                };
+
+                if is_updated(status, &updated_by) {
+                    updated_props.push(serde_json::Value::String(field.to.to_owned()));
+                }
+            }
+        }
+
+        if projection.updated_properties || projection.fields.iter().any(|f| f.report_update) {
+            source["updated_properties"] = serde_json::Value::Array(updated_props);
+        }
+
+        Ok(counters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_mapping_returns_energy_counter() {
+        let mapping = StatusMapping::load(None).unwrap();
+
+        let status = serde_json::json!({
+            "output2": true,
+            "power2": 12.5,
+            "energy2": 3.0,
+            "updated_properties": ["energy2"]
+        });
+        let mut source = serde_json::json!({ "energy": 10.0 });
+
+        let counters = mapping
+            .project("domo_light", "shelly_25", 2, &status, &mut source)
+            .unwrap();
+
+        assert_eq!(source["status"], true);
+        assert_eq!(source["power"], 12.5);
+        assert_eq!(source["energy"], 10.0);
+        assert_eq!(
+            counters,
+            [Counter {
+                field: "energy".to_owned(),
+                value: 3.0,
+                history: true
+            }]
+        );
+        assert_eq!(source["updated_properties"], serde_json::json!(["energy"]));
+    }
+
+    #[test]
+    fn actuator_specific_projection_wins() {
+        let mapping = StatusMapping::load(None).unwrap();
+
+        let status = serde_json::json!({ "rgbw_status": { "r": 1, "g": 2, "b": 3, "w": 4 } });
+        let mut source = serde_json::json!({});
+
+        mapping
+            .project(
+                "domo_light_dimmable",
+                "shelly_rgbw",
+                3,
+                &status,
+                &mut source,
+            )
+            .unwrap();
+        assert_eq!(source["status"], 3);
+
+        let status = serde_json::json!({ "input1": true, "status": false });
+        let mut source = serde_json::json!({});
+
+        mapping
+            .project(
+                "domo_door_sensor",
+                "domo_ble_contact",
+                1,
+                &status,
+                &mut source,
+            )
+            .unwrap();
+        assert_eq!(source["status"], false);
+    }
+
+    #[test]
+    fn relay_projection_clears_updated_properties() {
+        let mapping = StatusMapping::load(None).unwrap();
+
+        let status = serde_json::json!({ "output0": true, "updated_properties": ["output0"] });
+        // left by the projection of an actuator metering the power
+        let mut source = serde_json::json!({ "updated_properties": ["power"] });
+
+        mapping
+            .project("domo_light", "shelly_1", 0, &status, &mut source)
+            .unwrap();
+
+        assert_eq!(source["status"], true);
+        assert_eq!(source["updated_properties"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn require_updated_skips_stale_inputs() {
+        let mapping = StatusMapping::parse(
+            r#"
+            [[projection]]
+            source_topic = "domo_button"
+            require_updated = "input{channel}"
+            fields = [{ from = "input{channel}", to = "status" }]
+            "#,
+        )
+        .unwrap();
+
+        let status = serde_json::json!({ "input1": true, "updated_properties": ["input2"] });
+        let mut source = serde_json::json!({});
+
+        assert!(mapping
+            .project("domo_button", "shelly_1", 1, &status, &mut source)
+            .is_err());
+        assert!(mapping
+            .project("domo_button", "shelly_1", 2, &status, &mut source)
+            .is_ok());
+        assert!(StatusMapping::parse("[[projection]]\nsource_topic = \"x\"\nfields = []").is_err());
+    }
+}
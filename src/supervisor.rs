@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+use crate::metrics::metrics;
+
+pub type TaskResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    pub initial_delay: Duration,
+    /// Upper bound of the delay, doubled at every consecutive failure. A
+    /// task that ran longer than this is restarted after `initial_delay`.
+    pub max_delay: Duration,
+}
+
+/// Runs the subsystems of the bridge in their own tasks and restarts the
+/// ones that fail or panic.
+#[derive(Debug, Clone, Copy)]
+pub struct Supervisor {
+    policy: RestartPolicy,
+}
+
+impl Supervisor {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Supervisor { policy }
+    }
+
+    /// Spawns the task built by `factory`, building it again after a
+    /// failure. Supervision ends once the task returns `Ok`.
+    pub fn spawn<F, Fut>(&self, name: &'static str, factory: F) -> JoinHandle<()>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = TaskResult> + Send + 'static,
+    {
+        let policy = self.policy;
+
+        tokio::spawn(async move {
+            let mut delay = policy.initial_delay;
+
+            loop {
+                let started = Instant::now();
+
+                let error = match tokio::spawn(factory()).await {
+                    Ok(Ok(())) => {
+                        log::info!("Task {} stopped", name);
+                        return;
+                    }
+                    Ok(Err(e)) => e.to_string(),
+                    Err(e) => e.to_string(),
+                };
+
+                if started.elapsed() > policy.max_delay {
+                    delay = policy.initial_delay;
+                }
+
+                metrics().task_restarts.with_label_values(&[name]).inc();
+                log::error!("Task {} failed: {}, restarting in {:?}", name, error, delay);
+
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2).min(policy.max_delay);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn failed_tasks_are_restarted() {
+        let supervisor = Supervisor::new(RestartPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        });
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        // the metrics are global, the label is used by no other test
+        let restarts = metrics()
+            .task_restarts
+            .with_label_values(&["failed_tasks_are_restarted"]);
+        let restarts_before = restarts.get();
+
+        let task_runs = runs.clone();
+        let handle = supervisor.spawn("failed_tasks_are_restarted", move || {
+            let runs = task_runs.clone();
+            async move {
+                match runs.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err("failed".into()),
+                    1 => panic!("panicked"),
+                    _ => Ok(()),
+                }
+            }
+        });
+
+        handle.await.unwrap();
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(restarts.get() - restarts_before, 2);
+    }
+}
@@ -0,0 +1,327 @@
+use axum::{middleware::AddExtension, Extension};
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures_util::future::BoxFuture;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("cannot open {}: {}", path.display(), e))
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|e| format!("cannot parse {}: {}", path.display(), e))?;
+
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", path.display()));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    let mut reader = open(path)?;
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| format!("cannot parse {}: {}", path.display(), e))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(format!("no private key found in {}", path.display())),
+        }
+    }
+}
+
+/// Files and client authentication policy of the ESP32 WebSocket server.
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub client_ca_path: Option<PathBuf>,
+    /// The ESP32 log in with a certificate signed by the client CA, which
+    /// must then be configured.
+    pub require_client_cert: bool,
+}
+
+/// Builds the rustls configuration of the ESP32 WebSocket server. When a
+/// client CA is given, client certificates are verified against it. Clients
+/// without one still complete the handshake, even with
+/// `require_client_cert`, so that the REST API and `/metrics` stay reachable
+/// from hosts without a device certificate: the WebSocket refuses them when
+/// the ESP32 log in with their certificate.
+pub fn server_config(settings: &TlsSettings) -> Result<ServerConfig, Box<dyn Error>> {
+    let certs = load_certs(&settings.cert_path)?;
+    let key = load_key(&settings.key_path)?;
+
+    let verifier = match settings.client_ca_path.as_deref() {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| format!("invalid CA in {}: {}", path.display(), e))?;
+            }
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+        }
+        None if settings.require_client_cert => {
+            return Err("client certificates are required but no client CA is configured".into())
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(|e| {
+            format!(
+                "invalid certificate {} or key {}: {}",
+                settings.cert_path.display(),
+                settings.key_path.display(),
+                e
+            )
+        })?;
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Leaf certificate presented by the client during the handshake, added to
+/// the extensions of every request of the connection.
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertificate(pub Option<Certificate>);
+
+/// Identity of an ESP32 taken from its client certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateIdentity {
+    /// Colon separated, upper case.
+    pub mac_address: String,
+    /// Hex encoded serial number, lower case.
+    pub serial: String,
+}
+
+/// Finds a mac address in `name`, either alone, colon separated, or as the
+/// last `-` separated part as in `shelly_1plus-aabbccddeeff`.
+fn parse_mac_address(name: &str) -> Option<String> {
+    let candidate = name.rsplit('-').next()?.replace(':', "");
+
+    if candidate.len() != 12 || !candidate.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let candidate = candidate.to_uppercase();
+    let parts: Vec<&str> = (0..6).map(|i| &candidate[i * 2..i * 2 + 2]).collect();
+
+    Some(parts.join(":"))
+}
+
+/// Derives the device identity from the DNS names of the subject
+/// alternative name extension, falling back to the subject common name.
+pub fn certificate_identity(cert: &Certificate) -> Result<CertificateIdentity, String> {
+    let (_, cert) = X509Certificate::from_der(&cert.0)
+        .map_err(|e| format!("invalid client certificate: {}", e))?;
+
+    let mut names = Vec::new();
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    for cn in cert.subject().iter_common_name() {
+        if let Ok(cn) = cn.as_str() {
+            names.push(cn.to_owned());
+        }
+    }
+
+    let mac_address = names
+        .iter()
+        .find_map(|name| parse_mac_address(name))
+        .ok_or_else(|| format!("no mac address in client certificate {}", cert.subject()))?;
+
+    Ok(CertificateIdentity {
+        mac_address,
+        serial: hex::encode(cert.raw_serial()),
+    })
+}
+
+/// Rustls acceptor that exposes the client certificate to the handlers.
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        ClientCertAcceptor {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientCertificate>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+
+            let certificate = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first().cloned());
+
+            Ok((
+                stream,
+                Extension(ClientCertificate(certificate)).layer(service),
+            ))
+        })
+    }
+}
+
+/// SIGHUP notifications, never received where the handler cannot be
+/// installed or there are no Unix signals.
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<Signal>,
+}
+
+impl Hangup {
+    #[cfg(unix)]
+    fn new() -> Self {
+        let signal = match signal(SignalKind::hangup()) {
+            Ok(sighup) => Some(sighup),
+            Err(e) => {
+                log::warn!("cannot install the SIGHUP handler: {}", e);
+                None
+            }
+        };
+
+        Hangup { signal }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Self {
+        Hangup {}
+    }
+
+    async fn recv(&mut self) -> Option<()> {
+        #[cfg(unix)]
+        if let Some(signal) = self.signal.as_mut() {
+            return signal.recv().await;
+        }
+
+        std::future::pending().await
+    }
+}
+
+fn modification_times(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Reloads the certificates on SIGHUP, or when their modification time
+/// changes if `check_interval` is not zero. Handshakes started after the
+/// reload use the new certificate, established connections are untouched.
+/// A configuration that fails to load is logged and the previous one kept.
+pub async fn watch_certificates(
+    rustls_config: RustlsConfig,
+    settings: TlsSettings,
+    check_interval: Duration,
+) {
+    let mut paths = vec![settings.cert_path.as_path(), settings.key_path.as_path()];
+    if let Some(path) = settings.client_ca_path.as_deref() {
+        paths.push(path);
+    }
+
+    let mut sighup = Hangup::new();
+
+    let mut last_modified = modification_times(&paths);
+
+    // the timer branch is disabled when the check interval is zero
+    let mut check_timer = tokio::time::interval(check_interval.max(Duration::from_secs(1)));
+    check_timer.tick().await;
+
+    loop {
+        tokio::select! {
+            Some(_) = sighup.recv() => {
+                log::info!("SIGHUP received, reloading the TLS certificates");
+            }
+            _ = check_timer.tick(), if !check_interval.is_zero() => {
+                let modified = modification_times(&paths);
+                if modified == last_modified {
+                    continue;
+                }
+                log::info!("TLS certificates changed on disk, reloading");
+            }
+        }
+
+        last_modified = modification_times(&paths);
+
+        match server_config(&settings) {
+            Ok(config) => {
+                rustls_config.reload_from_config(Arc::new(config));
+                log::info!(
+                    "TLS certificates reloaded from {}",
+                    settings.cert_path.display()
+                );
+            }
+            Err(e) => {
+                log::error!("TLS reload failed, keeping the current certificates: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_address_from_certificate_names() {
+        assert_eq!(
+            parse_mac_address("shelly_1plus-a8032abe54dc").as_deref(),
+            Some("A8:03:2A:BE:54:DC")
+        );
+        assert_eq!(
+            parse_mac_address("a8:03:2a:be:54:dc").as_deref(),
+            Some("A8:03:2A:BE:54:DC")
+        );
+        assert_eq!(parse_mac_address("bridge.local"), None);
+        assert_eq!(parse_mac_address("shelly-a8032abe54"), None);
+    }
+}
@@ -1,20 +1,13 @@
 use std::collections::HashMap;
 use std::time::SystemTime;
 
-#[derive(Clone)]
-pub struct ValveData {
-    pub desired_state: serde_json::Value,
-    pub attempts: usize,
-}
-
 pub struct BestActuatorData {
-    actuator_mac_address: String,
-    rssi: i64,
-    timestamp: std::time::SystemTime,
+    pub actuator_mac_address: String,
+    pub rssi: i64,
+    pub timestamp: std::time::SystemTime,
 }
 
 pub struct ValveCommandManager {
-    pub valve_commands: HashMap<String, ValveData>,
     pub best_actuator: HashMap<String, BestActuatorData>,
 }
 
@@ -22,19 +15,9 @@ impl ValveCommandManager {
     pub fn new() -> Self {
         ValveCommandManager {
             best_actuator: HashMap::new(),
-            valve_commands: HashMap::new(),
         }
     }
 
-    pub fn remove(&mut self, valve_mac_address: &str) {
-        self.valve_commands.remove(valve_mac_address);
-    }
-
-    pub fn insert(&mut self, valve_mac_address: &str, valve_data: ValveData) {
-        self.valve_commands
-            .insert(valve_mac_address.to_owned(), valve_data);
-    }
-
     pub fn update_best_actuator(
         &mut self,
         valve_mac_address: &str,
@@ -44,7 +27,7 @@ impl ValveCommandManager {
         let now = SystemTime::now();
         if self.best_actuator.contains_key(valve_mac_address) {
             let data = self.best_actuator.get(valve_mac_address).unwrap();
-            if data.rssi < rssi || (data.timestamp.elapsed().unwrap().as_secs() > 30) {
+            if data.rssi < rssi || data.timestamp.elapsed().map_or(true, |e| e.as_secs() > 30) {
                 self.best_actuator.insert(
                     valve_mac_address.to_string(),
                     BestActuatorData {
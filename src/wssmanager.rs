@@ -1,17 +1,36 @@
-use axum::{extract::Extension, http, response::IntoResponse, routing::get, Router};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, Extension, FromRequest, RequestParts},
+    http::{self, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
 
 use axum_auth::AuthBasic;
 
-use crate::messages::{AuthCredMessage, BleBeaconMessage, ESP32CommandMessage, ESP32CommandType};
-use axum::extract::ws::Message;
+use crate::api::{self, ApiAdmin, ApiRequest};
+use crate::authguard::{AuthLogEntry, AuthOutcome, SharedAuthGuard, SharedAuthLog};
+use crate::commands::{request_action_message, shelly_action};
+use crate::messages::{
+    AuthCredMessage, BleBeaconMessage, Credentials, ESP32CommandMessage, ESP32CommandType,
+};
+use crate::metrics;
+use crate::sessions::{DeliveryError, SessionRegistry, SharedSessions};
+use crate::tls::{self, ClientCertAcceptor, ClientCertificate, TlsSettings};
 use axum::extract::ws::WebSocketUpgrade;
-use std::time::SystemTime;
-use std::{net::SocketAddr, path::PathBuf};
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc::error::SendTimeoutError;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tower_http::cors::{Any, CorsLayer};
 
 use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
 
 fn parse_esp32_message(
     shelly_message: &serde_json::Value,
@@ -87,57 +106,187 @@ fn parse_esp32_message(
     false
 }
 
+/// How long a command waits for room in the queue of a busy device.
+const COMMAND_SEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How the ESP32 authenticate when opening the WebSocket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    /// HTTP Basic credentials matched against the DHT topic of the device.
+    Basic,
+    /// Client certificate signed by the client CA, the mac address is taken
+    /// from the certificate.
+    Certificate,
+}
+
+/// Hands the credentials of a connecting ESP32 to the auth task.
+#[derive(Clone)]
+struct Authenticator {
+    mode: AuthMode,
+    tx_cred: Sender<AuthCredMessage>,
+    guard: SharedAuthGuard,
+    log: SharedAuthLog,
+}
+
+/// Credentials presented by a connecting ESP32, rejected with 401 when
+/// missing, 403 when the client certificate carries no device identity and
+/// 429 while the source or the user is locked out.
+struct DeviceLogin {
+    credentials: Credentials,
+    source_ip: IpAddr,
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for DeviceLogin {
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(authenticator) = Extension::<Authenticator>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let ConnectInfo(remote_addr) = ConnectInfo::<SocketAddr>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let credentials = match authenticator.mode {
+            AuthMode::Basic => match AuthBasic::from_request(req).await {
+                Ok(AuthBasic((user, password))) => Credentials::Basic {
+                    user,
+                    pass: password.unwrap_or_default(),
+                },
+                Err(_) => return Err(StatusCode::UNAUTHORIZED.into_response()),
+            },
+            AuthMode::Certificate => {
+                let certificate = match req.extensions().get::<ClientCertificate>() {
+                    Some(ClientCertificate(Some(certificate))) => certificate,
+                    _ => return Err(StatusCode::UNAUTHORIZED.into_response()),
+                };
+
+                match tls::certificate_identity(certificate) {
+                    Ok(identity) => Credentials::Certificate {
+                        mac_address: identity.mac_address,
+                        serial: identity.serial,
+                    },
+                    Err(e) => {
+                        log::warn!(
+                            "Rejected ESP32 client certificate from {}: {}",
+                            remote_addr.ip(),
+                            e
+                        );
+                        return Err(StatusCode::FORBIDDEN.into_response());
+                    }
+                }
+            }
+        };
+
+        let source_ip = remote_addr.ip();
+        let now = Instant::now();
+
+        // refused before the upgrade, without looking at the DHT
+        let locked_out =
+            authenticator
+                .guard
+                .lock()
+                .unwrap()
+                .locked_out(source_ip, credentials.user(), now);
+
+        if let Some(remaining) = locked_out {
+            let entry = AuthLogEntry::new(source_ip, &credentials, AuthOutcome::LockedOut);
+            authenticator
+                .log
+                .lock()
+                .unwrap()
+                .locked_out(entry, remaining, now);
+
+            let retry_after = remaining.as_secs().max(1).to_string();
+
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(http::header::RETRY_AFTER, retry_after)],
+            )
+                .into_response());
+        }
+
+        Ok(DeviceLogin {
+            credentials,
+            source_ip,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WssConfig {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub tls: TlsSettings,
+    pub auth_mode: AuthMode,
+    /// How often the certificate files are checked for changes, zero
+    /// disables the check and leaves SIGHUP as the only reload trigger.
+    pub cert_check_interval: Duration,
+    /// Enables the REST API under `/api` when set.
+    pub api_admin: Option<ApiAdmin>,
+    pub auth_guard: SharedAuthGuard,
+    pub auth_log: SharedAuthLog,
+}
+
 pub struct WssManager {
     //  listening port
     pub http_port: u16,
     pub channel_of_updates_tx: broadcast::Sender<BleBeaconMessage>,
-    pub channel_of_updates_rx: broadcast::Receiver<BleBeaconMessage>,
-    pub command_channel_tx: broadcast::Sender<ESP32CommandMessage>,
-    pub command_channel_rx: broadcast::Receiver<ESP32CommandMessage>,
     pub channel_of_actuator_updates_tx: broadcast::Sender<serde_json::Value>,
-    pub channel_of_actuator_updates_rx: broadcast::Receiver<serde_json::Value>,
+    pub sessions: SharedSessions,
+    server_handle: Handle,
+}
+
+/// Requests of the ESP32 logins and of the REST API, each served by its
+/// own task.
+pub struct WssRequests {
     pub rx_auth_cred: mpsc::Receiver<AuthCredMessage>,
+    pub rx_api: mpsc::Receiver<ApiRequest>,
 }
 
 impl WssManager {
-    pub async fn new(http_port: u16) -> WssManager {
-        let rootdir = std::env::var("CARGO_MANIFEST_DIR")
-            .map(|s| PathBuf::from(s).join("data"))
-            .unwrap_or_else(|_| "/etc/domo/".into());
+    pub async fn new(wss_config: WssConfig) -> Result<(WssManager, WssRequests), Box<dyn Error>> {
+        let tls_config = tls::server_config(&wss_config.tls)?;
 
-        let addr = SocketAddr::from(([0, 0, 0, 0], http_port));
+        let config = RustlsConfig::from_config(Arc::new(tls_config));
 
-        let config = RustlsConfig::from_pem_file(rootdir.join("Cert.pem"), rootdir.join("Key.pem"))
-            .await
-            .unwrap();
-
-        let (tx_auth_cred, rx_auth_cred) = mpsc::channel(32);
+        let addr = SocketAddr::new(wss_config.bind_address, wss_config.port);
 
-        let tx_auth_cred_copy = tx_auth_cred;
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("cannot listen on {}: {}", addr, e))?;
 
-        let (command_channel_tx, command_channel_rx) =
-            broadcast::channel::<ESP32CommandMessage>(16);
+        let (tx_auth_cred, rx_auth_cred) = mpsc::channel(32);
 
-        let command_channel_tx_copy = command_channel_tx.clone();
+        let authenticator = Authenticator {
+            mode: wss_config.auth_mode,
+            tx_cred: tx_auth_cred,
+            guard: wss_config.auth_guard.clone(),
+            log: wss_config.auth_log.clone(),
+        };
 
-        let (channel_of_updates_tx, channel_of_updates_rx) =
-            broadcast::channel::<BleBeaconMessage>(16);
+        let (channel_of_updates_tx, _) = broadcast::channel::<BleBeaconMessage>(16);
 
         let channel_of_updates_tx_copy = channel_of_updates_tx.clone();
 
-        let (channel_of_actuator_updates_tx, channel_of_actuator_updates_rx) =
-            broadcast::channel::<serde_json::Value>(16);
+        let (channel_of_actuator_updates_tx, _) = broadcast::channel::<serde_json::Value>(16);
 
         let channel_of_actuator_updates_tx_copy = channel_of_actuator_updates_tx.clone();
 
-        let app = Router::new()
+        let sessions: SharedSessions = Arc::new(Mutex::new(SessionRegistry::new()));
+
+        let (tx_api, rx_api) = mpsc::channel(8);
+
+        let mut app = Router::new()
+            .route("/metrics", get(WssManager::handle_metrics_req))
             .route(
                 "/",
                 get(WssManager::handle_websocket_req)
-                    .layer(Extension(command_channel_tx_copy))
                     .layer(Extension(channel_of_updates_tx_copy))
                     .layer(Extension(channel_of_actuator_updates_tx_copy))
-                    .layer(Extension(tx_auth_cred_copy)),
+                    .layer(Extension(authenticator))
+                    .layer(Extension(sessions.clone())),
             )
             .layer(
                 CorsLayer::new()
@@ -146,88 +295,179 @@ impl WssManager {
                     .allow_headers([http::header::CONTENT_TYPE]),
             );
 
+        match wss_config.api_admin {
+            Some(admin) => app = app.merge(api::router(admin, sessions.clone(), tx_api)),
+            None => log::info!("No API admin configured, the REST API is disabled"),
+        }
+
+        tokio::spawn(tls::watch_certificates(
+            config.clone(),
+            wss_config.tls.clone(),
+            wss_config.cert_check_interval,
+        ));
+
+        let server_handle = Handle::new();
+        let handle = server_handle.clone();
+
         tokio::spawn(async move {
-            axum_server::bind_rustls(addr, config)
-                .serve(app.into_make_service())
+            if let Err(e) = axum_server::from_tcp(listener)
+                .acceptor(ClientCertAcceptor::new(config))
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
+            {
+                log::error!("WebSocket server on {} stopped: {}", addr, e);
+            }
         });
 
-        WssManager {
-            http_port,
+        let wss_mgr = WssManager {
+            http_port: wss_config.port,
             channel_of_updates_tx,
-            channel_of_updates_rx,
-            command_channel_tx,
-            command_channel_rx,
             channel_of_actuator_updates_tx,
-            channel_of_actuator_updates_rx,
-            rx_auth_cred,
+            sessions,
+            server_handle,
+        };
+
+        Ok((
+            wss_mgr,
+            WssRequests {
+                rx_auth_cred,
+                rx_api,
+            },
+        ))
+    }
+
+    /// Receiver of the BLE beacons relayed by the ESP32 from now on.
+    pub fn subscribe_ble_updates(&self) -> broadcast::Receiver<BleBeaconMessage> {
+        self.channel_of_updates_tx.subscribe()
+    }
+
+    /// Receiver of the status updates of the ESP32 actuators from now on.
+    pub fn subscribe_actuator_updates(&self) -> broadcast::Receiver<serde_json::Value> {
+        self.channel_of_actuator_updates_tx.subscribe()
+    }
+
+    /// Stops accepting connections and closes the ESP32 sessions once
+    /// their queued commands are sent, waiting up to `timeout` for them.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.server_handle.graceful_shutdown(Some(timeout));
+
+        self.sessions.lock().unwrap().close_all();
+
+        let closed = tokio::time::timeout(timeout, async {
+            while !self.connected_actuators().is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+
+        if closed.is_err() {
+            log::warn!(
+                "ESP32 sessions still open after {:?}: {:?}",
+                timeout,
+                self.connected_actuators()
+            );
+        }
+    }
+
+    /// Mac addresses of the ESP32 with an open session.
+    pub fn connected_actuators(&self) -> Vec<String> {
+        self.sessions.lock().unwrap().connected()
+    }
+
+    /// Queues `cmd` for the ESP32 it is addressed to, the actuator relaying
+    /// it for valve commands. Waits up to `COMMAND_SEND_TIMEOUT` when the
+    /// queue of the device is full.
+    pub async fn send_command(&self, cmd: ESP32CommandMessage) -> Result<(), DeliveryError> {
+        let mac_address = match cmd.command_type {
+            ESP32CommandType::Valve => cmd.actuator_mac_address.clone(),
+            _ => cmd.mac_address.clone(),
+        };
+
+        let sender = self.sessions.lock().unwrap().sender(&mac_address)?;
+
+        sender
+            .send_timeout(cmd, COMMAND_SEND_TIMEOUT)
+            .await
+            .map_err(|e| match e {
+                SendTimeoutError::Timeout(_) => {
+                    metrics::metrics()
+                        .commands_dropped
+                        .with_label_values(&["queue_full"])
+                        .inc();
+                    DeliveryError::Timeout(mac_address)
+                }
+                SendTimeoutError::Closed(_) => DeliveryError::NotConnected(mac_address),
+            })
+    }
+
+    /// Asks every session to ping its device, skipping the busy ones.
+    pub fn ping_all(&self) {
+        let senders = self.sessions.lock().unwrap().senders();
+
+        for sender in senders {
+            let _ret = sender.try_send(ESP32CommandMessage {
+                command_type: ESP32CommandType::Ping,
+                actuator_mac_address: String::from(""),
+                mac_address: String::from(""),
+                payload: serde_json::json!({}),
+            });
+        }
+    }
+
+    async fn handle_metrics_req() -> Response {
+        match metrics::metrics().render() {
+            Ok(text) => text.into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
         }
     }
 
     async fn handle_websocket_req(
         ws: WebSocketUpgrade,
-        Extension(command_channel): Extension<broadcast::Sender<ESP32CommandMessage>>,
         Extension(updates_channel): Extension<broadcast::Sender<BleBeaconMessage>>,
         Extension(updates_actuator_channel): Extension<broadcast::Sender<serde_json::Value>>,
-        Extension(tx_cred): Extension<Sender<AuthCredMessage>>,
-        AuthBasic((user, password)): AuthBasic,
-    ) -> impl IntoResponse {
-        let mut command_receive_channel = command_channel.subscribe();
-
-        ws.on_upgrade(|mut socket| async move {
+        Extension(authenticator): Extension<Authenticator>,
+        Extension(sessions): Extension<SharedSessions>,
+        DeviceLogin {
+            credentials,
+            source_ip,
+        }: DeviceLogin,
+    ) -> Response {
+        ws.on_upgrade(move |mut socket| async move {
 
             let mut esp32_mac_address = String::from("");
 
             let mut last_pong_timestamp = SystemTime::now();
 
-            let mut pass = String::from("");
-
-            if let Some(password) = password {
-                pass = password;
-            }
-
             let (tx_resp, rx_resp) = oneshot::channel();
 
             let m = AuthCredMessage {
-                user,
-                pass,
+                credentials,
+                source_ip,
                 responder: tx_resp,
             };
 
-            tx_cred.send(m).await.unwrap();
+            authenticator.tx_cred.send(m).await.unwrap();
 
             let resp = rx_resp.await.unwrap();
 
-            match resp {
+            let mut session = match resp {
                 Ok(m) => {
                     if let Some(mac_address) = m.get("mac_address") {
                         esp32_mac_address = mac_address.as_str().unwrap().to_owned();
                     }
+                    let topic_name = m["topic"].as_str().unwrap_or_default();
+                    println!("Shelly plus {}, {} connected", topic_name, esp32_mac_address);
+                    sessions.lock().unwrap().register(&esp32_mac_address, topic_name, source_ip)
                 },
                 _=> {
                     return;
                 }
-            }
+            };
 
             let action_payload = serde_json::json!({});
 
-            let action_payload_string = action_payload.to_string();
-
-            let shelly_action = serde_json::json!({
-                                            "shelly_action" : {
-                                                "input" : {
-                                                    "action": {
-                                                        "action_name": "get_status_update",
-                                                        "action_payload": action_payload_string
-                                                    }
-                                                }
-                                            }
-                                        });
-
-            let message = serde_json::json!({
-                                            "messageType": "requestAction",
-                                            "data": shelly_action
-                                        });
+            let message = request_action_message(&shelly_action("get_status_update", &action_payload));
 
             //println!("Request status update for ESP32");
             let m = Message::Text(serde_json::to_string(&message).unwrap());
@@ -235,64 +475,48 @@ impl WssManager {
 
             loop {
                 tokio::select! {
-                        // received command from the dht
-                        command = command_receive_channel.recv() => {
-
-                                if let Ok(cmd) = command {
-                                    match cmd.command_type {
-                                        ESP32CommandType::Valve => {
-
-                                               if esp32_mac_address == cmd.actuator_mac_address {
-                                                    //println!("Received valve command {} ", esp32_mac_address);
-                                                    if let Some(shelly_action_payload) = cmd.payload.get("shelly_action") {
-                                                                let shelly_action = serde_json::json!({ "shelly_action": shelly_action_payload });
-
-                                                                let message = serde_json::json!({
-                                                                    "messageType": "requestAction",
-                                                                    "data": shelly_action
-                                                                });
-                                                                let m = Message::Text(serde_json::to_string(&message).unwrap());
-                                                                let _ret = socket.send(m).await;
-
-                                                    }
-                                               }
-
-                                        }
-                                        ESP32CommandType::Actuator => {
-                                            //println!("Received Actuator command");
-                                            if cmd.mac_address == esp32_mac_address {
-                                                if let Some(shelly_action_payload) = cmd.payload.get("shelly_action") {
-                                                            let shelly_action = serde_json::json!({ "shelly_action": shelly_action_payload });
-
-                                                            let message = serde_json::json!({
-                                                                "messageType": "requestAction",
-                                                                "data": shelly_action
-                                                            });
-                                                            let m = Message::Text(serde_json::to_string(&message).unwrap());
-                                                            let _ret = socket.send(m).await;
-                                                }
-                                            }
-                                        },
-                                        ESP32CommandType::Ping => {
-                                            if last_pong_timestamp.elapsed().unwrap().as_secs() > 60{
-                                                //println!("{} disconnected due to lack of PONGS", esp32_mac_address);
-                                                return;
-                                            }
-                                            //println!("Received Ping command request");
-                                            let _ret = socket.send(Message::Ping(vec![])).await;
-
+                        // received command addressed to this device
+                        Some(cmd) = session.commands.recv() => {
+
+                                match cmd.command_type {
+                                    ESP32CommandType::Valve | ESP32CommandType::Actuator => {
+                                        //println!("Received command for {}", esp32_mac_address);
+                                        let m = Message::Text(cmd.payload.to_string());
+                                        let _ret = socket.send(m).await;
+                                    }
+                                    ESP32CommandType::Ping => {
+                                        if last_pong_timestamp.elapsed().unwrap().as_secs() > 60{
+                                            //println!("{} disconnected due to lack of PONGS", esp32_mac_address);
+                                            break;
                                         }
+                                        //println!("Received Ping command request");
+                                        let _ret = socket.send(Message::Ping(vec![])).await;
 
                                     }
                                 }
                         }
+                        // a newer session of the same device replaced this one,
+                        // or the bridge is shutting down
+                        _ = &mut session.evicted => {
+                            while let Ok(cmd) = session.commands.try_recv() {
+                                if !matches!(cmd.command_type, ESP32CommandType::Ping) {
+                                    let _ret = socket.send(Message::Text(cmd.payload.to_string())).await;
+                                }
+                            }
+
+                            let _ret = socket.send(Message::Close(Some(CloseFrame {
+                                code: close_code::AWAY,
+                                reason: "session closed".into(),
+                            }))).await;
+                            break;
+                        }
                         // received message from an esp32
-                        Some(msg) = socket.recv() => {
+                        msg = socket.recv() => {
 
                             //println!("MSG {:?}", msg);
 
                             match msg {
-                                Ok(message) => {
+                                Some(Ok(message)) => {
                                     match message {
 
                                         Message::Text(message) => {
@@ -305,22 +529,30 @@ impl WssManager {
                                         },
                                         Message::Close(_) => {
                                             //println!("{} disconnected", esp32_mac_address);
-                                            return;
+                                            break;
                                         },
                                         Message::Pong(_) => {
                                             //println!("PONG FROM {}", esp32_mac_address);
                                             last_pong_timestamp = SystemTime::now();
+                                            sessions.lock().unwrap().pong(&esp32_mac_address, session.id);
                                         }
                                         _ => {}
                                     }
                                 },
-                                Err(e) => {
+                                Some(Err(e)) => {
                                     println!("ERROR {} {} ", esp32_mac_address, e);
                                 }
+                                None => {
+                                    break;
+                                }
                             }
                         }
                 }
             }
+
+            sessions.lock().unwrap().unregister(&esp32_mac_address, session.id);
+            log::info!("{} disconnected", esp32_mac_address);
         })
+        .into_response()
     }
 }